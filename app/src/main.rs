@@ -15,6 +15,8 @@ async fn run() {
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     {
         let mut renderer = create_wgpu_renderer_winit(&window).await;
+        // Reload changed files under res/ while the app runs.
+        renderer.set_hot_reload(std::env::var_os("HOT_RELOAD").is_some());
         // let _model = renderer.load_model("cube.obj").await.expect("Error while loading model");
         let mut last_render_time = Instant::now();
 
@@ -23,53 +25,51 @@ async fn run() {
                 Event::WindowEvent {
                     ref event,
                     window_id,
-                } if window_id == window.id() => {
-                    if !renderer.input(event) {
-                        match event {
-                            WindowEvent::CloseRequested
-                        | WindowEvent::KeyboardInput {
-                            event:
-                                KeyEvent {
-                                    state: ElementState::Pressed,
-                                    physical_key: PhysicalKey::Code(KeyCode::Escape),
-                                    ..
-                                },
-                            ..
-                        } => control_flow.exit(),
-                            WindowEvent::Resized(physical_size) => {
-                                renderer.resize(physical_size.width, physical_size.height);
-                            }
-                            WindowEvent::RedrawRequested => {
-                                window.request_redraw();
+                } if window_id == window.id() && !renderer.input(event) => {
+                    match event {
+                        WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => control_flow.exit(),
+                        WindowEvent::Resized(physical_size) => {
+                            renderer.resize(physical_size.width, physical_size.height);
+                        }
+                        WindowEvent::RedrawRequested => {
+                            window.request_redraw();
 
-                                // if !surface_configured {
-                                //     return;
-                                // }
+                            // if !surface_configured {
+                            //     return;
+                            // }
 
-                                let now = Instant::now();
-                                let dt = now - last_render_time;
-                                last_render_time = now;
-                                renderer.update(&dt);
-                                match renderer.render() {
-                                    Ok(_) => {}
-                                    Err(RenderError::OutOfMemory) => {
-                                        log::error!("OutOfMemory");
-                                        control_flow.exit();
-                                    }
+                            let now = Instant::now();
+                            let dt = now - last_render_time;
+                            last_render_time = now;
+                            renderer.update(&dt);
+                            match renderer.render() {
+                                Ok(_) => {}
+                                Err(RenderError::OutOfMemory) => {
+                                    log::error!("OutOfMemory");
+                                    control_flow.exit();
+                                }
 
-                                    Err(RenderError::Timeout) => {
-                                        log::warn!("Surface timeout")
-                                    }
+                                Err(RenderError::Timeout) => {
+                                    log::warn!("Surface timeout")
                                 }
                             }
-                            _ => {}
                         }
+                        _ => {}
                     }
                 }
                 Event::DeviceEvent {
                     event: DeviceEvent::MouseMotion{ delta, },
                     .. // We're not using device_id currently
-                } => if renderer.mouse_pressed() {
+                } if renderer.mouse_pressed() => {
                     renderer.camera_controller().process_mouse(delta.0, delta.1)
                 }
                 _ => {}
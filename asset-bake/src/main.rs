@@ -0,0 +1,81 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use pasts::Executor;
+use renderer::{
+    assets::{self, DirectorySource},
+    bake::{self, Baker},
+};
+
+// Collects the models below dir, as paths relative to res. The baked output
+// and hidden directories such as the mesh cache are skipped.
+fn find_models(res: &Path, dir: &Path, models: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if name.starts_with('.') || path == res.join("baked") {
+            continue;
+        }
+        if path.is_dir() {
+            find_models(res, &path, models)?;
+        } else if let Some(file_name) = path.strip_prefix(res)?.to_str() {
+            if bake::is_model(file_name) {
+                models.push(file_name.replace('\\', "/"));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Bakes every model in the res directory given as the first argument
+// ("res" by default) together with its textures, then writes the manifest
+// the renderer loads them through.
+async fn bake_all() -> anyhow::Result<()> {
+    let begin = Instant::now();
+
+    let res = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| "res".to_string()));
+    let mut models = Vec::new();
+    find_models(&res, &res, &mut models)?;
+    models.sort();
+
+    // Only the directory being baked is read from.
+    assets::unmount_all();
+    assets::mount("", DirectorySource::new(&res));
+    let mut baker = Baker::new(&res);
+    let mut failed = 0;
+    for model in &models {
+        println!("Baking {}", model);
+        if let Err(e) = baker.bake_model(model).await {
+            log::error!("Could not bake {}: {:?}", model, e);
+            failed += 1;
+        }
+    }
+    baker.write_manifest()?;
+
+    println!(
+        "Baked {} of {} models into {} files in {}ms",
+        models.len() - failed,
+        models.len(),
+        baker.len(),
+        begin.elapsed().as_millis()
+    );
+    if failed > 0 {
+        anyhow::bail!("{} models could not be baked", failed);
+    }
+    Ok(())
+}
+
+async fn run() {
+    env_logger::init();
+    if let Err(e) = bake_all().await {
+        log::error!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let executor = Executor::default();
+    executor.block_on(run());
+}
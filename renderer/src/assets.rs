@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, RwLock},
+    time::SystemTime,
+};
+
+// Every file the renderer loads (models, textures, shaders, baked assets) is
+// read through the sources mounted here, by a path relative to the asset
+// root with forward slashes such as "textures/cube-diffuse.jpg".
+//
+// Sources are stacked: the most recently mounted source that has a file
+// wins, so a mod mounted on top of the base assets overrides the files it
+// ships and leaves the rest to the base.
+
+pub struct AssetMetadata {
+    pub len: u64,
+    // None for sources that don't track modification times.
+    pub modified: Option<SystemTime>,
+}
+
+pub trait AssetSource: Send + Sync {
+    // Reads a whole file. Errors of kind NotFound let the sources below
+    // this one provide the file.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+    fn metadata(&self, path: &str) -> io::Result<AssetMetadata>;
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+// Resolves "." and ".." and turns backslashes into slashes, so every source
+// sees the same spelling of a path. None for paths leaving the root.
+pub(crate) fn normalize(path: &str) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+// Files in a directory on disk.
+pub struct DirectorySource {
+    root: PathBuf,
+}
+
+impl DirectorySource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl AssetSource for DirectorySource {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let full_path = self.root.join(path);
+        if !std::fs::metadata(&full_path)?.is_file() {
+            return Err(not_found(path));
+        }
+        std::fs::read(full_path)
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<AssetMetadata> {
+        let metadata = std::fs::metadata(self.root.join(path))?;
+        if !metadata.is_file() {
+            return Err(not_found(path));
+        }
+        Ok(AssetMetadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+// Files in a zip archive, which is also what .pak files are. The archive is
+// kept in memory.
+pub struct ArchiveSource {
+    archive: Mutex<zip::ZipArchive<Cursor<Vec<u8>>>>,
+}
+
+impl ArchiveSource {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(Self {
+            archive: Mutex::new(zip::ZipArchive::new(Cursor::new(bytes))?),
+        })
+    }
+}
+
+impl AssetSource for ArchiveSource {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut archive = self.archive.lock().unwrap();
+        let index = archive.index_for_name(path).ok_or_else(|| not_found(path))?;
+        let mut file = archive.by_index(index)?;
+        let mut bytes = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<AssetMetadata> {
+        let mut archive = self.archive.lock().unwrap();
+        let index = archive.index_for_name(path).ok_or_else(|| not_found(path))?;
+        let file = archive.by_index(index)?;
+        Ok(AssetMetadata {
+            len: file.size(),
+            modified: None,
+        })
+    }
+}
+
+// Files held in memory, for generated or downloaded assets.
+#[derive(Default)]
+pub struct MemorySource {
+    files: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, path: &str, bytes: Vec<u8>) {
+        if let Some(path) = normalize(path) {
+            self.files.write().unwrap().insert(path, bytes);
+        }
+    }
+
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.write().unwrap().remove(&normalize(path)?)
+    }
+}
+
+impl AssetSource for MemorySource {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files.read().unwrap().get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<AssetMetadata> {
+        let files = self.files.read().unwrap();
+        let bytes = files.get(path).ok_or_else(|| not_found(path))?;
+        Ok(AssetMetadata {
+            len: bytes.len() as u64,
+            modified: None,
+        })
+    }
+}
+
+// Files compiled into the executable, see embedded_assets!.
+pub struct EmbeddedSource {
+    files: HashMap<String, &'static [u8]>,
+}
+
+impl EmbeddedSource {
+    pub fn new(files: &[(&str, &'static [u8])]) -> Self {
+        Self {
+            files: files
+                .iter()
+                .filter_map(|(path, bytes)| Some((normalize(path)?, *bytes)))
+                .collect(),
+        }
+    }
+}
+
+impl AssetSource for EmbeddedSource {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files.get(path).map(|bytes| bytes.to_vec()).ok_or_else(|| not_found(path))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<AssetMetadata> {
+        let bytes = self.files.get(path).ok_or_else(|| not_found(path))?;
+        Ok(AssetMetadata {
+            len: bytes.len() as u64,
+            modified: None,
+        })
+    }
+}
+
+// Builds an EmbeddedSource from files below a directory, which is relative
+// to the file the macro is used in, like for include_bytes!:
+//
+// renderer::assets::mount("", renderer::embedded_assets!("../res", "cube.obj", "cube.mtl"));
+#[macro_export]
+macro_rules! embedded_assets {
+    ($root:literal, $($path:literal),* $(,)?) => {
+        $crate::assets::EmbeddedSource::new(&[
+            $(($path, include_bytes!(concat!($root, "/", $path)) as &'static [u8])),*
+        ])
+    };
+}
+
+struct Mount {
+    // Normalized, "" for the root.
+    point: String,
+    source: Box<dyn AssetSource>,
+}
+
+impl Mount {
+    // The path of a file below the mount point, relative to the source.
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.point.is_empty() {
+            return Some(path);
+        }
+        path.strip_prefix(self.point.as_str())?.strip_prefix('/')
+    }
+}
+
+// The res directory of the working directory, or the closest one next to
+// the executable or in one of its parent directories, so the app finds its
+// assets when started from target/debug as well.
+fn default_directory() -> Option<PathBuf> {
+    let working_directory = Path::new("res");
+    if working_directory.is_dir() {
+        return Some(working_directory.to_path_buf());
+    }
+    let executable = std::env::current_exe().ok()?;
+    executable
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("res"))
+        .find(|dir| dir.is_dir())
+}
+
+struct Assets {
+    mounts: Vec<Mount>,
+    cache_dir: Option<PathBuf>,
+}
+
+fn assets() -> &'static RwLock<Assets> {
+    static ASSETS: OnceLock<RwLock<Assets>> = OnceLock::new();
+    ASSETS.get_or_init(|| {
+        let directory = default_directory();
+        RwLock::new(Assets {
+            cache_dir: directory.as_ref().map(|dir| dir.join(".cache")),
+            mounts: directory
+                .into_iter()
+                .map(|dir| Mount {
+                    point: String::new(),
+                    source: Box::new(DirectorySource::new(dir)),
+                })
+                .collect(),
+        })
+    })
+}
+
+// Mounts a source at point ("" for the root) on top of the ones already
+// mounted. The res directory found next to the working directory or the
+// executable is mounted at the root from the start.
+pub fn mount(point: &str, source: impl AssetSource + 'static) {
+    let point = normalize(point).unwrap_or_default();
+    assets().write().unwrap().mounts.push(Mount {
+        point,
+        source: Box::new(source),
+    });
+}
+
+// Removes every mounted source, including the default res directory.
+pub fn unmount_all() {
+    assets().write().unwrap().mounts.clear();
+}
+
+// The directory preprocessed meshes are cached in, None disables the cache.
+// Defaults to .cache in the default res directory.
+pub fn set_cache_dir(dir: Option<PathBuf>) {
+    assets().write().unwrap().cache_dir = dir;
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    assets().read().unwrap().cache_dir.clone()
+}
+
+// Asks the mounted sources from the top down, until one has the file.
+fn find<T>(path: &str, f: impl Fn(&dyn AssetSource, &str) -> io::Result<T>) -> anyhow::Result<T> {
+    let normalized = normalize(path).ok_or_else(|| anyhow::anyhow!("Asset path {} leaves the asset root", path))?;
+    let assets = assets().read().unwrap();
+    for mount in assets.mounts.iter().rev() {
+        let Some(relative) = mount.relative(&normalized) else {
+            continue;
+        };
+        match f(mount.source.as_ref(), relative) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => return Ok(result?),
+        }
+    }
+    Err(anyhow::anyhow!("Asset {} not found", path))
+}
+
+pub fn read(path: &str) -> anyhow::Result<Vec<u8>> {
+    find(path, |source, path| source.read(path))
+}
+
+pub fn read_string(path: &str) -> anyhow::Result<String> {
+    Ok(String::from_utf8(read(path)?)?)
+}
+
+pub fn metadata(path: &str) -> anyhow::Result<AssetMetadata> {
+    find(path, |source, path| source.metadata(path))
+}
+
+// Whether a mounted source has the file. Errors other than NotFound count
+// as having it.
+pub fn exists(path: &str) -> bool {
+    find(path, |source, path| match source.metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    })
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{metadata, mount, normalize, read, read_string, DirectorySource, MemorySource};
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize("a/b/c.txt").as_deref(), Some("a/b/c.txt"));
+        assert_eq!(normalize("./a//b/../c.txt").as_deref(), Some("a/c.txt"));
+        assert_eq!(normalize("a\\b\\c.txt").as_deref(), Some("a/b/c.txt"));
+        assert_eq!(normalize("/a/b/").as_deref(), Some("a/b"));
+        assert_eq!(normalize("").as_deref(), Some(""));
+        assert_eq!(normalize("a/../../b"), None);
+        assert_eq!(normalize(".."), None);
+    }
+
+    fn source(files: &[(&str, &str)]) -> MemorySource {
+        let source = MemorySource::new();
+        for (path, contents) in files {
+            source.insert(path, contents.as_bytes().to_vec());
+        }
+        source
+    }
+
+    #[test]
+    fn later_mounts_shadow_earlier_ones() {
+        mount("assets_tests/stack", source(&[("a.txt", "bottom a"), ("b.txt", "bottom b")]));
+        mount("assets_tests/stack", source(&[("a.txt", "top a")]));
+        assert_eq!(read_string("assets_tests/stack/a.txt").unwrap(), "top a");
+        // Files the top source doesn't have come from the ones below it.
+        assert_eq!(read_string("assets_tests/stack/b.txt").unwrap(), "bottom b");
+        assert_eq!(read_string("assets_tests/stack/./sub/../b.txt").unwrap(), "bottom b");
+        assert_eq!(metadata("assets_tests/stack/b.txt").unwrap().len, 8);
+        assert!(read("assets_tests/stack/c.txt").is_err());
+        assert!(read("assets_tests/../../c.txt").is_err());
+    }
+
+    #[test]
+    fn mounts_only_see_paths_below_their_point() {
+        mount("assets_tests/points/inner", source(&[("file.txt", "inner")]));
+        mount("assets_tests/points", source(&[("inner/file.txt", "outer"), ("innerfile.txt", "sibling")]));
+        assert_eq!(read_string("assets_tests/points/inner/file.txt").unwrap(), "outer");
+        assert_eq!(read_string("assets_tests/points/innerfile.txt").unwrap(), "sibling");
+        mount("assets_tests/points/inner", source(&[("file.txt", "inner again")]));
+        assert_eq!(read_string("assets_tests/points/inner/file.txt").unwrap(), "inner again");
+    }
+
+    #[test]
+    fn directories_are_not_files() {
+        mount("assets_tests/dirs", source(&[("src", "file")]));
+        mount("assets_tests/dirs", DirectorySource::new(env!("CARGO_MANIFEST_DIR")));
+        // The src directory on disk doesn't shadow the file below it.
+        assert_eq!(read_string("assets_tests/dirs/src").unwrap(), "file");
+        assert_eq!(metadata("assets_tests/dirs/src").unwrap().len, 4);
+        assert!(read_string("assets_tests/dirs/Cargo.toml").is_ok());
+    }
+}
@@ -24,6 +24,13 @@ impl Projection {
         }
     }
 
+    // Scales clip space x and y to the direction of the ray through them in
+    // view space, with z = -1.
+    pub fn view_ray_scale(&self) -> (f32, f32) {
+        let tan = (self.fovy.0 / 2.0).tan();
+        (tan * self.aspect, tan)
+    }
+
     pub fn resize(&mut self, width: u32, height: u32) {
         self.aspect = width as f32 / height as f32;
     }
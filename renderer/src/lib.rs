@@ -1,5 +1,6 @@
 mod wgpu_renderer;
 mod camera;
+pub mod assets;
 
 // TODO: It should be the other way arround, we pass in a camera controller, the
 // controller should not live inside the renderer module.
@@ -25,6 +26,97 @@ pub struct ModelHandle(u16);
 #[allow(dead_code)]
 pub struct InstanceHandle(ModelHandle, u16);
 
+#[derive(Copy, Clone)]
+pub struct TextureHandle(u32);
+
+#[derive(Copy, Clone)]
+pub struct EnvironmentHandle(u32);
+
+// What is drawn behind the scene. Colors are linear.
+#[derive(Copy, Clone)]
+pub enum Skybox {
+    // A single color, (0.1, 0.2, 0.3) unless set otherwise.
+    Color([f32; 3]),
+    // Blends from the horizon color to the zenith color above the horizon
+    // and to the ground color below it.
+    Gradient {
+        zenith: [f32; 3],
+        horizon: [f32; 3],
+        ground: [f32; 3],
+    },
+    // The image of an environment loaded with load_environment, like an
+    // equirectangular HDR image.
+    Environment(EnvironmentHandle),
+}
+
+// How the HDR scene is compressed into the range of the display.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    // The filmic curve of the ACES reference transforms, the default.
+    Aces,
+    // Keeps colors closest to the scene's, but washes out bright ones.
+    Reinhard,
+    // Desaturates very bright colors towards white instead of skewing
+    // their hue.
+    AgX,
+}
+
+// How bright the scene is shown, in stops: each one doubles the brightness.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Exposure {
+    // A fixed exposure, Manual(0.0) unless set otherwise.
+    Manual(f32),
+    // Follows the average luminance of the frames over time, brightened or
+    // darkened by compensation.
+    Auto { compensation: f32 },
+}
+
+pub use wgpu_renderer::ModelVertex;
+pub use wgpu_renderer::{ImageError, LoadProgress, TextureCacheStats, TextureKind};
+pub use wgpu_renderer::{FilterMode, SamplerDesc, TextureQuality, WrapMode};
+
+// A material for meshes created at runtime. Maps it doesn't provide fall back
+// to the factors alone.
+#[derive(Clone)]
+pub struct Material {
+    // Meshes are blended by alpha when it is below one.
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub emissive_factor: [f32; 3],
+    // Textures loaded with load_texture_from_bytes, base color and emissive
+    // ones as TextureKind::Color, normal maps as TextureKind::Normal and the
+    // others as TextureKind::Data.
+    pub base_color_texture: Option<TextureHandle>,
+    pub normal_texture: Option<TextureHandle>,
+    pub metallic_roughness_texture: Option<TextureHandle>,
+    pub occlusion_texture: Option<TextureHandle>,
+    pub emissive_texture: Option<TextureHandle>,
+    // How every texture of the material is sampled.
+    pub sampler: SamplerDesc,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            metallic_factor: 0.0,
+            roughness_factor: 0.5,
+            emissive_factor: [0.0; 3],
+            base_color_texture: None,
+            normal_texture: None,
+            metallic_roughness_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            sampler: SamplerDesc::default(),
+        }
+    }
+}
+
+// Returns the contents of a file referenced by name from another file, such
+// as the MTL files and textures of an OBJ.
+pub type FileResolver<'a> = dyn Fn(&str) -> anyhow::Result<Vec<u8>> + Send + Sync + 'a;
+
 #[allow(dead_code)]
 pub trait Renderer {
     fn resize(&mut self, width: u32, height: u32);
@@ -32,15 +124,94 @@ pub trait Renderer {
     fn update(&mut self, dt: &Duration);
     fn render(&mut self) -> Result<(), RenderError>;
 
-    // Will allocate space for upto max_instances upfront.
+    // Will allocate space for upto max_instances upfront. Returns right away,
+    // the model is loaded in the background and a placeholder cube is drawn
+    // for it until then, see load_progress. Errors loading it only show up
+    // there, as failed, and in the log. Animations played in the meantime
+    // start once it is loaded.
     fn load_model<'a>(&'a mut self, file_path: &'a str, max_instances: u16) -> Pin<Box<dyn Future<Output = anyhow::Result<ModelHandle>> + Send + 'a>>;
+    // Loads an OBJ from memory, the MTL files and textures it references are
+    // requested from the resolvers by the names the OBJ and MTL files use.
+    fn load_model_from_bytes<'a>(
+        &'a mut self,
+        obj: &'a [u8],
+        mtl_resolver: &'a FileResolver<'a>,
+        texture_resolver: &'a FileResolver<'a>,
+        max_instances: u16,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<ModelHandle>> + Send + 'a>>;
+    // Decodes an encoded image (PNG, JPEG, ...) held in memory and
+    // generates its mip chain, which kind decides how to filter. KTX2 and
+    // DDS files are uploaded as they are, with their own mips. On devices
+    // without BC compression BC1 to BC5 are decompressed on the CPU, while
+    // BC6H and BC7 (like ETC2 and ASTC without their features) fail to load.
+    fn load_texture_from_bytes(&mut self, bytes: &[u8], label: &str, kind: TextureKind) -> anyhow::Result<TextureHandle>;
+    // Sets the anisotropic filtering of every material, including the ones
+    // loaded already. Defaults to TextureQuality::High.
+    fn set_texture_quality(&mut self, quality: TextureQuality);
+    // Loads an equirectangular image, usually a Radiance .hdr or an OpenEXR
+    // .exr file, and precomputes its diffuse and specular lighting on the
+    // GPU. This takes a moment, so environments are best loaded up front.
+    fn load_environment(&mut self, file_path: &str) -> anyhow::Result<EnvironmentHandle>;
+    // Lights the scene with an environment loaded with load_environment, or
+    // with a dim uniform ambient light for None, the default.
+    fn set_environment(&mut self, environment: Option<EnvironmentHandle>) -> anyhow::Result<()>;
+    // Independent of the environment lighting the scene.
+    fn set_skybox(&mut self, skybox: Skybox) -> anyhow::Result<()>;
+    // The scene is rendered in HDR and tonemapped to the surface.
+    fn set_tonemapper(&mut self, tonemapper: Tonemapper);
+    fn set_exposure(&mut self, exposure: Exposure);
+    // Watches the files of loaded models and the shader, and reloads them
+    // in place when they change: handles and instances stay valid, and keep
+    // playing clips the new model still has. Shader
+    // errors are logged and the previous shader is kept. Off by default,
+    // meant for development.
+    fn set_hot_reload(&mut self, enabled: bool);
+    // Textures are shared between every material loading the same file,
+    // these count how often one was found loaded already.
+    fn texture_cache_stats(&self) -> TextureCacheStats;
+    // How many of the models requested with load_model are drawn already,
+    // for loading screens.
+    fn load_progress(&self) -> LoadProgress;
+    // Uploads a triangle list built at runtime as a model of its own, which
+    // is instanced like a loaded one. generate_tangents replaces the
+    // tangents of the vertices with MikkTSpace ones, texture coordinates are
+    // expected with v pointing down like in wgpu.
+    fn create_mesh(
+        &mut self,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: &Material,
+        generate_tangents: bool,
+        max_instances: u16,
+    ) -> anyhow::Result<ModelHandle>;
+    // Like create_mesh, but the geometry can be replaced as often as every
+    // frame with update_dynamic_mesh. It may start out empty.
+    fn create_dynamic_mesh(
+        &mut self,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: &Material,
+        max_instances: u16,
+    ) -> anyhow::Result<ModelHandle>;
+    // Replaces the vertices and indices of a dynamic mesh, which may change
+    // in number.
+    fn update_dynamic_mesh(&mut self, model: ModelHandle, vertices: &[ModelVertex], indices: &[u32]) -> anyhow::Result<()>;
 
     fn add_instance(&mut self, model: ModelHandle, instance: &Instance) -> InstanceHandle; 
     fn update_instance(&mut self, model: InstanceHandle, instance: &Instance);  // TODO: Make a drop() for InstanceHandle instead instead.
     fn remove_instance(&mut self, model: InstanceHandle);  // TODO: Make a drop() for InstanceHandle instead instead.
 
+    // Plays the named animation clip of the instance's model on that instance
+    // only, starting start_time seconds in. For models still loading the clip
+    // is looked up once they are, a missing one is logged then.
+    fn play_animation(&mut self, instance: &InstanceHandle, clip: &str, start_time: f32, looping: bool) -> anyhow::Result<()>;
+    // Returns the instance to its rest pose.
+    fn stop_animation(&mut self, instance: &InstanceHandle);
+
     fn mouse_pressed(&self) -> bool; //TODO: Remove
     fn camera_controller(&mut self) -> &mut CameraController; //TODO: Remove
 }
 
 pub use wgpu_renderer::create_wgpu_renderer_winit;
+pub use wgpu_renderer::bake;
+pub use wgpu_renderer::primitives;
@@ -1,11 +1,11 @@
 use std::{future::Future, pin::Pin, time::Duration};
 
-use resources::load_wgpu_model;
-
 use crate::Instance;
 
 use instanced_rendering::InstanceManager;
 use model::WgpuModel;
+use animated_instances::{AnimatedInstances, AnimationState, NamedAnimationState};
+use dynamic_mesh::DynamicMesh;
 use wgpu::util::DeviceExt;
 use winit::{
     event::{ElementState, KeyEvent, MouseButton, WindowEvent},
@@ -18,10 +18,31 @@ use cgmath::prelude::*;
 mod model;
 mod texture;
 mod resources;
+mod geometry;
+mod gltf_loader;
+mod mesh_cache;
+mod animation;
+mod animated_instances;
+mod dynamic_mesh;
+mod sampler;
+mod manifest;
+mod baked_texture;
+mod compressed_texture;
+mod image_decoding;
+mod texture_cache;
+mod streaming;
+mod hot_reload;
+mod environment;
+mod skybox;
+mod tonemapping;
+pub mod bake;
+pub mod primitives;
 
 use texture::Texture;
 use resources::load_string;
 
+const SHADER_FILE: &str = "shader.wgsl";
+
 use crate::{
     camera::{camera_controller::CameraController, projection::Projection, Camera, CameraUniform},
 };
@@ -33,20 +54,27 @@ struct LightUniform {
     // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
     _padding: u32,
     color: [f32; 3],
-    _padding2: u32,
+    // The mip level of the prefiltered environment for a roughness of 1.
+    environment_max_lod: f32,
 }
 
 mod instanced_rendering;
 
 struct WgpuRenderer<'a> {
-    device: wgpu::Device,
+    // Shared with the model streaming thread.
+    device: std::sync::Arc<wgpu::Device>,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     width: u32,
     height: u32,
     surface: wgpu::Surface<'a>,
     depth_texture: Texture,
-    render_pipeline: wgpu::RenderPipeline,
+    pipelines: ScenePipelines,
+    // For meshes with blended materials, drawn after the opaque ones.
+    blended_pipelines: ScenePipelines,
+    // Kept to recreate the pipelines when the shader is reloaded.
+    render_pipeline_layout: wgpu::PipelineLayout,
+    animated_render_pipeline_layout: wgpu::PipelineLayout,
 
     camera_controller: CameraController,
     camera: Camera,
@@ -58,18 +86,51 @@ struct WgpuRenderer<'a> {
     light_uniform: LightUniform,
     light_buffer: wgpu::Buffer,
     light_bind_group: wgpu::BindGroup,
+    light_bind_group_layout: wgpu::BindGroupLayout,
+    environment_baker: environment::EnvironmentBaker,
+    environment_sampler: wgpu::Sampler,
+    // Lights the scene while no environment is set.
+    default_environment: environment::Environment,
+    // EnvironmentHandle -> environment
+    loaded_environments: Vec<environment::Environment>,
+    skybox: skybox::SkyboxPass,
+    tonemap_pass: tonemapping::TonemapPass,
     
     mouse_pressed: bool, // NEW!
 
-    texture_bind_group_layout: wgpu::BindGroupLayout,
+    material_layout: std::sync::Arc<model::MaterialLayout>,
+    animation_bind_group_layout: wgpu::BindGroupLayout,
+    texture_cache: std::sync::Arc<texture_cache::TextureCache>,
+    streamer: streaming::ModelStreamer,
+    // Drawn with the default material for models still loading.
+    placeholder_mesh: model::WgpuMesh,
+    // Only while hot reloading is on.
+    file_watcher: Option<hot_reload::FileWatcher>,
 
     // instance_groups: Vec<InstanceGroup>,
     instance_manager: InstanceManager,
     loaded_models: Vec<WgpuModel>,
+    // TextureHandle -> texture
+    loaded_textures: Vec<std::sync::Arc<Texture>>,
+    // ModelHandle -> joint palettes and morph weights, only for models with
+    // skinned or morphed meshes.
+    animated_instances: Vec<Option<AnimatedInstances>>,
+    // ModelHandle -> instance id -> animation played before the model was
+    // loaded, None once it is.
+    queued_animations: Vec<Option<std::collections::HashMap<u16, NamedAnimationState>>>,
+    // ModelHandle -> spare buffers, only for dynamic meshes.
+    dynamic_meshes: Vec<Option<DynamicMesh>>,
 }
 
 
-use super::{InstanceHandle, ModelHandle, RenderError, Renderer};
+use super::{EnvironmentHandle, Exposure, FileResolver, InstanceHandle, Material, ModelHandle, RenderError, Renderer, Skybox, TextureHandle, Tonemapper};
+
+pub use model::ModelVertex;
+pub use texture::TextureKind;
+pub use image_decoding::ImageError;
+pub use texture_cache::TextureCacheStats;
+pub use streaming::LoadProgress;
+pub use sampler::{FilterMode, SamplerDesc, TextureQuality, WrapMode};
 
 impl<'a> WgpuRenderer<'a> {
     // Creating some of the wgpu types requires async code
@@ -100,7 +161,14 @@ impl<'a> WgpuRenderer<'a> {
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    required_features: wgpu::Features::empty(),
+                    // Baked textures are BC compressed where the adapter
+                    // supports it and decompressed on the CPU otherwise,
+                    // KTX2 and DDS files may use any compressed format, but
+                    // only BC1 to BC5 have that fallback.
+                    required_features: adapter.features()
+                        & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                            | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                            | wgpu::Features::TEXTURE_COMPRESSION_ASTC),
                     // WebGL doesn't support all of wgpu's features, so if
                     // we're building for the web, we'll have to disable some.
                     required_limits: if cfg!(target_arch = "wasm32") {
@@ -137,47 +205,39 @@ impl<'a> WgpuRenderer<'a> {
         };
 
         surface.configure(&device, &config);
+        let device = std::sync::Arc::new(device);
 
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        // This should match the filterable field of the
-                        // corresponding Texture entry above.
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
-            });
+        let material_layout = std::sync::Arc::new(model::MaterialLayout {
+            bind_group_layout: model::WgpuMaterial::bind_group_layout(&device),
+            samplers: sampler::SamplerCache::new(TextureQuality::High),
+        });
+        let animation_bind_group_layout = AnimatedInstances::bind_group_layout(&device);
+        let uploads = std::sync::Arc::new(streaming::Uploads::default());
+        let texture_cache = std::sync::Arc::new(texture_cache::TextureCache::new(
+            texture::DefaultTextures::new(&device, &uploads).unwrap(),
+        ));
+        let cube = primitives::cube(1.0);
+        let placeholder_mesh = model::MeshData {
+            name: "placeholder".to_string(),
+            vertices: cube.vertices,
+            indices: cube.indices,
+            material: Some(0),
+            node: None,
+            skin_vertices: None,
+            morph_targets: vec![],
+            weight_offset: 0,
+        }
+        .upload(&device, &uploads, "placeholder", 0);
+        // Dim enough not to wash out the direct light.
+        let default_environment = environment::Environment::uniform(&device, &uploads, [0.01; 3]);
+        let environment_baker = environment::EnvironmentBaker::new(&device, &queue).unwrap();
+        let mut streamer = streaming::ModelStreamer::new(
+            device.clone(),
+            uploads,
+            material_layout.clone(),
+            texture_cache.clone(),
+        );
+        streamer.flush(&device, &queue);
 
 
         let camera = Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
@@ -226,7 +286,7 @@ impl<'a> WgpuRenderer<'a> {
             direction: [0.0, 1.0, 0.0],
             _padding: 0,
             color: [1.0, 1.0, 1.0],
-            _padding2: 0,
+            environment_max_lod: default_environment.max_lod(),
         };
 
         // We'll want to update our lights position, so we use COPY_DST
@@ -238,55 +298,92 @@ impl<'a> WgpuRenderer<'a> {
 
         let light_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    // The irradiance and prefiltered maps of the environment,
+                    // and the BRDF lookup table.
+                    environment_texture_entry(1, wgpu::TextureViewDimension::Cube),
+                    environment_texture_entry(2, wgpu::TextureViewDimension::Cube),
+                    environment_texture_entry(3, wgpu::TextureViewDimension::D2),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
                 label: None,
             });
 
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: None,
+        let environment_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("environment sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
         });
+        let light_bind_group = create_light_bind_group(
+            &device,
+            &light_bind_group_layout,
+            &light_buffer,
+            &default_environment,
+            &environment_baker.brdf_lut,
+            &environment_sampler,
+        );
 
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
-                    &texture_bind_group_layout,
+                    &material_layout.bind_group_layout,
                     &camera_bind_group_layout,
                     &light_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = {
-            let shader_src = load_string("shader.wgsl").unwrap();
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("shader.wgsl"),
-                source: wgpu::ShaderSource::Wgsl(shader_src.into()),
-            }; 
-            create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), instanced_rendering::InstanceRaw::desc()],
-                shader,
-            )
-        };
+        let animated_render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Animated Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &material_layout.bind_group_layout,
+                    &camera_bind_group_layout,
+                    &light_bind_group_layout,
+                    &animation_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let shader_src = load_string(SHADER_FILE).unwrap();
+        let pipelines = create_scene_pipelines(
+            &device,
+            &render_pipeline_layout,
+            &animated_render_pipeline_layout,
+            tonemapping::HDR_FORMAT,
+            &shader_src,
+            false,
+        );
+        let blended_pipelines = create_scene_pipelines(
+            &device,
+            &render_pipeline_layout,
+            &animated_render_pipeline_layout,
+            tonemapping::HDR_FORMAT,
+            &shader_src,
+            true,
+        );
+
+        let skybox = skybox::SkyboxPass::new(&device, tonemapping::HDR_FORMAT, texture::Texture::DEPTH_FORMAT, &default_environment).unwrap();
+        let tonemap_pass = tonemapping::TonemapPass::new(&device, config.format, config.width, config.height).unwrap();
 
         let mut res = Self {
             surface,
@@ -295,7 +392,10 @@ impl<'a> WgpuRenderer<'a> {
             config,
             width: size.width,
             height: size.height,
-            render_pipeline,
+            pipelines,
+            blended_pipelines,
+            render_pipeline_layout,
+            animated_render_pipeline_layout,
 
             camera,
             projection,
@@ -309,15 +409,31 @@ impl<'a> WgpuRenderer<'a> {
             light_uniform,
             light_buffer,
             light_bind_group,
+            light_bind_group_layout,
+            environment_baker,
+            environment_sampler,
+            default_environment,
+            loaded_environments: vec![],
+            skybox,
+            tonemap_pass,
 
             mouse_pressed: false,
 
             // instance_groups: vec![instance_group],
 
-            texture_bind_group_layout,
+            material_layout,
+            animation_bind_group_layout,
+            texture_cache,
+            streamer,
+            placeholder_mesh,
+            file_watcher: None,
             instance_manager: InstanceManager::new(),
 
             loaded_models: vec![],
+            loaded_textures: vec![],
+            animated_instances: vec![],
+            queued_animations: vec![],
+            dynamic_meshes: vec![],
         };
         let model_handle = res.load_model("backpack.obj", 102).await.unwrap();
         const NUM_INSTANCES_PER_ROW: u32 = 10;
@@ -357,6 +473,145 @@ impl<'a> WgpuRenderer<'a> {
 
         res
     }
+
+    fn texture(&self, handle: Option<TextureHandle>, fallback: &std::sync::Arc<Texture>) -> anyhow::Result<std::sync::Arc<Texture>> {
+        match handle {
+            Some(TextureHandle(index)) => self
+                .loaded_textures
+                .get(index as usize)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Unknown texture {}", index)),
+            None => Ok(fallback.clone()),
+        }
+    }
+
+    fn create_material(&self, material: &Material) -> anyhow::Result<model::WgpuMaterial> {
+        let defaults = &self.texture_cache.defaults;
+        let textures = model::MaterialTextures {
+            base_color: self.texture(material.base_color_texture, &defaults.white)?,
+            normal: self.texture(material.normal_texture, &defaults.normal)?,
+            metallic_roughness: self.texture(material.metallic_roughness_texture, &defaults.white)?,
+            occlusion: self.texture(material.occlusion_texture, &defaults.white)?,
+            emissive: self.texture(material.emissive_texture, &defaults.white)?,
+        };
+        let mut uniform = model::MaterialUniform::new();
+        uniform.base_color_factor = material.base_color_factor;
+        uniform.metallic_factor = material.metallic_factor;
+        uniform.roughness_factor = material.roughness_factor;
+        uniform.emissive_factor = material.emissive_factor;
+        Ok(model::WgpuMaterial::new(
+            &self.device,
+            "runtime material",
+            textures,
+            model::MaterialSamplers::all(material.sampler),
+            uniform,
+            &self.material_layout,
+        ))
+    }
+
+    // Registers a loaded model with room for max_instances instances.
+    fn add_model(&mut self, model: WgpuModel, max_instances: u16) -> ModelHandle {
+        let model_handle = ModelHandle(self.loaded_models.len() as u16);
+        let animated = AnimatedInstances::is_animated(&model).then(|| {
+            AnimatedInstances::new(&self.device, &self.animation_bind_group_layout, &model, max_instances)
+        });
+        self.animated_instances.push(animated);
+        self.queued_animations.push(None);
+        self.dynamic_meshes.push(None);
+        self.loaded_models.push(model);
+        self.instance_manager.add_instance_group(&self.device, model_handle.0, max_instances);
+        model_handle
+    }
+
+    // Reloads the watched files that changed. Reloaded models keep their
+    // handles and instances, the old data is drawn until the new one is
+    // uploaded. Baked data older than the changed sources is skipped, see
+    // manifest::load_baked.
+    fn reload_changed_files(&mut self) {
+        let Some(watcher) = &mut self.file_watcher else {
+            return;
+        };
+        let changed = watcher.changed();
+        if changed.is_empty() {
+            return;
+        }
+        if changed.iter().any(|path| path == SHADER_FILE) {
+            self.reload_shader();
+        }
+        for (i, model) in self.loaded_models.iter().enumerate() {
+            if !model.sources.iter().any(|source| changed.contains(source)) {
+                continue;
+            }
+            // Unchanged textures are found again by their content.
+            for source in &model.sources {
+                self.texture_cache.forget(source);
+            }
+            log::info!("Reloading {}", model.sources[0]);
+            self.streamer.load(i as u16, &model.sources[0]);
+        }
+    }
+
+    // Keeps the previous pipelines if the new shader doesn't compile.
+    fn reload_shader(&mut self) {
+        let shader_src = match load_string(SHADER_FILE) {
+            Ok(shader_src) => shader_src,
+            Err(e) => {
+                log::error!("Could not read {}: {:#}", SHADER_FILE, e);
+                return;
+            }
+        };
+        // Only errors of the new pipelines end up in the scope.
+        let _paused = self.streamer.pause();
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let create = |blended| create_scene_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            &self.animated_render_pipeline_layout,
+            tonemapping::HDR_FORMAT,
+            &shader_src,
+            blended,
+        );
+        let pipelines = (create(false), create(true));
+        if let Some(e) = streaming::block_on(self.device.pop_error_scope()) {
+            log::error!("Could not compile {}, keeping the previous one: {}", SHADER_FILE, e);
+            return;
+        }
+        (self.pipelines, self.blended_pipelines) = pipelines;
+        log::info!("Reloaded {}", SHADER_FILE);
+    }
+
+    // Draws the meshes whose materials are blended, or the opaque ones.
+    fn draw_meshes<'p>(&'p self, render_pass: &mut wgpu::RenderPass<'p>, blended: bool) {
+        let pipelines = if blended { &self.blended_pipelines } else { &self.pipelines };
+        for (i, instance_group) in self.instance_manager.instance_groups.iter().enumerate() {
+            render_pass.set_vertex_buffer(1, instance_group.buffer().slice(..));
+            // let model = &self.loaded_models[instance_group.model_handle.0];
+            let model = &self.loaded_models[i];
+            let animated = self.animated_instances[i].as_ref();
+            for (mesh_index, mesh) in model.meshes.iter().enumerate() { 
+                let material = &model.materials[mesh.material]; // TODO: Cache material bind-groups between draw-calls?
+                if material.blended != blended {
+                    continue;
+                }
+                match (animated.and_then(|a| a.mesh_bind_group(mesh_index)), &mesh.skin_buffer) {
+                    (Some(animation_bind_group), Some(skin_buffer)) => {
+                        render_pass.set_pipeline(&pipelines.skinned);
+                        render_pass.set_bind_group(3, animation_bind_group, &[]);
+                        render_pass.set_vertex_buffer(2, skin_buffer.slice(..));
+                    }
+                    (Some(animation_bind_group), None) => {
+                        render_pass.set_pipeline(&pipelines.morphed);
+                        render_pass.set_bind_group(3, animation_bind_group, &[]);
+                    }
+                    _ => render_pass.set_pipeline(&pipelines.render),
+                }
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.set_bind_group(0, &material.bind_group, &[]);
+                render_pass.draw_indexed(0..mesh.num_elements, 0, 0..instance_group.len() as u32);  // TODO: Reimplement instancing
+            }
+        }
+    }
 }
 
 impl Renderer for WgpuRenderer<'_> {
@@ -377,6 +632,7 @@ impl Renderer for WgpuRenderer<'_> {
             self.surface.configure(&self.device, &self.config);
             self.depth_texture =
                 texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+            self.tonemap_pass.resize(&self.device, width, height);
         }
     }
 
@@ -431,7 +687,53 @@ impl Renderer for WgpuRenderer<'_> {
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
         );
+        self.skybox.update(&self.queue, &self.camera, &self.projection);
+        self.tonemap_pass.update(&self.queue, dt);
+
+        // Loaded models replace their placeholders, keeping their instances
+        // and what they are playing.
+        for (handle, mut model) in self.streamer.update(&self.device, &self.queue) {
+            let i = handle as usize;
+            // The texture quality may have changed while it was loading.
+            for material in &mut model.materials {
+                material.rebind(&self.device, &self.material_layout);
+            }
+            if let Some(watcher) = &mut self.file_watcher {
+                for source in &model.sources {
+                    watcher.watch(source);
+                }
+            }
+            let states = match self.queued_animations[i].take() {
+                Some(queued) => queued,
+                None => self.animated_instances[i]
+                    .as_ref()
+                    .map(|animated| animated.named_states(&self.loaded_models[i]))
+                    .unwrap_or_default(),
+            };
+            let max_instances = self.instance_manager.instance_groups[i].max_instances();
+            let mut animated = AnimatedInstances::is_animated(&model).then(|| {
+                AnimatedInstances::new(&self.device, &self.animation_bind_group_layout, &model, max_instances)
+            });
+            for (instance_id, state) in states {
+                let clip = model.animations.iter().position(|animation| animation.name == state.clip);
+                match (&mut animated, clip) {
+                    (Some(animated), Some(clip)) => {
+                        animated.play(instance_id, AnimationState { clip, time: state.time, looping: state.looping })
+                    }
+                    _ => log::warn!("{} has no animation {:?} to play", model.sources[0], state.clip),
+                }
+            }
+            self.animated_instances[i] = animated;
+            self.loaded_models[i] = model;
+        }
+        self.reload_changed_files();
 
+        let dt = dt.as_secs_f32();
+        for (i, animated) in self.animated_instances.iter_mut().enumerate() {
+            if let Some(animated) = animated {
+                animated.update(&self.queue, &self.loaded_models[i], &self.instance_manager.instance_groups[i], dt);
+            }
+        }
     }
 
     fn render(&mut self) -> Result<(), RenderError> {
@@ -461,15 +763,10 @@ impl Renderer for WgpuRenderer<'_> {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view: self.tonemap_pass.target_view(),
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(self.skybox.clear_color()),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -485,22 +782,15 @@ impl Renderer for WgpuRenderer<'_> {
                 timestamp_writes: None,
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
             render_pass.set_bind_group(2, &self.light_bind_group, &[]);
-            for (i, instance_group) in self.instance_manager.instance_groups.iter().enumerate() {
-                render_pass.set_vertex_buffer(1, instance_group.buffer().slice(..));
-                // let model = &self.loaded_models[instance_group.model_handle.0];
-                let model = &self.loaded_models[i];
-                for mesh in &model.meshes { 
-                    let material = &model.materials[mesh.material]; // TODO: Cache material bind-groups between draw-calls?
-                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                    render_pass.set_bind_group(0, &material.bind_group, &[]);
-                    render_pass.draw_indexed(0..mesh.num_elements, 0, 0..instance_group.len() as u32);  // TODO: Reimplement instancing
-                }
-            }
+            self.draw_meshes(&mut render_pass, false);
+            self.skybox.draw(&mut render_pass);
+            // Blended meshes aren't sorted, overlapping ones may blend in the
+            // wrong order.
+            self.draw_meshes(&mut render_pass, true);
         }
+        self.tonemap_pass.draw(&mut encoder, &view);
 
         self.queue.submit(std::iter::once(encoder.finish()));
         output.present();
@@ -511,39 +801,374 @@ impl Renderer for WgpuRenderer<'_> {
     fn load_model<'a>(&'a mut self, file_path: &'a str, max_instances: u16) -> Pin<Box<dyn Future<Output = anyhow::Result<ModelHandle>> + Send + 'a>> {
         Box::pin(
             async move {
+                let placeholder = model::WgpuModel {
+                    meshes: vec![self.placeholder_mesh.clone()],
+                    materials: vec![resources::default_material(&self.texture_cache, &self.device, &self.material_layout)],
+                    nodes: vec![],
+                    skins: vec![],
+                    animations: vec![],
+                    sources: vec![],
+                };
+                let handle = self.add_model(placeholder, max_instances);
+                self.queued_animations[handle.0 as usize] = Some(Default::default());
+                self.streamer.load(handle.0, file_path);
+                Ok(handle)
+            }
+        )
+    }
 
-                let model = load_wgpu_model(file_path, &self.device, &self.queue, &self.texture_bind_group_layout).await?;
-                let model_handle = ModelHandle(self.loaded_models.len() as u16);
-                self.loaded_models.push(model);
-                self.instance_manager.add_instance_group(&mut self.device, model_handle.0, max_instances);
-                Ok(model_handle)
+    fn load_model_from_bytes<'a>(
+        &'a mut self,
+        obj: &'a [u8],
+        mtl_resolver: &'a FileResolver<'a>,
+        texture_resolver: &'a FileResolver<'a>,
+        max_instances: u16,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<ModelHandle>> + Send + 'a>> {
+        Box::pin(
+            async move {
+                let model = resources::load_obj_model_from_bytes(
+                    obj,
+                    mtl_resolver,
+                    texture_resolver,
+                    &self.device,
+                    self.streamer.uploads(),
+                    &self.material_layout,
+                    &self.texture_cache,
+                ).await?;
+                self.streamer.flush(&self.device, &self.queue);
+                Ok(self.add_model(model, max_instances))
             }
         )
     }
 
+    fn load_texture_from_bytes(&mut self, bytes: &[u8], label: &str, kind: TextureKind) -> anyhow::Result<TextureHandle> {
+        // The same image loaded twice gets two handles to one texture.
+        let texture = self.texture_cache.load(
+            None,
+            kind,
+            || Ok(bytes.to_vec()),
+            |bytes| Texture::from_bytes(&self.device, self.streamer.uploads(), bytes, label, kind),
+        )?;
+        self.streamer.flush(&self.device, &self.queue);
+        let handle = TextureHandle(self.loaded_textures.len() as u32);
+        self.loaded_textures.push(texture);
+        Ok(handle)
+    }
+
+    fn texture_cache_stats(&self) -> TextureCacheStats {
+        self.texture_cache.stats()
+    }
+
+    fn load_progress(&self) -> LoadProgress {
+        self.streamer.progress()
+    }
+
+    fn set_hot_reload(&mut self, enabled: bool) {
+        if !enabled {
+            self.file_watcher = None;
+            return;
+        }
+        if self.file_watcher.is_some() {
+            return;
+        }
+        let mut watcher = hot_reload::FileWatcher::new();
+        watcher.watch(SHADER_FILE);
+        for source in self.loaded_models.iter().flat_map(|model| &model.sources) {
+            watcher.watch(source);
+        }
+        self.file_watcher = Some(watcher);
+    }
+
+    fn load_environment(&mut self, file_path: &str) -> anyhow::Result<EnvironmentHandle> {
+        let bytes = resources::load_binary(file_path)?;
+        let equirectangular = Texture::from_bytes(&self.device, self.streamer.uploads(), &bytes, file_path, TextureKind::Color)?;
+        self.streamer.flush(&self.device, &self.queue);
+        let environment = self.environment_baker.bake(&self.device, &self.queue, &equirectangular, file_path);
+        let handle = EnvironmentHandle(self.loaded_environments.len() as u32);
+        self.loaded_environments.push(environment);
+        Ok(handle)
+    }
+
+    fn set_environment(&mut self, environment: Option<EnvironmentHandle>) -> anyhow::Result<()> {
+        let environment = match environment {
+            Some(EnvironmentHandle(index)) => self
+                .loaded_environments
+                .get(index as usize)
+                .ok_or_else(|| anyhow::anyhow!("Unknown environment {}", index))?,
+            None => &self.default_environment,
+        };
+        self.light_uniform.environment_max_lod = environment.max_lod();
+        self.light_bind_group = create_light_bind_group(
+            &self.device,
+            &self.light_bind_group_layout,
+            &self.light_buffer,
+            environment,
+            &self.environment_baker.brdf_lut,
+            &self.environment_sampler,
+        );
+        Ok(())
+    }
+
+    fn set_skybox(&mut self, skybox: Skybox) -> anyhow::Result<()> {
+        match skybox {
+            Skybox::Color(color) => self.skybox.set_color(color),
+            Skybox::Gradient { zenith, horizon, ground } => self.skybox.set_gradient(zenith, horizon, ground),
+            Skybox::Environment(EnvironmentHandle(index)) => {
+                let environment = self
+                    .loaded_environments
+                    .get(index as usize)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown environment {}", index))?;
+                self.skybox.set_environment(&self.device, environment);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_tonemapper(&mut self, tonemapper: Tonemapper) {
+        self.tonemap_pass.set_tonemapper(tonemapper);
+    }
+
+    fn set_exposure(&mut self, exposure: Exposure) {
+        self.tonemap_pass.set_exposure(exposure);
+    }
+
+    fn set_texture_quality(&mut self, quality: TextureQuality) {
+        if !self.material_layout.samplers.set_quality(quality) {
+            return;
+        }
+        for model in &mut self.loaded_models {
+            for material in &mut model.materials {
+                material.rebind(&self.device, &self.material_layout);
+            }
+        }
+    }
+
+    fn create_mesh(
+        &mut self,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: &Material,
+        generate_tangents: bool,
+        max_instances: u16,
+    ) -> anyhow::Result<ModelHandle> {
+        let material = self.create_material(material)?;
+        let model = resources::create_mesh_model(vertices, indices, material, generate_tangents, &self.device, self.streamer.uploads())?;
+        self.streamer.flush(&self.device, &self.queue);
+        Ok(self.add_model(model, max_instances))
+    }
+
+    fn create_dynamic_mesh(
+        &mut self,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: &Material,
+        max_instances: u16,
+    ) -> anyhow::Result<ModelHandle> {
+        resources::check_triangles(vertices.len(), indices)?;
+        let material = self.create_material(material)?;
+        let (mesh, dynamic) = DynamicMesh::new(&self.device, &self.queue, vertices, indices, 0);
+        let model = WgpuModel {
+            meshes: vec![mesh],
+            materials: vec![material],
+            nodes: vec![],
+            skins: vec![],
+            animations: vec![],
+            sources: vec![],
+        };
+        let handle = self.add_model(model, max_instances);
+        self.dynamic_meshes[handle.0 as usize] = Some(dynamic);
+        Ok(handle)
+    }
+
+    fn update_dynamic_mesh(&mut self, model: ModelHandle, vertices: &[ModelVertex], indices: &[u32]) -> anyhow::Result<()> {
+        let Some(dynamic) = self.dynamic_meshes.get_mut(model.0 as usize).and_then(Option::as_mut) else {
+            anyhow::bail!("Model {} is not a dynamic mesh", model.0);
+        };
+        resources::check_triangles(vertices.len(), indices)?;
+        let mesh = &mut self.loaded_models[model.0 as usize].meshes[0];
+        dynamic.update(&self.device, &self.queue, mesh, vertices, indices);
+        Ok(())
+    }
+
     fn add_instance(&mut self, model: ModelHandle, instance: &Instance) -> InstanceHandle {
         self.instance_manager.add_instance(&self.queue ,model, instance)
     }
 
     fn update_instance(&mut self, model: InstanceHandle, instance: &Instance) {
-        let _ = instance;
-        let _ = model;
-        todo!()
+        self.instance_manager.update_instance(&self.queue, model, instance);
     }
 
     fn remove_instance(&mut self, model: InstanceHandle) {
-        let _ = model;
-        todo!()
+        self.instance_manager.delete_instance(&self.queue, model);
+    }
+
+    fn play_animation(&mut self, instance: &InstanceHandle, clip: &str, start_time: f32, looping: bool) -> anyhow::Result<()> {
+        let InstanceHandle(model, instance_id) = instance;
+        // Checked against the model once it is loaded.
+        if let Some(queued) = &mut self.queued_animations[model.0 as usize] {
+            let state = NamedAnimationState { clip: clip.to_string(), time: start_time, looping };
+            queued.insert(*instance_id, state);
+            return Ok(());
+        }
+        let Some(animated) = &mut self.animated_instances[model.0 as usize] else {
+            anyhow::bail!("Model {} has no skins or morph targets", model.0);
+        };
+        let clip = self.loaded_models[model.0 as usize]
+            .animations
+            .iter()
+            .position(|animation| animation.name == clip)
+            .ok_or_else(|| anyhow::anyhow!("Model {} has no animation {:?}", model.0, clip))?;
+        animated.play(*instance_id, AnimationState { clip, time: start_time, looping });
+        Ok(())
+    }
+
+    fn stop_animation(&mut self, instance: &InstanceHandle) {
+        let InstanceHandle(model, instance_id) = instance;
+        if let Some(queued) = &mut self.queued_animations[model.0 as usize] {
+            queued.remove(instance_id);
+        }
+        if let Some(animated) = &mut self.animated_instances[model.0 as usize] {
+            animated.stop(*instance_id);
+        }
     }
 }
 
-fn create_render_pipeline(
+fn environment_texture_entry(binding: u32, view_dimension: wgpu::TextureViewDimension) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    }
+}
+
+// The light and the maps of the environment lighting the scene.
+fn create_light_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    light_buffer: &wgpu::Buffer,
+    environment: &environment::Environment,
+    brdf_lut: &Texture,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&environment.irradiance.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&environment.prefiltered.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&brdf_lut.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: None,
+    })
+}
+
+// The pipelines of static, skinned and morphed meshes, which share the
+// scene shader.
+struct ScenePipelines {
+    render: wgpu::RenderPipeline,
+    skinned: wgpu::RenderPipeline,
+    morphed: wgpu::RenderPipeline,
+}
+
+fn create_scene_pipelines(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
+    animated_layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
+    shader_src: &str,
+    blended: bool,
+) -> ScenePipelines {
+    let shader = || wgpu::ShaderModuleDescriptor {
+        label: Some(SHADER_FILE),
+        source: wgpu::ShaderSource::Wgsl(shader_src.into()),
+    };
+    let color_target = wgpu::ColorTargetState {
+        format: color_format,
+        blend: Some(if blended {
+            wgpu::BlendState::ALPHA_BLENDING
+        } else {
+            wgpu::BlendState {
+                alpha: wgpu::BlendComponent::REPLACE,
+                color: wgpu::BlendComponent::REPLACE,
+            }
+        }),
+        write_mask: wgpu::ColorWrites::ALL,
+    };
+    let depth_stencil = wgpu::DepthStencilState {
+        format: texture::Texture::DEPTH_FORMAT,
+        // Blended surfaces don't hide what is drawn behind them later.
+        depth_write_enabled: !blended,
+        depth_compare: wgpu::CompareFunction::Less,
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    };
+    let render_pipeline = create_render_pipeline(
+        device,
+        layout,
+        color_target.clone(),
+        Some(depth_stencil.clone()),
+        &[model::ModelVertex::desc(), instanced_rendering::InstanceRaw::desc()],
+        shader(),
+        "vs_main",
+    );
+    let skinned_render_pipeline = create_render_pipeline(
+        device,
+        animated_layout,
+        color_target.clone(),
+        Some(depth_stencil.clone()),
+        &[
+            model::ModelVertex::desc(),
+            instanced_rendering::InstanceRaw::desc(),
+            model::SkinVertex::desc(),
+        ],
+        shader(),
+        "vs_skinned",
+    );
+    let morphed_render_pipeline = create_render_pipeline(
+        device,
+        animated_layout,
+        color_target,
+        Some(depth_stencil),
+        &[model::ModelVertex::desc(), instanced_rendering::InstanceRaw::desc()],
+        shader(),
+        "vs_morphed",
+    );
+    ScenePipelines {
+        render: render_pipeline,
+        skinned: skinned_render_pipeline,
+        morphed: morphed_render_pipeline,
+    }
+}
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    color_target: wgpu::ColorTargetState,
+    depth_stencil: Option<wgpu::DepthStencilState>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
+    vertex_entry_point: &str,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
 
@@ -552,21 +1177,14 @@ fn create_render_pipeline(
         layout: Some(layout),
         vertex: wgpu::VertexState {
             module: &shader,
-            entry_point: "vs_main",
+            entry_point: vertex_entry_point,
             buffers: vertex_layouts,
             compilation_options: Default::default(),
         },
         fragment: Some(wgpu::FragmentState {
             module: &shader,
             entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
+            targets: &[Some(color_target)],
             compilation_options: Default::default(),
         }),
         primitive: wgpu::PrimitiveState {
@@ -581,13 +1199,7 @@ fn create_render_pipeline(
             // Requires Features::CONSERVATIVE_RASTERIZATION
             conservative: false,
         },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-            format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState::default(),
-        }),
+        depth_stencil,
         multisample: wgpu::MultisampleState {
             count: 1,
             mask: !0,
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use cgmath::SquareMatrix;
+use wgpu::util::DeviceExt;
+
+use super::{animation, instanced_rendering::InstanceGroup, model};
+
+type JointMatrix = [[f32; 4]; 4];
+
+// Start of the joint palette and morph weight buffers, the per-instance data
+// follows at a 16 byte offset.
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct InstanceDataHeader {
+    values_per_instance: u32,
+    _padding: [u32; 3],
+}
+
+const HEADER_SIZE: u64 = size_of::<InstanceDataHeader>() as u64;
+
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationState {
+    // Index into WgpuModel::animations.
+    pub clip: usize,
+    pub time: f32,
+    pub looping: bool,
+}
+
+// An AnimationState naming its clip, which outlives the model it was played
+// on: it is started again once the model is loaded or reloaded.
+#[derive(Clone, Debug)]
+pub struct NamedAnimationState {
+    pub clip: String,
+    pub time: f32,
+    pub looping: bool,
+}
+
+// A buffer with values_per_instance values for every instance. Storage
+// buffers can't be empty, so it always has room for at least one value.
+fn create_instance_buffer<T: bytemuck::Pod>(
+    device: &wgpu::Device,
+    label: &str,
+    rest: &[T],
+    fallback: T,
+    max_instances: u16,
+) -> wgpu::Buffer {
+    let header = InstanceDataHeader {
+        values_per_instance: rest.len() as u32,
+        _padding: [0; 3],
+    };
+    let mut contents = bytemuck::bytes_of(&header).to_vec();
+    for _ in 0..max_instances {
+        contents.extend_from_slice(bytemuck::cast_slice(rest));
+    }
+    if rest.is_empty() || max_instances == 0 {
+        contents.extend_from_slice(bytemuck::bytes_of(&fallback));
+    }
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: &contents,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+// Joint matrices and morph target weights of every instance of an animated
+// model. The instance at position i of the instance buffer reads its values
+// starting at i * values_per_instance.
+pub struct AnimatedInstances {
+    joint_buffer: wgpu::Buffer,
+    weight_buffer: wgpu::Buffer,
+    // MeshIndex -> bind group, only for meshes that are skinned or morphed.
+    mesh_bind_groups: Vec<Option<wgpu::BindGroup>>,
+    max_instances: usize,
+    // Instances that aren't playing anything stay in the rest pose.
+    states: HashMap<u16, AnimationState>, // Instance id -> state
+    rest_joints: Vec<JointMatrix>,
+    rest_weights: Vec<f32>,
+    joints: Vec<JointMatrix>,
+    weights: Vec<f32>,
+}
+
+impl AnimatedInstances {
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                // Joint palette
+                storage_entry(0),
+                // Morph weights
+                storage_entry(1),
+                // Morph targets of the mesh
+                storage_entry(2),
+            ],
+            label: Some("animation_bind_group_layout"),
+        })
+    }
+
+    // Models whose meshes neither have skins nor morph targets don't need any.
+    pub fn is_animated(model: &model::WgpuModel) -> bool {
+        model
+            .meshes
+            .iter()
+            .any(|mesh| mesh.skin_buffer.is_some() || mesh.morph_targets.is_some())
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        model: &model::WgpuModel,
+        max_instances: u16,
+    ) -> Self {
+        let pose = animation::sample_pose(&model.nodes, None, 0.0);
+        let globals = animation::global_transforms(&model.nodes, &pose);
+        let mut rest_joints = Vec::new();
+        animation::joint_matrices(&model.skins, &globals, &mut rest_joints);
+        let rest_weights = pose.weights;
+
+        let identity = cgmath::Matrix4::<f32>::identity().into();
+        let joint_buffer = create_instance_buffer(device, "joint palette buffer", &rest_joints, identity, max_instances);
+        let weight_buffer = create_instance_buffer(device, "morph weight buffer", &rest_weights, 0.0, max_instances);
+
+        // Stands in for the morph targets of meshes without any.
+        let mut contents = bytemuck::bytes_of(&model::MorphTargetsHeader {
+            num_targets: 0,
+            num_vertices: 0,
+            weight_offset: 0,
+            _padding: 0,
+        })
+        .to_vec();
+        contents.extend_from_slice(bytemuck::bytes_of(&model::MorphVertex::default()));
+        let no_morph_targets = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("empty morph target buffer"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let mesh_bind_groups = model
+            .meshes
+            .iter()
+            .map(|mesh| {
+                if mesh.skin_buffer.is_none() && mesh.morph_targets.is_none() {
+                    return None;
+                }
+                let morph_targets = mesh.morph_targets.as_deref().unwrap_or(&no_morph_targets);
+                Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: joint_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: weight_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: morph_targets.as_entire_binding(),
+                        },
+                    ],
+                    label: Some(&format!("{} animation_bind_group", mesh.name)),
+                }))
+            })
+            .collect();
+
+        Self {
+            joint_buffer,
+            weight_buffer,
+            mesh_bind_groups,
+            max_instances: max_instances as usize,
+            states: HashMap::new(),
+            rest_joints,
+            rest_weights,
+            joints: Vec::new(),
+            weights: Vec::new(),
+        }
+    }
+
+    pub fn mesh_bind_group(&self, mesh: usize) -> Option<&wgpu::BindGroup> {
+        self.mesh_bind_groups.get(mesh)?.as_ref()
+    }
+
+    pub fn play(&mut self, instance_id: u16, state: AnimationState) {
+        self.states.insert(instance_id, state);
+    }
+
+    pub fn stop(&mut self, instance_id: u16) {
+        self.states.remove(&instance_id);
+    }
+
+    // What every instance is playing, by the clip names of model.
+    pub fn named_states(&self, model: &model::WgpuModel) -> HashMap<u16, NamedAnimationState> {
+        self.states
+            .iter()
+            .map(|(id, state)| {
+                let named = NamedAnimationState {
+                    clip: model.animations[state.clip].name.clone(),
+                    time: state.time,
+                    looping: state.looping,
+                };
+                (*id, named)
+            })
+            .collect()
+    }
+
+    // Advances every playing clip by dt and uploads the joint matrices and
+    // morph weights of all instances.
+    pub fn update(&mut self, queue: &wgpu::Queue, model: &model::WgpuModel, instances: &InstanceGroup, dt: f32) {
+        // Forget instances that were removed.
+        self.states.retain(|id, _| instances.instance_index(*id).is_some());
+
+        let num_instances = (instances.len() as usize).min(self.max_instances);
+        if num_instances == 0 {
+            return;
+        }
+
+        self.joints.clear();
+        self.weights.clear();
+        for _ in 0..num_instances {
+            self.joints.extend_from_slice(&self.rest_joints);
+            self.weights.extend_from_slice(&self.rest_weights);
+        }
+
+        let mut joints = Vec::with_capacity(self.rest_joints.len());
+        for (id, state) in self.states.iter_mut() {
+            let clip = &model.animations[state.clip];
+            state.time += dt;
+            if state.looping && clip.duration > 0.0 {
+                state.time %= clip.duration;
+            } else {
+                state.time = state.time.min(clip.duration);
+            }
+
+            let Some(index) = instances.instance_index(*id).map(usize::from) else {
+                continue;
+            };
+            if index >= num_instances {
+                continue;
+            }
+
+            let pose = animation::sample_pose(&model.nodes, Some(clip), state.time);
+            let globals = animation::global_transforms(&model.nodes, &pose);
+            joints.clear();
+            animation::joint_matrices(&model.skins, &globals, &mut joints);
+
+            let start = index * joints.len();
+            self.joints[start..start + joints.len()].copy_from_slice(&joints);
+            let start = index * pose.weights.len();
+            self.weights[start..start + pose.weights.len()].copy_from_slice(&pose.weights);
+        }
+
+        if !self.joints.is_empty() {
+            queue.write_buffer(&self.joint_buffer, HEADER_SIZE, bytemuck::cast_slice(&self.joints));
+        }
+        if !self.weights.is_empty() {
+            queue.write_buffer(&self.weight_buffer, HEADER_SIZE, bytemuck::cast_slice(&self.weights));
+        }
+    }
+}
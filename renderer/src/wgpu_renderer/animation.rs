@@ -0,0 +1,293 @@
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3};
+
+use super::model::ModelNode;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Property {
+    Translation,
+    Rotation,
+    Scale,
+    // Morph target weights of the meshes of the node.
+    Weights,
+}
+
+pub struct Sampler {
+    pub interpolation: Interpolation,
+    // Keyframe times in seconds, increasing.
+    pub times: Vec<f32>,
+    // Keyframe values of `components` floats each. Cubic splines store an
+    // in-tangent, the value and an out-tangent for every keyframe.
+    pub values: Vec<f32>,
+    pub components: usize,
+}
+
+impl Sampler {
+    fn element(&self, index: usize) -> &[f32] {
+        &self.values[index * self.components..(index + 1) * self.components]
+    }
+
+    fn value(&self, keyframe: usize) -> &[f32] {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.element(keyframe * 3 + 1),
+            _ => self.element(keyframe),
+        }
+    }
+
+    // Writes the value at the given time into out. Rotations (spherical) are
+    // slerped and kept normalized.
+    pub fn sample(&self, time: f32, spherical: bool, out: &mut [f32]) {
+        let Some(&last_time) = self.times.last() else {
+            return;
+        };
+        if self.times.len() == 1 || time <= self.times[0] {
+            out.copy_from_slice(self.value(0));
+            return;
+        }
+        if time >= last_time {
+            out.copy_from_slice(self.value(self.times.len() - 1));
+            return;
+        }
+
+        // times[i] <= time < times[i + 1]
+        let i = self.times.partition_point(|t| *t <= time) - 1;
+        let dt = self.times[i + 1] - self.times[i];
+        let u = if dt > 0.0 { (time - self.times[i]) / dt } else { 0.0 };
+
+        match self.interpolation {
+            Interpolation::Step => out.copy_from_slice(self.value(i)),
+            Interpolation::Linear if spherical => {
+                let q = slerp(to_quaternion(self.value(i)), to_quaternion(self.value(i + 1)), u);
+                out.copy_from_slice(&[q.v.x, q.v.y, q.v.z, q.s]);
+            }
+            Interpolation::Linear => {
+                for (o, (a, b)) in out.iter_mut().zip(self.value(i).iter().zip(self.value(i + 1))) {
+                    *o = a + (b - a) * u;
+                }
+            }
+            Interpolation::CubicSpline => {
+                // Hermite spline with tangents scaled by the keyframe delta.
+                let (u2, u3) = (u * u, u * u * u);
+                let h00 = 2.0 * u3 - 3.0 * u2 + 1.0;
+                let h10 = u3 - 2.0 * u2 + u;
+                let h01 = -2.0 * u3 + 3.0 * u2;
+                let h11 = u3 - u2;
+                let v0 = self.value(i);
+                let b0 = self.element(i * 3 + 2);
+                let v1 = self.value(i + 1);
+                let a1 = self.element((i + 1) * 3);
+                for (c, o) in out.iter_mut().enumerate() {
+                    *o = h00 * v0[c] + h10 * dt * b0[c] + h01 * v1[c] + h11 * dt * a1[c];
+                }
+                if spherical {
+                    let q = to_quaternion(out).normalize();
+                    out.copy_from_slice(&[q.v.x, q.v.y, q.v.z, q.s]);
+                }
+            }
+        }
+    }
+}
+
+// glTF stores quaternions as (x, y, z, w).
+fn to_quaternion(v: &[f32]) -> Quaternion<f32> {
+    Quaternion::new(v[3], v[0], v[1], v[2])
+}
+
+fn slerp(a: Quaternion<f32>, b: Quaternion<f32>, u: f32) -> Quaternion<f32> {
+    // Take the shortest path.
+    let (b, cos_theta) = if a.dot(b) < 0.0 { (-b, -a.dot(b)) } else { (b, a.dot(b)) };
+    if cos_theta > 0.9995 {
+        // Nearly identical rotations, fall back to a normalized lerp.
+        return (a * (1.0 - u) + b * u).normalize();
+    }
+    let theta = cos_theta.acos();
+    let sin_theta = theta.sin();
+    let wa = ((1.0 - u) * theta).sin() / sin_theta;
+    let wb = (u * theta).sin() / sin_theta;
+    (a * wa + b * wb).normalize()
+}
+
+pub struct Channel {
+    // Index into WgpuModel::nodes.
+    pub node: usize,
+    pub property: Property,
+    pub sampler: Sampler,
+}
+
+pub struct AnimationClip {
+    pub name: String,
+    // Time of the last keyframe of any channel.
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+pub struct Skin {
+    // Indices into WgpuModel::nodes.
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<[[f32; 4]; 4]>,
+    // Where the joints of this skin start in the model's joint palette,
+    // joint indices of skinned vertices already include it.
+    pub joint_offset: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct NodeTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl NodeTransform {
+    pub fn from_node(node: &ModelNode) -> Self {
+        Self {
+            translation: node.translation.into(),
+            rotation: to_quaternion(&node.rotation),
+            scale: node.scale.into(),
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+pub struct Pose {
+    // Local transform of every node.
+    pub transforms: Vec<NodeTransform>,
+    // Morph target weights of all nodes, each node's starting at its
+    // weight_offset.
+    pub weights: Vec<f32>,
+}
+
+// The clip (if any) applied on top of the rest pose.
+pub fn sample_pose(nodes: &[ModelNode], clip: Option<&AnimationClip>, time: f32) -> Pose {
+    let mut pose = Pose {
+        transforms: nodes.iter().map(NodeTransform::from_node).collect(),
+        weights: nodes.iter().flat_map(|node| node.weights.iter().copied()).collect(),
+    };
+    for channel in clip.iter().flat_map(|clip| &clip.channels) {
+        let transform = &mut pose.transforms[channel.node];
+        let mut value = [0.0; 4];
+        match channel.property {
+            Property::Translation => {
+                channel.sampler.sample(time, false, &mut value[..3]);
+                transform.translation = Vector3::new(value[0], value[1], value[2]);
+            }
+            Property::Rotation => {
+                channel.sampler.sample(time, true, &mut value);
+                transform.rotation = to_quaternion(&value);
+            }
+            Property::Scale => {
+                channel.sampler.sample(time, false, &mut value[..3]);
+                transform.scale = Vector3::new(value[0], value[1], value[2]);
+            }
+            Property::Weights => {
+                let node = &nodes[channel.node];
+                let start = node.weight_offset as usize;
+                channel.sampler.sample(time, false, &mut pose.weights[start..start + node.weights.len()]);
+            }
+        }
+    }
+    pose
+}
+
+// Model space transforms of every node.
+pub fn global_transforms(nodes: &[ModelNode], pose: &Pose) -> Vec<Matrix4<f32>> {
+    let mut globals = vec![Matrix4::identity(); nodes.len()];
+    let mut stack = (0..nodes.len())
+        .filter(|i| nodes[*i].parent.is_none())
+        .map(|i| (i, Matrix4::identity()))
+        .collect::<Vec<_>>();
+    while let Some((node, parent_transform)) = stack.pop() {
+        globals[node] = parent_transform * pose.transforms[node].matrix();
+        stack.extend(nodes[node].children.iter().map(|child| (*child, globals[node])));
+    }
+    globals
+}
+
+// Appends the joint matrices of all skins, in palette order.
+pub fn joint_matrices(skins: &[Skin], globals: &[Matrix4<f32>], out: &mut Vec<[[f32; 4]; 4]>) {
+    for skin in skins {
+        for (joint, inverse_bind_matrix) in skin.joints.iter().zip(&skin.inverse_bind_matrices) {
+            out.push((globals[*joint] * Matrix4::from(*inverse_bind_matrix)).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interpolation, Sampler};
+
+    fn sample(sampler: &Sampler, time: f32, spherical: bool) -> Vec<f32> {
+        let mut out = vec![0.0; sampler.components];
+        sampler.sample(time, spherical, &mut out);
+        out
+    }
+
+    fn close(a: &[f32], b: &[f32]) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5)
+    }
+
+    #[test]
+    fn step_holds_each_keyframe() {
+        let sampler = Sampler {
+            interpolation: Interpolation::Step,
+            times: vec![0.0, 1.0, 2.0],
+            values: vec![1.0, 2.0, 3.0],
+            components: 1,
+        };
+        assert_eq!(sample(&sampler, 0.5, false), [1.0]);
+        assert_eq!(sample(&sampler, 1.0, false), [2.0]);
+        assert_eq!(sample(&sampler, 1.99, false), [2.0]);
+    }
+
+    #[test]
+    fn linear_interpolates_and_clamps() {
+        let sampler = Sampler {
+            interpolation: Interpolation::Linear,
+            times: vec![1.0, 3.0],
+            values: vec![0.0, 10.0, 4.0, 20.0],
+            components: 2,
+        };
+        assert_eq!(sample(&sampler, 0.0, false), [0.0, 10.0]);
+        assert_eq!(sample(&sampler, 2.5, false), [3.0, 17.5]);
+        assert_eq!(sample(&sampler, 5.0, false), [4.0, 20.0]);
+    }
+
+    #[test]
+    fn linear_rotations_are_slerped() {
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        // From no rotation to a half turn around Y.
+        let sampler = Sampler {
+            interpolation: Interpolation::Linear,
+            times: vec![0.0, 1.0],
+            values: vec![0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0],
+            components: 4,
+        };
+        assert!(close(&sample(&sampler, 0.5, true), &[0.0, half, 0.0, half]));
+    }
+
+    #[test]
+    fn cubic_splines_follow_their_tangents() {
+        // In-tangent, value and out-tangent per keyframe: leaves 0 flat and
+        // arrives at 2 with a slope of 1.
+        let sampler = Sampler {
+            interpolation: Interpolation::CubicSpline,
+            times: vec![0.0, 2.0],
+            values: vec![0.0, 0.0, 0.0, 1.0, 2.0, 0.0],
+            components: 1,
+        };
+        assert_eq!(sample(&sampler, 0.0, false), [0.0]);
+        assert_eq!(sample(&sampler, 2.0, false), [2.0]);
+        // h01 * 2 + h11 * dt * 1 at u = 0.5, below the straight line.
+        assert!(close(&sample(&sampler, 1.0, false), &[0.75]));
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    baked_texture, compressed_texture, gltf_loader,
+    manifest::{self, Manifest, ManifestEntry, SourceFile},
+    mesh_cache, model, resources,
+    texture::{self, TextureKind},
+};
+
+// Preprocesses assets into the baked directory of a res directory, so the
+// renderer loads meshes in the mesh cache format and textures with their
+// mips, compressed where possible, instead of parsing OBJ files and decoding
+// images.
+//
+// Baked files are named after their content hash, so assets with identical
+// content share a file.
+pub struct Baker {
+    // Where baked files and the manifest are written.
+    output_dir: PathBuf,
+    manifest: Manifest,
+    // Texture keys that were baked already, models often share textures.
+    textures: HashSet<String>,
+}
+
+// Whether bake_model knows how to bake the file.
+pub fn is_model(file_name: &str) -> bool {
+    matches!(
+        Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref(),
+        Some("obj" | "gltf" | "glb")
+    )
+}
+
+impl Baker {
+    // Assets are read through crate::assets and baked into res_dir/baked,
+    // res_dir usually being the directory mounted at the asset root.
+    pub fn new(res_dir: impl AsRef<Path>) -> Self {
+        Self {
+            output_dir: res_dir.as_ref().join(manifest::BAKED_DIR),
+            manifest: Manifest::default(),
+            textures: HashSet::new(),
+        }
+    }
+
+    // Bakes the meshes of the model and every texture its materials use.
+    // file_name is an asset path, like for Renderer::load_model.
+    pub async fn bake_model(&mut self, file_name: &str) -> anyhow::Result<()> {
+        if resources::is_gltf(file_name) {
+            gltf_loader::bake_gltf_model(self, file_name)
+        } else {
+            resources::bake_obj_model(self, file_name).await
+        }
+    }
+
+    // Writes the manifest, only then does the renderer pick up what was baked.
+    pub fn write_manifest(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.output_dir)?;
+        std::fs::write(self.output_dir.join("manifest.txt"), self.manifest.to_text())?;
+        Ok(())
+    }
+
+    // Number of baked files the manifest refers to.
+    pub fn len(&self) -> usize {
+        self.manifest.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifest.entries.is_empty()
+    }
+
+    // sources are the files (relative to res) the baked file is made from.
+    fn add(&mut self, key: String, extension: &str, bytes: &[u8], sources: &[String]) -> anyhow::Result<()> {
+        let sources = sources
+            .iter()
+            .map(|source| SourceFile::new(source))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let hash = manifest::content_hash(bytes);
+        let file = format!("{:016x}.{}", hash, extension);
+        std::fs::create_dir_all(&self.output_dir)?;
+        std::fs::write(self.output_dir.join(&file), bytes)?;
+        self.manifest.entries.insert(key, ManifestEntry { file, hash, sources });
+        Ok(())
+    }
+
+    pub(super) fn add_model(
+        &mut self,
+        file_name: &str,
+        sources: &[String],
+        material_libraries: &[String],
+        materials: &[tobj::Material],
+        meshes: &[model::MeshData],
+    ) -> anyhow::Result<()> {
+        // The manifest keeps track of the sources instead of the cache file.
+        let bytes = mesh_cache::serialize(&[], material_libraries, materials, meshes)?;
+        self.add(file_name.to_string(), "mesh", &bytes, sources)
+    }
+
+    // Bakes the texture under name unless it was already. pixels produces
+    // the decoded RGBA8 pixels with their size from the sources.
+    pub(super) fn add_texture(
+        &mut self,
+        name: &str,
+        kind: TextureKind,
+        sources: &[String],
+        pixels: impl FnOnce() -> anyhow::Result<(Vec<u8>, u32, u32)>,
+    ) -> anyhow::Result<()> {
+        let key = manifest::texture_key(name, kind.is_linear());
+        if !self.textures.insert(key.clone()) {
+            return Ok(());
+        }
+        let (pixels, width, height) = pixels()?;
+        let bytes = baked_texture::bake(pixels, width, height, kind);
+        self.add(key, "tex", &bytes, sources)
+    }
+
+    // Bakes an image file, if the material references one. KTX2 and DDS
+    // files are in their GPU format already and are loaded as they are.
+    pub(super) fn add_texture_file(&mut self, file_name: &str, kind: TextureKind) -> anyhow::Result<()> {
+        if file_name.is_empty() || compressed_texture::is_container_file(file_name) {
+            return Ok(());
+        }
+        self.add_texture(file_name, kind, &[file_name.to_string()], || {
+            texture::Texture::decode_rgba8(&resources::load_binary(file_name)?, file_name)
+        })
+    }
+}
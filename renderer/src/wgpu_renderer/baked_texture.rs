@@ -0,0 +1,178 @@
+use anyhow::{anyhow, bail};
+
+use super::{
+    compressed_texture,
+    streaming::Uploads,
+    texture::{self, TextureKind},
+};
+
+// Baked textures store their whole mip chain, already in the format they are
+// uploaded in:
+//
+// TextureHeader
+// every mip level, largest first, rows of whole blocks
+const MAGIC: [u8; 4] = *b"RWTX";
+const VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextureHeader {
+    magic: [u8; 4],
+    version: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+    mip_level_count: u32,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BakedFormat {
+    Rgba8,
+    Rgba8Srgb,
+    Bc1,
+    Bc1Srgb,
+    Bc3,
+    Bc3Srgb,
+}
+
+impl BakedFormat {
+    const ALL: [BakedFormat; 6] = [
+        BakedFormat::Rgba8,
+        BakedFormat::Rgba8Srgb,
+        BakedFormat::Bc1,
+        BakedFormat::Bc1Srgb,
+        BakedFormat::Bc3,
+        BakedFormat::Bc3Srgb,
+    ];
+
+    fn wgpu_format(self) -> wgpu::TextureFormat {
+        match self {
+            BakedFormat::Rgba8 => wgpu::TextureFormat::Rgba8Unorm,
+            BakedFormat::Rgba8Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+            BakedFormat::Bc1 => wgpu::TextureFormat::Bc1RgbaUnorm,
+            BakedFormat::Bc1Srgb => wgpu::TextureFormat::Bc1RgbaUnormSrgb,
+            BakedFormat::Bc3 => wgpu::TextureFormat::Bc3RgbaUnorm,
+            BakedFormat::Bc3Srgb => wgpu::TextureFormat::Bc3RgbaUnormSrgb,
+        }
+    }
+
+    fn compression(self) -> Option<texpresso::Format> {
+        match self {
+            BakedFormat::Rgba8 | BakedFormat::Rgba8Srgb => None,
+            BakedFormat::Bc1 | BakedFormat::Bc1Srgb => Some(texpresso::Format::Bc1),
+            BakedFormat::Bc3 | BakedFormat::Bc3Srgb => Some(texpresso::Format::Bc3),
+        }
+    }
+
+    fn level_size(self, width: u32, height: u32) -> usize {
+        match self.compression() {
+            Some(compression) => compression.compressed_size(width as usize, height as usize),
+            None => width as usize * height as usize * 4,
+        }
+    }
+}
+
+fn mip_level_size(size: u32, level: usize) -> u32 {
+    (size >> level).max(1)
+}
+
+// Picks the format of a texture. Normal maps stay uncompressed since BC1
+// and BC3 visibly band them, and BC textures need sizes in whole blocks.
+fn choose_format(pixels: &[u8], width: u32, height: u32, kind: TextureKind) -> BakedFormat {
+    if kind == TextureKind::Normal || !width.is_multiple_of(4) || !height.is_multiple_of(4) {
+        return match kind {
+            TextureKind::Color => BakedFormat::Rgba8Srgb,
+            _ => BakedFormat::Rgba8,
+        };
+    }
+    let has_alpha = pixels.chunks_exact(4).any(|pixel| pixel[3] != 255);
+    match (kind, has_alpha) {
+        (TextureKind::Color, false) => BakedFormat::Bc1Srgb,
+        (TextureKind::Color, true) => BakedFormat::Bc3Srgb,
+        (_, false) => BakedFormat::Bc1,
+        (_, true) => BakedFormat::Bc3,
+    }
+}
+
+// Generates the mip chain of decoded RGBA8 pixels and compresses it.
+pub fn bake(pixels: Vec<u8>, width: u32, height: u32, kind: TextureKind) -> Vec<u8> {
+    let format = choose_format(&pixels, width, height, kind);
+    let levels = texture::generate_mips(pixels, width, height, kind);
+    let header = TextureHeader {
+        magic: MAGIC,
+        version: VERSION,
+        format: format as u32,
+        width,
+        height,
+        mip_level_count: levels.len() as u32,
+    };
+    let mut bytes = bytemuck::bytes_of(&header).to_vec();
+    for (level, pixels) in levels.iter().enumerate() {
+        let (width, height) = (mip_level_size(width, level), mip_level_size(height, level));
+        match format.compression() {
+            Some(compression) => {
+                let start = bytes.len();
+                bytes.resize(start + format.level_size(width, height), 0);
+                compression.compress(
+                    pixels,
+                    width as usize,
+                    height as usize,
+                    texpresso::Params::default(),
+                    &mut bytes[start..],
+                );
+            }
+            None => bytes.extend_from_slice(pixels),
+        }
+    }
+    bytes
+}
+
+// Whether the bytes are a baked texture rather than an image file.
+pub fn is_baked(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Uploads a baked texture. Devices without BC support get the texture
+// decompressed on the CPU.
+pub fn load(
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    bytes: &[u8],
+    label: &str,
+) -> anyhow::Result<texture::Texture> {
+    let header_size = size_of::<TextureHeader>();
+    if bytes.len() < header_size {
+        bail!("{} is too short for a baked texture", label);
+    }
+    let header: TextureHeader = bytemuck::pod_read_unaligned(&bytes[..header_size]);
+    if header.magic != MAGIC || header.version != VERSION {
+        bail!("{} is not a baked texture of this version", label);
+    }
+    let format = BakedFormat::ALL
+        .get(header.format as usize)
+        .copied()
+        .ok_or_else(|| anyhow!("Unknown format {} in baked texture {}", header.format, label))?;
+
+    let mut levels = Vec::new();
+    let mut offset = header_size;
+    for level in 0..header.mip_level_count as usize {
+        let (width, height) = (mip_level_size(header.width, level), mip_level_size(header.height, level));
+        let size = format.level_size(width, height);
+        let data = bytes
+            .get(offset..offset + size)
+            .ok_or_else(|| anyhow!("Baked texture {} is truncated", label))?;
+        levels.push(data);
+        offset += size;
+    }
+
+    compressed_texture::upload(
+        device,
+        uploads,
+        format.wgpu_format(),
+        header.width,
+        header.height,
+        &levels,
+        label,
+    )
+}
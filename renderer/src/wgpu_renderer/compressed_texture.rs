@@ -0,0 +1,283 @@
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context};
+
+use super::{
+    streaming::Uploads,
+    texture::{self, TextureKind},
+};
+
+// KTX2 and DDS files hold textures in the format they are uploaded in,
+// usually block compressed, together with their mip chain. They are uploaded
+// as they are instead of being decoded like images.
+
+const KTX2_MAGIC: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(&KTX2_MAGIC) || bytes.starts_with(&DDS_MAGIC)
+}
+
+// Whether the file name is one of a container, for when the file isn't read.
+pub fn is_container_file(file_name: &str) -> bool {
+    let file_name = file_name.to_ascii_lowercase();
+    file_name.ends_with(".ktx2") || file_name.ends_with(".dds")
+}
+
+struct ContainerTexture {
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    // Largest first, rows of whole blocks.
+    levels: Vec<Vec<u8>>,
+}
+
+fn ktx2_format(format: ktx2::Format) -> Option<wgpu::TextureFormat> {
+    use ktx2::Format as K;
+    use wgpu::{AstcBlock, AstcChannel, TextureFormat as W};
+
+    let astc = |block, srgb| {
+        let channel = if srgb { AstcChannel::UnormSrgb } else { AstcChannel::Unorm };
+        W::Astc { block, channel }
+    };
+    Some(match format {
+        K::R8G8B8A8_UNORM => W::Rgba8Unorm,
+        K::R8G8B8A8_SRGB => W::Rgba8UnormSrgb,
+        K::R16G16B16A16_SFLOAT => W::Rgba16Float,
+        K::R32G32B32A32_SFLOAT => W::Rgba32Float,
+        K::BC1_RGB_UNORM_BLOCK | K::BC1_RGBA_UNORM_BLOCK => W::Bc1RgbaUnorm,
+        K::BC1_RGB_SRGB_BLOCK | K::BC1_RGBA_SRGB_BLOCK => W::Bc1RgbaUnormSrgb,
+        K::BC2_UNORM_BLOCK => W::Bc2RgbaUnorm,
+        K::BC2_SRGB_BLOCK => W::Bc2RgbaUnormSrgb,
+        K::BC3_UNORM_BLOCK => W::Bc3RgbaUnorm,
+        K::BC3_SRGB_BLOCK => W::Bc3RgbaUnormSrgb,
+        K::BC4_UNORM_BLOCK => W::Bc4RUnorm,
+        K::BC4_SNORM_BLOCK => W::Bc4RSnorm,
+        K::BC5_UNORM_BLOCK => W::Bc5RgUnorm,
+        K::BC5_SNORM_BLOCK => W::Bc5RgSnorm,
+        K::BC6H_UFLOAT_BLOCK => W::Bc6hRgbUfloat,
+        K::BC6H_SFLOAT_BLOCK => W::Bc6hRgbFloat,
+        K::BC7_UNORM_BLOCK => W::Bc7RgbaUnorm,
+        K::BC7_SRGB_BLOCK => W::Bc7RgbaUnormSrgb,
+        K::ETC2_R8G8B8_UNORM_BLOCK => W::Etc2Rgb8Unorm,
+        K::ETC2_R8G8B8_SRGB_BLOCK => W::Etc2Rgb8UnormSrgb,
+        K::ETC2_R8G8B8A1_UNORM_BLOCK => W::Etc2Rgb8A1Unorm,
+        K::ETC2_R8G8B8A1_SRGB_BLOCK => W::Etc2Rgb8A1UnormSrgb,
+        K::ETC2_R8G8B8A8_UNORM_BLOCK => W::Etc2Rgba8Unorm,
+        K::ETC2_R8G8B8A8_SRGB_BLOCK => W::Etc2Rgba8UnormSrgb,
+        K::EAC_R11_UNORM_BLOCK => W::EacR11Unorm,
+        K::EAC_R11_SNORM_BLOCK => W::EacR11Snorm,
+        K::EAC_R11G11_UNORM_BLOCK => W::EacRg11Unorm,
+        K::EAC_R11G11_SNORM_BLOCK => W::EacRg11Snorm,
+        K::ASTC_4x4_UNORM_BLOCK => astc(AstcBlock::B4x4, false),
+        K::ASTC_4x4_SRGB_BLOCK => astc(AstcBlock::B4x4, true),
+        K::ASTC_5x4_UNORM_BLOCK => astc(AstcBlock::B5x4, false),
+        K::ASTC_5x4_SRGB_BLOCK => astc(AstcBlock::B5x4, true),
+        K::ASTC_5x5_UNORM_BLOCK => astc(AstcBlock::B5x5, false),
+        K::ASTC_5x5_SRGB_BLOCK => astc(AstcBlock::B5x5, true),
+        K::ASTC_6x5_UNORM_BLOCK => astc(AstcBlock::B6x5, false),
+        K::ASTC_6x5_SRGB_BLOCK => astc(AstcBlock::B6x5, true),
+        K::ASTC_6x6_UNORM_BLOCK => astc(AstcBlock::B6x6, false),
+        K::ASTC_6x6_SRGB_BLOCK => astc(AstcBlock::B6x6, true),
+        K::ASTC_8x5_UNORM_BLOCK => astc(AstcBlock::B8x5, false),
+        K::ASTC_8x5_SRGB_BLOCK => astc(AstcBlock::B8x5, true),
+        K::ASTC_8x6_UNORM_BLOCK => astc(AstcBlock::B8x6, false),
+        K::ASTC_8x6_SRGB_BLOCK => astc(AstcBlock::B8x6, true),
+        K::ASTC_8x8_UNORM_BLOCK => astc(AstcBlock::B8x8, false),
+        K::ASTC_8x8_SRGB_BLOCK => astc(AstcBlock::B8x8, true),
+        K::ASTC_10x5_UNORM_BLOCK => astc(AstcBlock::B10x5, false),
+        K::ASTC_10x5_SRGB_BLOCK => astc(AstcBlock::B10x5, true),
+        K::ASTC_10x6_UNORM_BLOCK => astc(AstcBlock::B10x6, false),
+        K::ASTC_10x6_SRGB_BLOCK => astc(AstcBlock::B10x6, true),
+        K::ASTC_10x8_UNORM_BLOCK => astc(AstcBlock::B10x8, false),
+        K::ASTC_10x8_SRGB_BLOCK => astc(AstcBlock::B10x8, true),
+        K::ASTC_10x10_UNORM_BLOCK => astc(AstcBlock::B10x10, false),
+        K::ASTC_10x10_SRGB_BLOCK => astc(AstcBlock::B10x10, true),
+        K::ASTC_12x10_UNORM_BLOCK => astc(AstcBlock::B12x10, false),
+        K::ASTC_12x10_SRGB_BLOCK => astc(AstcBlock::B12x10, true),
+        K::ASTC_12x12_UNORM_BLOCK => astc(AstcBlock::B12x12, false),
+        K::ASTC_12x12_SRGB_BLOCK => astc(AstcBlock::B12x12, true),
+        _ => return None,
+    })
+}
+
+fn dxgi_format(format: ddsfile::DxgiFormat) -> Option<wgpu::TextureFormat> {
+    use ddsfile::DxgiFormat as D;
+    use wgpu::TextureFormat as W;
+
+    Some(match format {
+        D::R8G8B8A8_UNorm => W::Rgba8Unorm,
+        D::R8G8B8A8_UNorm_sRGB => W::Rgba8UnormSrgb,
+        D::R16G16B16A16_Float => W::Rgba16Float,
+        D::R32G32B32A32_Float => W::Rgba32Float,
+        D::BC1_UNorm => W::Bc1RgbaUnorm,
+        D::BC1_UNorm_sRGB => W::Bc1RgbaUnormSrgb,
+        D::BC2_UNorm => W::Bc2RgbaUnorm,
+        D::BC2_UNorm_sRGB => W::Bc2RgbaUnormSrgb,
+        D::BC3_UNorm => W::Bc3RgbaUnorm,
+        D::BC3_UNorm_sRGB => W::Bc3RgbaUnormSrgb,
+        D::BC4_UNorm => W::Bc4RUnorm,
+        D::BC4_SNorm => W::Bc4RSnorm,
+        D::BC5_UNorm => W::Bc5RgUnorm,
+        D::BC5_SNorm => W::Bc5RgSnorm,
+        D::BC6H_UF16 => W::Bc6hRgbUfloat,
+        D::BC6H_SF16 => W::Bc6hRgbFloat,
+        D::BC7_UNorm => W::Bc7RgbaUnorm,
+        D::BC7_UNorm_sRGB => W::Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+// Legacy DDS headers don't tell sRGB from linear data, kind does.
+fn d3d_format(format: ddsfile::D3DFormat, kind: TextureKind) -> Option<wgpu::TextureFormat> {
+    use ddsfile::D3DFormat as D;
+    use wgpu::TextureFormat as W;
+
+    let format = match format {
+        D::A8B8G8R8 => W::Rgba8Unorm,
+        D::DXT1 => W::Bc1RgbaUnorm,
+        D::DXT2 | D::DXT3 => W::Bc2RgbaUnorm,
+        D::DXT4 | D::DXT5 => W::Bc3RgbaUnorm,
+        _ => return None,
+    };
+    Some(if kind.is_linear() { format } else { format.add_srgb_suffix() })
+}
+
+fn read_ktx2(bytes: &[u8], label: &str) -> anyhow::Result<ContainerTexture> {
+    let reader = ktx2::Reader::new(bytes).with_context(|| format!("{} is not a valid KTX2 file", label))?;
+    let header = reader.header();
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count != 1 {
+        bail!("{} is not a 2D texture, only those are supported", label);
+    }
+    // Basis Universal textures have no format, they would need transcoding.
+    let format = header
+        .format
+        .ok_or_else(|| anyhow!("{} is a Basis Universal texture, which isn't supported", label))?;
+    let format = ktx2_format(format).ok_or_else(|| anyhow!("{} has the unsupported format {:?}", label, format))?;
+
+    let levels = reader
+        .levels()
+        .map(|level| match header.supercompression_scheme {
+            None => Ok(level.data.to_vec()),
+            Some(ktx2::SupercompressionScheme::Zstandard) => {
+                let mut data = Vec::with_capacity(level.uncompressed_byte_length as usize);
+                ruzstd::decoding::StreamingDecoder::new(level.data)
+                    .map_err(|e| anyhow!("Could not decompress {}: {}", label, e))?
+                    .read_to_end(&mut data)?;
+                Ok(data)
+            }
+            Some(scheme) => Err(anyhow!("{} uses the unsupported supercompression {:?}", label, scheme)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ContainerTexture {
+        format,
+        width: header.pixel_width,
+        height: header.pixel_height.max(1),
+        levels,
+    })
+}
+
+fn read_dds(bytes: &[u8], label: &str, kind: TextureKind) -> anyhow::Result<ContainerTexture> {
+    let dds = ddsfile::Dds::read(bytes).with_context(|| format!("{} is not a valid DDS file", label))?;
+    if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 || dds.header.caps2.contains(ddsfile::Caps2::CUBEMAP) {
+        bail!("{} is not a 2D texture, only those are supported", label);
+    }
+    let format = match &dds.header10 {
+        Some(header10) => dxgi_format(header10.dxgi_format),
+        None => dds.get_d3d_format().and_then(|format| d3d_format(format, kind)),
+    }
+    .ok_or_else(|| anyhow!("{} has an unsupported format", label))?;
+
+    let (width, height) = (dds.get_width(), dds.get_height());
+    let mut data = dds.get_data(0)?;
+    let mut levels = Vec::new();
+    for level in 0..dds.get_num_mipmap_levels().max(1) {
+        let size = texture::level_size(format, width, height, level);
+        if data.len() < size {
+            bail!("DDS file {} is truncated", label);
+        }
+        let (level_data, rest) = data.split_at(size);
+        levels.push(level_data.to_vec());
+        data = rest;
+    }
+    Ok(ContainerTexture {
+        format,
+        width,
+        height,
+        levels,
+    })
+}
+
+// The formats that can be decompressed on the CPU, and what to. texpresso
+// has no BC6H or BC7 decoder, so those have no fallback.
+fn cpu_decompression(format: wgpu::TextureFormat) -> Option<(texpresso::Format, wgpu::TextureFormat)> {
+    use wgpu::TextureFormat as W;
+
+    let compression = match format.remove_srgb_suffix() {
+        W::Bc1RgbaUnorm => texpresso::Format::Bc1,
+        W::Bc2RgbaUnorm => texpresso::Format::Bc2,
+        W::Bc3RgbaUnorm => texpresso::Format::Bc3,
+        W::Bc4RUnorm => texpresso::Format::Bc4,
+        W::Bc5RgUnorm => texpresso::Format::Bc5,
+        _ => return None,
+    };
+    let uncompressed = if format.is_srgb() { W::Rgba8UnormSrgb } else { W::Rgba8Unorm };
+    Some((compression, uncompressed))
+}
+
+// Uploads a mip chain in format. Devices without the format's compression
+// feature get BC1 to BC5 decompressed on the CPU, the other compressed
+// formats (BC6H, BC7, ETC2 and ASTC) fail to load on them.
+pub fn upload(
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    levels: &[&[u8]],
+    label: &str,
+) -> anyhow::Result<texture::Texture> {
+    let missing = format.required_features().difference(device.features());
+    if missing.is_empty() {
+        return texture::Texture::from_mips(device, uploads, format, width, height, levels, label);
+    }
+    let (compression, uncompressed) =
+        cpu_decompression(format).ok_or_else(|| anyhow!("{} needs the missing device features {:?}", label, missing))?;
+    // The decompressor expects every level to hold all of its blocks.
+    texture::check_mips(device, format, width, height, levels, label)?;
+    let decompressed = levels
+        .iter()
+        .enumerate()
+        .map(|(level, data)| {
+            let size = wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            }
+            .mip_level_size(level as u32, wgpu::TextureDimension::D2);
+            let mut pixels = vec![0; size.width as usize * size.height as usize * 4];
+            compression.decompress(data, size.width as usize, size.height as usize, &mut pixels);
+            pixels
+        })
+        .collect::<Vec<_>>();
+    let levels = decompressed.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    texture::Texture::from_mips(device, uploads, uncompressed, width, height, &levels, label)
+}
+
+// Loads a KTX2 or DDS file. The format stored in the file decides the color
+// space, except for legacy DDS files which don't store it.
+pub fn load(
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    bytes: &[u8],
+    label: &str,
+    kind: TextureKind,
+) -> anyhow::Result<texture::Texture> {
+    let container = if bytes.starts_with(&KTX2_MAGIC) {
+        read_ktx2(bytes, label)?
+    } else {
+        read_dds(bytes, label, kind)?
+    };
+    let levels = container.levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    upload(device, uploads, container.format, container.width, container.height, &levels, label)
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use super::model;
+
+// Buffers grow to the next power of two so meshes that change size a little
+// every frame don't reallocate every frame.
+fn capacity(size: u64) -> u64 {
+    size.max(256).next_power_of_two()
+}
+
+fn create_buffer(device: &wgpu::Device, label: &str, usage: wgpu::BufferUsages, size: u64) -> Arc<wgpu::Buffer> {
+    Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: capacity(size),
+        usage: usage | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    }))
+}
+
+// Writes data into buffer, replacing it first if it's too small.
+fn write_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &mut Arc<wgpu::Buffer>,
+    label: &str,
+    usage: wgpu::BufferUsages,
+    data: &[u8],
+) {
+    if buffer.size() < data.len() as u64 {
+        *buffer = create_buffer(device, label, usage, data.len() as u64);
+    }
+    if !data.is_empty() {
+        queue.write_buffer(buffer, 0, data);
+    }
+}
+
+// The second set of buffers of a mesh whose geometry is rewritten at runtime.
+// Updates are written into these and then swapped with the mesh's own, so
+// the buffers the last frame was drawn from are left alone. The mesh itself
+// is drawn like any other.
+pub struct DynamicMesh {
+    vertex_buffer: Arc<wgpu::Buffer>,
+    index_buffer: Arc<wgpu::Buffer>,
+}
+
+impl DynamicMesh {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &[model::ModelVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> (model::WgpuMesh, Self) {
+        let vertex_bytes = bytemuck::cast_slice::<_, u8>(vertices);
+        let index_bytes = bytemuck::cast_slice::<_, u8>(indices);
+        let buffers = || {
+            (
+                create_buffer(device, "dynamic Vertex Buffer", wgpu::BufferUsages::VERTEX, vertex_bytes.len() as u64),
+                create_buffer(device, "dynamic Index Buffer", wgpu::BufferUsages::INDEX, index_bytes.len() as u64),
+            )
+        };
+        let (vertex_buffer, index_buffer) = buffers();
+        let mut mesh = model::WgpuMesh {
+            name: "dynamic mesh".to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: 0,
+            material,
+            skin_buffer: None,
+            morph_targets: None,
+            bounds: model::Aabb::from_vertices(&[]),
+        };
+        let (vertex_buffer, index_buffer) = buffers();
+        let mut dynamic = Self {
+            vertex_buffer,
+            index_buffer,
+        };
+        dynamic.update(device, queue, &mut mesh, vertices, indices);
+        (mesh, dynamic)
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mesh: &mut model::WgpuMesh,
+        vertices: &[model::ModelVertex],
+        indices: &[u32],
+    ) {
+        write_buffer(
+            device,
+            queue,
+            &mut self.vertex_buffer,
+            "dynamic Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            bytemuck::cast_slice(vertices),
+        );
+        write_buffer(
+            device,
+            queue,
+            &mut self.index_buffer,
+            "dynamic Index Buffer",
+            wgpu::BufferUsages::INDEX,
+            bytemuck::cast_slice(indices),
+        );
+        std::mem::swap(&mut self.vertex_buffer, &mut mesh.vertex_buffer);
+        std::mem::swap(&mut self.index_buffer, &mut mesh.index_buffer);
+        mesh.num_elements = indices.len() as u32;
+        mesh.bounds = model::Aabb::from_vertices(vertices);
+    }
+}
@@ -0,0 +1,356 @@
+use std::sync::Arc;
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use super::{resources, streaming::Uploads, texture::Texture};
+
+const SHADER_FILE: &str = "environment.wgsl";
+
+// Everything the passes write is half float, which storage textures support
+// on every backend.
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+const CUBE_SIZE: u32 = 512;
+const IRRADIANCE_SIZE: u32 = 32;
+// Mip i is prefiltered for roughness i / (PREFILTERED_MIPS - 1).
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+
+const WORKGROUP_SIZE: u32 = 8;
+
+// The maps the scene shader samples for ambient light. Views of cube maps
+// are cube views.
+pub struct Environment {
+    // The environment itself, for skyboxes.
+    pub cube: Texture,
+    // Diffuse lighting by surface normal.
+    pub irradiance: Texture,
+    // Specular lighting by reflection direction, blurrier with every mip.
+    pub prefiltered: Texture,
+}
+
+impl Environment {
+    // Light of the same color from every direction, for scenes without an
+    // environment.
+    pub fn uniform(device: &wgpu::Device, uploads: &Uploads, color: [f32; 3]) -> Self {
+        let cube = || uniform_cube(device, uploads, color);
+        Self {
+            cube: cube(),
+            irradiance: cube(),
+            prefiltered: cube(),
+        }
+    }
+
+    // The mip level of the prefiltered map for a roughness of 1.
+    pub fn max_lod(&self) -> f32 {
+        (self.prefiltered.texture.mip_level_count() - 1) as f32
+    }
+}
+
+fn uniform_cube(device: &wgpu::Device, uploads: &Uploads, color: [f32; 3]) -> Texture {
+    let size = wgpu::Extent3d {
+        width: 1,
+        height: 1,
+        depth_or_array_layers: 6,
+    };
+    let texture = Arc::new(device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("uniform environment"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    }));
+    let texel = [color[0], color[1], color[2], 1.0].map(half::f16::from_f32);
+    let data = bytemuck::cast_slice(&[texel; 6]).to_vec();
+    uploads.write_texture(
+        &texture,
+        0,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(8),
+            rows_per_image: Some(1),
+        },
+        size,
+        data,
+    );
+    let view = cube_view(&texture);
+    Texture { texture, view }
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
+
+fn create_cube(device: &wgpu::Device, size: u32, mip_level_count: u32, label: &str) -> Arc<wgpu::Texture> {
+    Arc::new(device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    }))
+}
+
+fn workgroups(size: u32) -> u32 {
+    size.div_ceil(WORKGROUP_SIZE)
+}
+
+// Turns equirectangular images into environments with compute passes. The
+// BRDF lookup table doesn't depend on the environment, it is computed once.
+pub struct EnvironmentBaker {
+    equirectangular_to_cube: wgpu::ComputePipeline,
+    irradiance: wgpu::ComputePipeline,
+    prefilter: wgpu::ComputePipeline,
+    sampler: wgpu::Sampler,
+    pub brdf_lut: Texture,
+}
+
+impl EnvironmentBaker {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self> {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(SHADER_FILE),
+            source: wgpu::ShaderSource::Wgsl(resources::load_string(SHADER_FILE)?.into()),
+        });
+        // Every pass binds only what its entry point uses.
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let equirectangular_to_cube = pipeline("equirectangular_to_cube");
+        let irradiance = pipeline("irradiance");
+        let prefilter = pipeline("prefilter");
+        let brdf_lut_pipeline = pipeline("brdf_lut");
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("environment baking sampler"),
+            // Wraps around the equirectangular image horizontally.
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture = Arc::new(device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF lookup table"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("BRDF lookup table"),
+            layout: &brdf_lut_pipeline.get_bind_group_layout(0),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&view),
+            }],
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("BRDF Lookup Table Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&brdf_lut_pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(workgroups(BRDF_LUT_SIZE), workgroups(BRDF_LUT_SIZE), 1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(Self {
+            equirectangular_to_cube,
+            irradiance,
+            prefilter,
+            sampler,
+            brdf_lut: Texture { texture, view },
+        })
+    }
+
+    // The equirectangular image needs its full mip chain and to be uploaded
+    // already.
+    pub fn bake(&self, device: &wgpu::Device, queue: &wgpu::Queue, equirectangular: &Texture, label: &str) -> Environment {
+        let cube_mips = CUBE_SIZE.ilog2() + 1;
+        let cube = create_cube(device, CUBE_SIZE, cube_mips, label);
+        let irradiance = create_cube(device, IRRADIANCE_SIZE, 1, label);
+        let prefiltered = create_cube(device, PREFILTERED_SIZE, PREFILTERED_MIPS, label);
+        let environment_view = cube_view(&cube);
+        let sampler = wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::Sampler(&self.sampler),
+        };
+        let environment = wgpu::BindGroupEntry {
+            binding: 3,
+            resource: wgpu::BindingResource::TextureView(&environment_view),
+        };
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        // Each level of the cube map is sampled from the image, there's no
+        // need to downsample the cube map itself.
+        let source = wgpu::BindGroupEntry {
+            binding: 2,
+            resource: wgpu::BindingResource::TextureView(&equirectangular.view),
+        };
+        for mip_level in 0..cube_mips {
+            self.write_faces(
+                device,
+                &mut encoder,
+                &self.equirectangular_to_cube,
+                &[sampler.clone(), source.clone()],
+                &cube,
+                mip_level,
+            );
+        }
+
+        self.write_faces(
+            device,
+            &mut encoder,
+            &self.irradiance,
+            &[sampler.clone(), environment.clone()],
+            &irradiance,
+            0,
+        );
+
+        for mip_level in 0..PREFILTERED_MIPS {
+            let roughness = mip_level as f32 / (PREFILTERED_MIPS - 1) as f32;
+            // Uniform buffers need 16 bytes.
+            let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Prefilter Params"),
+                contents: bytemuck::cast_slice(&[roughness, 0.0, 0.0, 0.0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let params = wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params.as_entire_binding(),
+            };
+            self.write_faces(
+                device,
+                &mut encoder,
+                &self.prefilter,
+                &[params, sampler.clone(), environment.clone()],
+                &prefiltered,
+                mip_level,
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let irradiance_view = cube_view(&irradiance);
+        let prefiltered_view = cube_view(&prefiltered);
+        Environment {
+            cube: Texture {
+                texture: cube,
+                view: environment_view,
+            },
+            irradiance: Texture {
+                texture: irradiance,
+                view: irradiance_view,
+            },
+            prefiltered: Texture {
+                texture: prefiltered,
+                view: prefiltered_view,
+            },
+        }
+    }
+
+    // Runs a pass writing the faces of one level of a cube map and copies
+    // them into it.
+    fn write_faces(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::ComputePipeline,
+        inputs: &[wgpu::BindGroupEntry],
+        cube: &wgpu::Texture,
+        mip_level: u32,
+    ) {
+        let size = (cube.width() >> mip_level).max(1);
+        let faces = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Cube Faces"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size * 6,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: FORMAT,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let output = faces.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut entries = inputs.to_vec();
+        entries.push(wgpu::BindGroupEntry {
+            binding: 4,
+            resource: wgpu::BindingResource::TextureView(&output),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cube Faces"),
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(workgroups(size), workgroups(size), 6);
+        }
+        for face in 0..6 {
+            encoder.copy_texture_to_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &faces,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: face * size,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyTexture {
+                    texture: cube,
+                    mip_level,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: face },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
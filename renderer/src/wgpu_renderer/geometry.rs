@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3, Zero};
+
+use super::model::ModelVertex;
+
+// Faces meeting at a sharper angle than this keep separate normals.
+pub const SMOOTHING_CREASE_ANGLE: cgmath::Deg<f32> = cgmath::Deg(60.0);
+
+// Bit pattern of a position, used to find the corners that share it even
+// when they belong to different vertices (e.g. across UV seams).
+fn position_key(position: [f32; 3]) -> [u32; 3] {
+    // +0.0 and -0.0 compare equal but have different bits.
+    position.map(|c| if c == 0.0 { 0 } else { c.to_bits() })
+}
+
+// Generates angle weighted smooth normals for meshes that don't provide any.
+// Faces around a shared position are only smoothed together when their
+// normals are less than crease_angle apart, vertices that lie on a crease are
+// split so every side keeps its own normal. Returns the original index of
+// every new vertex.
+pub fn generate_normals<A: Into<cgmath::Rad<f32>>>(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    crease_angle: A,
+) -> Vec<u32> {
+    let cos_crease = crease_angle.into().0.cos();
+
+    let mut face_normals = Vec::with_capacity(indices.len() / 3);
+    // The angle of the triangle at each corner, used as weight.
+    let mut corner_angles = Vec::with_capacity(indices.len());
+    for c in indices.chunks_exact(3) {
+        let p0: Vector3<f32> = vertices[c[0] as usize].position.into();
+        let p1: Vector3<f32> = vertices[c[1] as usize].position.into();
+        let p2: Vector3<f32> = vertices[c[2] as usize].position.into();
+
+        let face_normal = (p1 - p0).cross(p2 - p0);
+        // Zero area triangles don't contribute to any normal.
+        face_normals.push(if face_normal.magnitude2() > 0.0 {
+            face_normal.normalize()
+        } else {
+            Vector3::zero()
+        });
+
+        for (a, b, c) in [(p0, p1, p2), (p1, p2, p0), (p2, p0, p1)] {
+            let (e0, e1) = (b - a, c - a);
+            let angle = if e0.magnitude2() > 0.0 && e1.magnitude2() > 0.0 {
+                e0.angle(e1).0
+            } else {
+                0.0
+            };
+            corner_angles.push(angle);
+        }
+    }
+
+    let mut corners_at_position: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (corner, index) in indices.iter().enumerate() {
+        corners_at_position
+            .entry(position_key(vertices[*index as usize].position))
+            .or_default()
+            .push(corner);
+    }
+
+    let corner_normals = (0..indices.len())
+        .map(|corner| {
+            let face_normal = face_normals[corner / 3];
+            let key = position_key(vertices[indices[corner] as usize].position);
+            let normal = corners_at_position[&key]
+                .iter()
+                .filter(|other| face_normal.dot(face_normals[*other / 3]) >= cos_crease)
+                .fold(Vector3::zero(), |sum, other| {
+                    sum + face_normals[*other / 3] * corner_angles[*other]
+                });
+
+            if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else if face_normal.magnitude2() > 0.0 {
+                face_normal
+            } else {
+                Vector3::unit_y()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    split_vertices(vertices, indices, &corner_normals, |vertex, normal| {
+        vertex.normal = normal.into();
+    })
+}
+
+// Gives every vertex one copy per distinct value among the corners that
+// reference it, and lets set store the value in the copy. Returns the
+// original index of every new vertex, so other per-vertex data can follow.
+fn split_vertices<T: Copy + Into<[f32; N]>, const N: usize>(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    corner_values: &[T],
+    set: impl Fn(&mut ModelVertex, T),
+) -> Vec<u32> {
+    let mut copies: HashMap<(u32, [u32; N]), u32> = HashMap::new();
+    let mut new_vertices = Vec::with_capacity(vertices.len());
+    let mut sources = Vec::with_capacity(vertices.len());
+    for (index, value) in indices.iter_mut().zip(corner_values) {
+        let key: [f32; N] = (*value).into();
+        *index = *copies
+            .entry((*index, key.map(f32::to_bits)))
+            .or_insert_with(|| {
+                let mut vertex = vertices[*index as usize];
+                set(&mut vertex, *value);
+                new_vertices.push(vertex);
+                sources.push(*index);
+                (new_vertices.len() - 1) as u32
+            });
+    }
+    *vertices = new_vertices;
+    sources
+}
+
+// Any unit vector perpendicular to the normal, for vertices whose tangent
+// can't be derived from their texture coordinates.
+fn fallback_tangent(normal: Vector3<f32>) -> Vector3<f32> {
+    let axis = if normal.x.abs() < 0.9 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_y()
+    };
+    let tangent = axis - normal * normal.dot(axis);
+    if tangent.magnitude2() > 0.0 {
+        tangent.normalize()
+    } else {
+        Vector3::unit_x()
+    }
+}
+
+// Adapts an indexed triangle list to the MikkTSpace interface, collecting
+// the generated tangents per corner.
+struct MikkTSpaceMesh<'a> {
+    vertices: &'a [ModelVertex],
+    indices: &'a [u32],
+    corner_tangents: Vec<[f32; 4]>,
+}
+
+impl MikkTSpaceMesh<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &ModelVertex {
+        &self.vertices[self.indices[face * 3 + vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for MikkTSpaceMesh<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.vertex(face, vert).tex_coords
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.corner_tangents[face * 3 + vert] = tangent;
+    }
+}
+
+// Generates MikkTSpace tangents, the same ones texturing tools bake normal
+// maps against. The bitangent sign is stored in w. Corners of a vertex can
+// end up with different tangents (e.g. on mirrored UVs), those vertices are
+// split, the original index of every new vertex is returned.
+pub fn compute_tangents(vertices: &mut Vec<ModelVertex>, indices: &mut [u32], flip_bitangent: bool) -> Vec<u32> {
+    let mut mesh = MikkTSpaceMesh {
+        vertices,
+        indices,
+        corner_tangents: vec![[0.0; 4]; indices.len()],
+    };
+
+    let corner_tangents = if bevy_mikktspace::generate_tangents(&mut mesh) {
+        mesh.corner_tangents
+    } else {
+        log::warn!("MikkTSpace tangent generation failed, using arbitrary tangents");
+        indices
+            .iter()
+            .map(|i| {
+                let normal = Vector3::from(vertices[*i as usize].normal);
+                let normal = if normal.magnitude2() > 0.0 {
+                    normal.normalize()
+                } else {
+                    Vector3::unit_z()
+                };
+                fallback_tangent(normal).extend(1.0).into()
+            })
+            .collect()
+    };
+
+    let sign = if flip_bitangent { -1.0 } else { 1.0 };
+    split_vertices(vertices, indices, &corner_tangents, |vertex, tangent| {
+        vertex.tangent = [tangent[0], tangent[1], tangent[2], tangent[3] * sign];
+    })
+}
@@ -0,0 +1,730 @@
+use std::{path::Path, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, bail, Context};
+use base64::Engine;
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector3, Vector4};
+use gltf::animation::util::ReadOutputs;
+
+use super::{
+    animation, bake::Baker, geometry, manifest, mesh_cache, model, resources::{self, load_binary}, sampler, texture,
+    streaming::Uploads, texture_cache::TextureCache,
+};
+
+// The file a URI found in a glTF file refers to, relative to res. None for
+// embedded data URIs.
+fn uri_path(base_dir: &Path, uri: &str) -> Option<String> {
+    if uri.starts_with("data:") {
+        return None;
+    }
+    Some(base_dir.join(uri.replace("%20", " ")).to_string_lossy().into_owned())
+}
+
+// The external buffer files of a glTF file.
+fn buffer_files<'a>(document: &'a gltf::Document, base_dir: &'a Path) -> impl Iterator<Item = String> + 'a {
+    document.buffers().filter_map(|buffer| match buffer.source() {
+        gltf::buffer::Source::Uri(uri) => uri_path(base_dir, uri),
+        gltf::buffer::Source::Bin => None,
+    })
+}
+
+// Resolves a URI found in a glTF file, either an embedded base64 data URI or
+// a path relative to the glTF file itself.
+fn load_uri(base_dir: &Path, uri: &str) -> anyhow::Result<Vec<u8>> {
+    if let Some(path) = uri_path(base_dir, uri) {
+        return load_binary(&path);
+    }
+    let (_, payload) = uri
+        .trim_start_matches("data:")
+        .split_once(";base64,")
+        .ok_or_else(|| anyhow!("Unsupported data URI"))?;
+    Ok(base64::engine::general_purpose::STANDARD.decode(payload)?)
+}
+
+// The bytes of an image, embedded in a buffer or in a file of its own.
+fn image_bytes(image: &gltf::Image, base_dir: &Path, buffers: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let range = view.offset()..view.offset() + view.length();
+            buffers[view.buffer().index()]
+                .get(range)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| anyhow!("Image {} lies outside of its buffer", image.index()))
+        }
+        gltf::image::Source::Uri { uri, .. } => load_uri(base_dir, uri),
+    }
+}
+
+// The name images are baked under, they are identified by their index since
+// they may not have a name or a file.
+fn image_name(file_name: &str, image: &gltf::Image) -> String {
+    format!("{}#image{}", file_name, image.index())
+}
+
+struct TextureLoader<'a> {
+    file_name: &'a str,
+    base_dir: &'a Path,
+    buffers: &'a [Vec<u8>],
+    device: &'a wgpu::Device,
+    uploads: &'a Uploads,
+    // The same image can be used as color (sRGB) and as data (linear), the
+    // cache keeps them apart by kind.
+    cache: &'a TextureCache,
+}
+
+impl TextureLoader<'_> {
+    fn load(&self, texture: gltf::Texture, kind: texture::TextureKind) -> anyhow::Result<Arc<texture::Texture>> {
+        let image = texture.source();
+        let label = image
+            .name()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("glTF image {}", image.index()));
+        let name = image_name(self.file_name, &image);
+        let read = || match manifest::load_baked(&manifest::texture_key(&name, kind.is_linear())) {
+            Some(baked) => Ok(baked),
+            None => image_bytes(&image, self.base_dir, self.buffers),
+        };
+        self.cache.load(Some(&name), kind, read, |bytes| {
+            resources::create_texture(self.device, self.uploads, bytes, &label, kind)
+        })
+    }
+
+    fn load_or(
+        &self,
+        texture: Option<gltf::Texture>,
+        fallback: &Arc<texture::Texture>,
+        kind: texture::TextureKind,
+    ) -> anyhow::Result<Arc<texture::Texture>> {
+        match texture {
+            Some(texture) => self.load(texture, kind),
+            None => Ok(fallback.clone()),
+        }
+    }
+}
+
+// How the texture asks to be sampled, textures without a sampler repeat
+// and filter trilinearly.
+fn sampler_desc(texture: Option<gltf::Texture>) -> sampler::SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use sampler::{FilterMode, WrapMode};
+
+    let Some(texture) = texture else {
+        return sampler::SamplerDesc::default();
+    };
+    let gltf_sampler = texture.sampler();
+    let wrap = |mode| match mode {
+        WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => WrapMode::MirrorRepeat,
+        WrappingMode::Repeat => WrapMode::Repeat,
+    };
+    // Textures always have mips, filters without mipmapping pick the
+    // nearest level.
+    let (min_filter, mipmap_filter) = match gltf_sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => (FilterMode::Nearest, FilterMode::Nearest),
+        Some(MinFilter::Linear | MinFilter::LinearMipmapNearest) => (FilterMode::Linear, FilterMode::Nearest),
+        Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, FilterMode::Linear),
+        Some(MinFilter::LinearMipmapLinear) | None => (FilterMode::Linear, FilterMode::Linear),
+    };
+    sampler::SamplerDesc {
+        wrap_u: wrap(gltf_sampler.wrap_s()),
+        wrap_v: wrap(gltf_sampler.wrap_t()),
+        mag_filter: match gltf_sampler.mag_filter() {
+            Some(MagFilter::Nearest) => FilterMode::Nearest,
+            Some(MagFilter::Linear) | None => FilterMode::Linear,
+        },
+        min_filter,
+        mipmap_filter,
+    }
+}
+
+fn load_material(
+    material: gltf::Material,
+    textures: &TextureLoader,
+    defaults: &texture::DefaultTextures,
+    layout: &model::MaterialLayout,
+) -> anyhow::Result<model::WgpuMaterial> {
+    let pbr = material.pbr_metallic_roughness();
+
+    let mut uniform = model::MaterialUniform::new();
+    uniform.base_color_factor = pbr.base_color_factor();
+    uniform.metallic_factor = pbr.metallic_factor();
+    uniform.roughness_factor = pbr.roughness_factor();
+    uniform.emissive_factor = material.emissive_factor();
+    if let Some(normal) = material.normal_texture() {
+        uniform.normal_scale = normal.scale();
+    }
+    if let Some(occlusion) = material.occlusion_texture() {
+        uniform.occlusion_strength = occlusion.strength();
+    }
+
+    let base_color = pbr.base_color_texture().map(|i| i.texture());
+    let normal = material.normal_texture().map(|n| n.texture());
+    let metallic_roughness = pbr.metallic_roughness_texture().map(|i| i.texture());
+    let occlusion = material.occlusion_texture().map(|o| o.texture());
+    let emissive = material.emissive_texture().map(|i| i.texture());
+    let material_samplers = model::MaterialSamplers {
+        base_color: sampler_desc(base_color.clone()),
+        normal: sampler_desc(normal.clone()),
+        metallic_roughness: sampler_desc(metallic_roughness.clone()),
+        occlusion: sampler_desc(occlusion.clone()),
+        emissive: sampler_desc(emissive.clone()),
+    };
+    let material_textures = model::MaterialTextures {
+        base_color: textures.load_or(base_color, &defaults.white, texture::TextureKind::Color)?,
+        normal: textures.load_or(normal, &defaults.normal, texture::TextureKind::Normal)?,
+        metallic_roughness: textures.load_or(metallic_roughness, &defaults.white, texture::TextureKind::Data)?,
+        occlusion: textures.load_or(occlusion, &defaults.white, texture::TextureKind::Data)?,
+        emissive: textures.load_or(emissive, &defaults.white, texture::TextureKind::Color)?,
+    };
+
+    let name = material
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("glTF material {}", material.index().unwrap_or(0)));
+    let mut wgpu_material = model::WgpuMaterial::new(textures.device, &name, material_textures, material_samplers, uniform, layout);
+    // The alpha mode decides, textures may carry alpha too.
+    wgpu_material.blended = material.alpha_mode() == gltf::material::AlphaMode::Blend;
+    Ok(wgpu_material)
+}
+
+struct PrimitiveData {
+    vertices: Vec<model::ModelVertex>,
+    indices: Vec<u32>,
+    skin_vertices: Option<Vec<model::SkinVertex>>,
+    // The deltas of each morph target, if the primitive has any.
+    morph_targets: Vec<Vec<model::MorphVertex>>,
+}
+
+// Reads the joint influences of a skinned primitive, with the joint indices
+// moved into the model's joint palette and the weights normalized.
+fn read_skin_vertices(
+    primitive: &gltf::Primitive,
+    buffers: &[Vec<u8>],
+    skin: &animation::Skin,
+    num_vertices: usize,
+) -> anyhow::Result<Vec<model::SkinVertex>> {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let joints = reader
+        .read_joints(0)
+        .ok_or_else(|| anyhow!("Skinned primitive has no joints"))?;
+    let weights = reader
+        .read_weights(0)
+        .ok_or_else(|| anyhow!("Skinned primitive has no weights"))?;
+
+    let skin_vertices = joints
+        .into_u16()
+        .zip(weights.into_f32())
+        .map(|(joints, weights)| {
+            if let Some(joint) = joints.iter().find(|j| **j as usize >= skin.joints.len()) {
+                bail!("Joint {} out of bounds", joint);
+            }
+            let sum: f32 = weights.iter().sum();
+            let weights = if sum > 0.0 {
+                weights.map(|w| w / sum)
+            } else {
+                [1.0, 0.0, 0.0, 0.0]
+            };
+            Ok(model::SkinVertex {
+                joints: joints.map(|j| j + skin.joint_offset as u16),
+                weights,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if skin_vertices.len() != num_vertices {
+        bail!("Joint count doesn't match the vertex count");
+    }
+    Ok(skin_vertices)
+}
+
+// Reads one primitive into vertices and indices. Static primitives get the
+// node's world transform baked in, skinned ones stay in bind space.
+fn load_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[Vec<u8>],
+    transform: Matrix4<f32>,
+    skin: Option<&animation::Skin>,
+) -> anyhow::Result<PrimitiveData> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        bail!("Unsupported primitive mode {:?}", primitive.mode());
+    }
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| anyhow!("Primitive has no positions"))?;
+
+    let mut vertices = positions
+        .map(|position| model::ModelVertex {
+            position,
+            tex_coords: [0.0; 2],
+            normal: [0.0; 3],
+            tangent: [0.0; 4],
+        })
+        .collect::<Vec<_>>();
+    let mut indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    if let Some(&index) = indices.iter().find(|i| **i as usize >= vertices.len()) {
+        bail!("Index {} out of bounds", index);
+    }
+
+    if let Some(tex_coords) = reader.read_tex_coords(0) {
+        for (v, tex_coords) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+            v.tex_coords = tex_coords;
+        }
+    }
+
+    let has_normals = match reader.read_normals() {
+        Some(normals) => {
+            for (v, normal) in vertices.iter_mut().zip(normals) {
+                v.normal = normal;
+            }
+            true
+        }
+        None => false,
+    };
+    // Tangents are only valid together with the normals they were made for.
+    let has_tangents = match reader.read_tangents() {
+        Some(tangents) if has_normals => {
+            for (v, tangent) in vertices.iter_mut().zip(tangents) {
+                v.tangent = tangent;
+            }
+            true
+        }
+        _ => false,
+    };
+
+    let mut skin_vertices = match skin {
+        Some(skin) => Some(read_skin_vertices(primitive, buffers, skin, vertices.len())?),
+        None => None,
+    };
+
+    // Only position and normal deltas are used, tangents follow the base mesh.
+    let mut morph_targets = reader
+        .read_morph_targets()
+        .map(|(positions, normals, _)| {
+            let mut deltas = vec![model::MorphVertex::default(); vertices.len()];
+            for (d, position) in deltas.iter_mut().zip(positions.into_iter().flatten()) {
+                d.position = Vector3::from(position).extend(0.0).into();
+            }
+            for (d, normal) in deltas.iter_mut().zip(normals.into_iter().flatten()) {
+                d.normal = Vector3::from(normal).extend(0.0).into();
+            }
+            deltas
+        })
+        .collect::<Vec<_>>();
+
+    // Vertices split while generating normals and tangents keep their joints
+    // and morph deltas.
+    let mut remap = |sources: Vec<u32>| {
+        if let Some(skin_vertices) = skin_vertices.as_mut() {
+            *skin_vertices = sources.iter().map(|i| skin_vertices[*i as usize]).collect();
+        }
+        for deltas in morph_targets.iter_mut() {
+            *deltas = sources.iter().map(|i| deltas[*i as usize]).collect();
+        }
+    };
+
+    if !has_normals {
+        remap(geometry::generate_normals(&mut vertices, &mut indices, geometry::SMOOTHING_CREASE_ANGLE));
+    }
+    if !has_tangents {
+        // glTF texture coordinates already match wgpu's, so the bitangent
+        // doesn't need flipping.
+        remap(geometry::compute_tangents(&mut vertices, &mut indices, false));
+    }
+
+    if skin_vertices.is_some() {
+        return Ok(PrimitiveData { vertices, indices, skin_vertices, morph_targets });
+    }
+
+    let linear = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+    let normal_matrix = linear.invert().map(|m| m.transpose()).unwrap_or(linear);
+    // Mirroring transforms flip the winding order and the tangent frame.
+    let mirrored = linear.determinant() < 0.0;
+    for v in vertices.iter_mut() {
+        v.position = (transform * Vector4::new(v.position[0], v.position[1], v.position[2], 1.0))
+            .truncate()
+            .into();
+        let normal = normal_matrix * Vector3::from(v.normal);
+        if normal.magnitude2() > 0.0 {
+            v.normal = normal.normalize().into();
+        }
+        let tangent = linear * Vector3::new(v.tangent[0], v.tangent[1], v.tangent[2]);
+        if tangent.magnitude2() > 0.0 {
+            let w = if mirrored { -v.tangent[3] } else { v.tangent[3] };
+            v.tangent = tangent.normalize().extend(w).into();
+        }
+    }
+    if mirrored {
+        for c in indices.chunks_exact_mut(3) {
+            c.swap(1, 2);
+        }
+    }
+    for d in morph_targets.iter_mut().flatten() {
+        d.position = (linear * Vector4::from(d.position).truncate()).extend(0.0).into();
+        d.normal = (normal_matrix * Vector4::from(d.normal).truncate()).extend(0.0).into();
+    }
+
+    Ok(PrimitiveData { vertices, indices, skin_vertices, morph_targets })
+}
+
+// The rest weights of a node's morph targets, one per target of the mesh's
+// primitives.
+fn morph_weights(node: &gltf::Node, mesh: &gltf::Mesh) -> Vec<f32> {
+    let num_targets = mesh
+        .primitives()
+        .map(|primitive| primitive.morph_targets().len())
+        .max()
+        .unwrap_or(0);
+    let mut weights = node.weights().or(mesh.weights()).unwrap_or_default().to_vec();
+    weights.resize(num_targets, 0.0);
+    weights
+}
+
+fn load_skins(document: &gltf::Document, buffers: &[Vec<u8>]) -> Vec<animation::Skin> {
+    let mut joint_offset = 0;
+    document
+        .skins()
+        .map(|skin| {
+            let reader = skin.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+            let joints = skin.joints().map(|joint| joint.index()).collect::<Vec<_>>();
+            // Missing inverse bind matrices are identity matrices.
+            let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+                Some(matrices) => matrices.collect(),
+                None => vec![Matrix4::<f32>::identity().into(); joints.len()],
+            };
+            let skin = animation::Skin {
+                joints,
+                inverse_bind_matrices,
+                joint_offset,
+            };
+            joint_offset += skin.joints.len() as u32;
+            skin
+        })
+        .collect()
+}
+
+fn load_animations(
+    document: &gltf::Document,
+    buffers: &[Vec<u8>],
+    nodes: &[model::ModelNode],
+) -> anyhow::Result<Vec<animation::AnimationClip>> {
+    document
+        .animations()
+        .map(|animation| {
+            let mut channels = Vec::new();
+            for channel in animation.channels() {
+                let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+                let times = reader
+                    .read_inputs()
+                    .ok_or_else(|| anyhow!("Animation channel has no keyframe times"))?
+                    .collect::<Vec<_>>();
+                let node = channel.target().node().index();
+                let (property, values, components) = match reader.read_outputs() {
+                    Some(ReadOutputs::Translations(t)) => (animation::Property::Translation, t.flatten().collect::<Vec<_>>(), 3),
+                    Some(ReadOutputs::Rotations(r)) => (animation::Property::Rotation, r.into_f32().flatten().collect(), 4),
+                    Some(ReadOutputs::Scales(s)) => (animation::Property::Scale, s.flatten().collect(), 3),
+                    Some(ReadOutputs::MorphTargetWeights(w)) => {
+                        (animation::Property::Weights, w.into_f32().collect(), nodes[node].weights.len())
+                    }
+                    None => bail!("Animation channel has no keyframe values"),
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => animation::Interpolation::Step,
+                    gltf::animation::Interpolation::Linear => animation::Interpolation::Linear,
+                    gltf::animation::Interpolation::CubicSpline => animation::Interpolation::CubicSpline,
+                };
+                let values_per_keyframe = if interpolation == animation::Interpolation::CubicSpline { 3 } else { 1 };
+                if values.len() != times.len() * values_per_keyframe * components {
+                    bail!("Animation channel has {} keyframes but {} values", times.len(), values.len());
+                }
+                channels.push(animation::Channel {
+                    node,
+                    property,
+                    sampler: animation::Sampler {
+                        interpolation,
+                        times,
+                        values,
+                        components,
+                    },
+                });
+            }
+
+            let duration = channels
+                .iter()
+                .filter_map(|c| c.sampler.times.last().copied())
+                .fold(0.0, f32::max);
+            Ok(animation::AnimationClip {
+                name: animation
+                    .name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("animation {}", animation.index())),
+                duration,
+                channels,
+            })
+        })
+        .collect()
+}
+
+// Reads the primitives of every mesh in the default scene.
+fn load_meshes(
+    document: &gltf::Document,
+    buffers: &[Vec<u8>],
+    skins: &[animation::Skin],
+    nodes: &[model::ModelNode],
+) -> anyhow::Result<Vec<model::MeshData>> {
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| anyhow!("No scenes"))?;
+
+    let mut meshes = Vec::new();
+    let mut stack = scene
+        .nodes()
+        .map(|node| (node, Matrix4::<f32>::identity()))
+        .collect::<Vec<_>>();
+    while let Some((node, parent_transform)) = stack.pop() {
+        let transform = parent_transform * Matrix4::from(node.transform().matrix());
+        stack.extend(node.children().map(|child| (child, transform)));
+
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
+        // The transform of a skinned mesh's node is ignored, its joints place it.
+        let skin = node.skin().map(|skin| &skins[skin.index()]);
+        for primitive in mesh.primitives() {
+            let skin = skin.filter(|_| primitive.get(&gltf::Semantic::Joints(0)).is_some());
+            let PrimitiveData { vertices, indices, skin_vertices, morph_targets } =
+                load_primitive(&primitive, buffers, transform, skin)
+                    .with_context(|| format!("Could not load mesh {:?}", mesh.name()))?;
+            meshes.push(model::MeshData {
+                name: mesh.name().unwrap_or_default().to_string(),
+                vertices,
+                indices,
+                material: primitive.material().index(),
+                node: Some(node.index()),
+                skin_vertices,
+                morph_targets,
+                weight_offset: nodes[node.index()].weight_offset,
+            });
+        }
+    }
+    Ok(meshes)
+}
+
+// Parses the glTF file and loads its buffers.
+fn read_gltf(file_name: &str) -> anyhow::Result<(gltf::Document, Vec<Vec<u8>>)> {
+    let base_dir = Path::new(file_name).parent().unwrap_or(Path::new(""));
+    let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(&load_binary(file_name)?)
+        .with_context(|| format!("Could not parse {}", file_name))?;
+
+    let buffers = document
+        .buffers()
+        .map(|buffer| {
+            let data = match buffer.source() {
+                gltf::buffer::Source::Bin => blob.take().ok_or_else(|| anyhow!("Missing binary chunk"))?,
+                gltf::buffer::Source::Uri(uri) => load_uri(base_dir, uri)?,
+            };
+            // Views and accessors are only checked against the declared
+            // length.
+            if data.len() < buffer.length() {
+                bail!("Buffer {} has {} bytes, expected {}", buffer.index(), data.len(), buffer.length());
+            }
+            Ok(data)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .with_context(|| format!("Could not load the buffers of {}", file_name))?;
+    Ok((document, buffers))
+}
+
+// The hierarchy is kept in the same order as the glTF nodes.
+fn load_nodes(document: &gltf::Document) -> Vec<model::ModelNode> {
+    let mut weight_offset = 0;
+    let mut nodes = document
+        .nodes()
+        .map(|node| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            let weights = node.mesh().map(|mesh| morph_weights(&node, &mesh)).unwrap_or_default();
+            let node = model::ModelNode {
+                name: node.name().unwrap_or_default().to_string(),
+                parent: None,
+                children: node.children().map(|child| child.index()).collect(),
+                translation,
+                rotation,
+                scale,
+                weights,
+                weight_offset,
+                meshes: vec![],
+            };
+            weight_offset += node.weights.len() as u32;
+            node
+        })
+        .collect::<Vec<_>>();
+    for node in document.nodes() {
+        for child in node.children() {
+            nodes[child.index()].parent = Some(node.index());
+        }
+    }
+    nodes
+}
+
+// Bakes the meshes of the glTF file and the images its materials use, with
+// the same color spaces load_material loads them in. Skins, animations and
+// materials are still read from the glTF file when loading.
+pub fn bake_gltf_model(baker: &mut Baker, file_name: &str) -> anyhow::Result<()> {
+    let base_dir = Path::new(file_name).parent().unwrap_or(Path::new(""));
+    let (document, buffers) = read_gltf(file_name)?;
+    let nodes = load_nodes(&document);
+    let skins = load_skins(&document, &buffers);
+    let mesh_data = load_meshes(&document, &buffers, &skins, &nodes)
+        .with_context(|| format!("Could not load the meshes of {}", file_name))?;
+    // Images may be embedded in any of the buffers.
+    let sources: Vec<String> = std::iter::once(file_name.to_string())
+        .chain(buffer_files(&document, base_dir))
+        .collect();
+    baker.add_model(file_name, &sources, &[], &[], &mesh_data)?;
+
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let textures = [
+            (pbr.base_color_texture().map(|i| i.texture()), texture::TextureKind::Color),
+            (material.normal_texture().map(|n| n.texture()), texture::TextureKind::Normal),
+            (pbr.metallic_roughness_texture().map(|i| i.texture()), texture::TextureKind::Data),
+            (material.occlusion_texture().map(|o| o.texture()), texture::TextureKind::Data),
+            (material.emissive_texture().map(|i| i.texture()), texture::TextureKind::Color),
+        ];
+        for (texture, kind) in textures {
+            let Some(image) = texture.map(|texture| texture.source()) else {
+                continue;
+            };
+            let mut image_sources = sources.clone();
+            if let gltf::image::Source::Uri { uri, .. } = image.source() {
+                image_sources.extend(uri_path(base_dir, uri));
+            }
+            baker.add_texture(&image_name(file_name, &image), kind, &image_sources, || {
+                let bytes = image_bytes(&image, base_dir, &buffers)?;
+                texture::Texture::decode_rgba8(&bytes, &image_name(file_name, &image))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn load_gltf_model(
+    file_name: &str,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    cache: &TextureCache,
+    cached: Option<&mesh_cache::CachedModel>,
+) -> anyhow::Result<model::WgpuModel> {
+    let begin = Instant::now();
+
+    let base_dir = Path::new(file_name).parent().unwrap_or(Path::new(""));
+    let (document, buffers) = read_gltf(file_name)?;
+    println!(
+        "Buffers of {} took {}ms to load.",
+        file_name,
+        begin.elapsed().as_millis()
+    );
+
+    let textures = TextureLoader {
+        file_name,
+        base_dir,
+        buffers: &buffers,
+        device,
+        uploads,
+        cache,
+    };
+    let defaults = &cache.defaults;
+    let mut materials = document
+        .materials()
+        .map(|material| load_material(material, &textures, defaults, layout))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Primitives without a material use the glTF default material, which
+    // has all factors at one.
+    let default_material_index = materials.len();
+    materials.push(model::WgpuMaterial::new(
+        device,
+        "glTF default material",
+        model::MaterialTextures {
+            base_color: defaults.white.clone(),
+            normal: defaults.normal.clone(),
+            metallic_roughness: defaults.white.clone(),
+            occlusion: defaults.white.clone(),
+            emissive: defaults.white.clone(),
+        },
+        model::MaterialSamplers::default(),
+        model::MaterialUniform::new(),
+        layout,
+    ));
+
+    let mut nodes = load_nodes(&document);
+
+    let skins = load_skins(&document, &buffers);
+    if skins.iter().map(|skin| skin.joints.len()).sum::<usize>() > u16::MAX as usize {
+        bail!("{} has too many joints", file_name);
+    }
+    let animations = load_animations(&document, &buffers, &nodes)?;
+
+    let material = |index: Option<usize>| index.filter(|i| *i < default_material_index).unwrap_or(default_material_index);
+    let meshes = match cached {
+        Some(cached) => {
+            let mut meshes = Vec::new();
+            for mesh in &cached.meshes {
+                if let Some(node) = mesh.node() {
+                    nodes
+                        .get_mut(node)
+                        .ok_or_else(|| anyhow!("Mesh cache of {} doesn't match its nodes", file_name))?
+                        .meshes
+                        .push(meshes.len());
+                }
+                meshes.push(cached.upload(mesh, device, uploads, file_name, material(mesh.material())));
+            }
+            meshes
+        }
+        None => {
+            let mesh_data = load_meshes(&document, &buffers, &skins, &nodes)
+                .with_context(|| format!("Could not load the meshes of {}", file_name))?;
+            // The meshes only depend on the glTF file and its external buffers.
+            let dependencies = std::iter::once(file_name.to_string())
+                .chain(buffer_files(&document, base_dir))
+                .collect::<Vec<_>>();
+            if let Err(e) = mesh_cache::store(file_name, &dependencies, &[], &[], &mesh_data) {
+                log::warn!("Could not write mesh cache of {}: {}", file_name, e);
+            }
+            mesh_data
+                .iter()
+                .enumerate()
+                .map(|(i, mesh)| {
+                    if let Some(node) = mesh.node {
+                        nodes[node].meshes.push(i);
+                    }
+                    mesh.upload(device, uploads, file_name, material(mesh.material))
+                })
+                .collect()
+        }
+    };
+
+    println!(
+        "Model {} took {}ms to load.",
+        file_name,
+        begin.elapsed().as_millis()
+    );
+    let sources = std::iter::once(file_name.to_string())
+        .chain(buffer_files(&document, base_dir))
+        .chain(document.images().filter_map(|image| match image.source() {
+            gltf::image::Source::Uri { uri, .. } => uri_path(base_dir, uri),
+            gltf::image::Source::View { .. } => None,
+        }))
+        .collect();
+    Ok(model::WgpuModel {
+        meshes,
+        materials,
+        nodes,
+        skins,
+        animations,
+        sources,
+    })
+}
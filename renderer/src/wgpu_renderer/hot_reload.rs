@@ -0,0 +1,61 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Files are checked at most this often, each check asks the asset sources
+// about every watched file.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// What a file looked like when it was last checked, None if it was missing.
+type Stamp = Option<(u64, Option<SystemTime>)>;
+
+fn stamp(path: &str) -> Stamp {
+    crate::assets::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.len, metadata.modified))
+}
+
+// Notices when watched files change by polling their size and modification
+// time, which works for every asset source and needs no OS support.
+pub struct FileWatcher {
+    files: HashMap<String, Stamp>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    // Files already watched keep their stamp, so a change is reported once.
+    pub fn watch(&mut self, path: &str) {
+        if !self.files.contains_key(path) {
+            self.files.insert(path.to_string(), stamp(path));
+        }
+    }
+
+    // The watched files that changed since the last call, empty if that
+    // was less than POLL_INTERVAL ago. Files that were deleted are reported
+    // once they're back.
+    pub fn changed(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+        let mut changed = Vec::new();
+        for (path, last) in &mut self.files {
+            let current = stamp(path);
+            if current != *last {
+                if current.is_some() {
+                    changed.push(path.clone());
+                }
+                *last = current;
+            }
+        }
+        changed
+    }
+}
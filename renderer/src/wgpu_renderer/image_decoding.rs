@@ -0,0 +1,86 @@
+use std::{fmt, io::Cursor, time::Instant};
+
+use image::{DynamicImage, ImageReader};
+
+// Why an encoded image couldn't be turned into a texture. Loading functions
+// return it inside their anyhow errors, so callers can downcast to it.
+#[derive(Debug)]
+pub enum ImageError {
+    // Not in a format the renderer decodes.
+    UnsupportedFormat(String),
+    // Truncated or malformed data.
+    Corrupt(String),
+    // Larger than the device allows for a 2D texture.
+    TooLarge { width: u32, height: u32, max_dimension: u32 },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::UnsupportedFormat(reason) => write!(f, "unsupported image format: {}", reason),
+            ImageError::Corrupt(reason) => write!(f, "corrupt image: {}", reason),
+            ImageError::TooLarge {
+                width,
+                height,
+                max_dimension,
+            } => write!(
+                f,
+                "image is {}x{}, textures can be at most {} pixels wide and high",
+                width, height, max_dimension
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<image::ImageError> for ImageError {
+    fn from(error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Unsupported(e) => ImageError::UnsupportedFormat(e.to_string()),
+            e => ImageError::Corrupt(e.to_string()),
+        }
+    }
+}
+
+// The texture size wgpu guarantees, for decoding without a device at hand.
+pub const DEFAULT_MAX_DIMENSION: u32 = 8192;
+
+// Decodes a PNG, JPEG, TGA, BMP, GIF, HDR, EXR or PNM image, keeping its
+// channels and bit depth. The size is checked from the header before any
+// pixels are decoded.
+pub fn decode(bytes: &[u8], max_dimension: u32) -> Result<DynamicImage, ImageError> {
+    let begin = Instant::now();
+    let reader = || {
+        ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|e| ImageError::Corrupt(e.to_string()))
+    };
+    let format = reader()?
+        .format()
+        .ok_or_else(|| ImageError::UnsupportedFormat("unknown file signature".to_string()))?;
+    let (width, height) = reader()?.into_dimensions()?;
+    if width > max_dimension || height > max_dimension {
+        return Err(ImageError::TooLarge {
+            width,
+            height,
+            max_dimension,
+        });
+    }
+    let image = reader()?.decode()?;
+    println!(
+        "Decoding {:?} image of {}x{} took {}ms",
+        format,
+        width,
+        height,
+        begin.elapsed().as_millis()
+    );
+    Ok(image)
+}
+
+// Whether the image has more precision than 8 bits per channel, like 16 bit
+// PNGs or HDR and EXR images.
+pub fn is_high_precision(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bits_per_pixel() / color.channel_count() as u16 > 8
+}
@@ -85,49 +85,110 @@ pub struct InstanceManager {
 pub struct InstanceGroup {
     model: u16, // TODO: Remove
     buffer: wgpu::Buffer,
-    instance_indices: Box<[u16]>,      // Sparse set
+    slots: InstanceSlots,
     instance_data: Box<[InstanceRaw]>, // Sparse set
-    free_list: Vec<u16>,               // Sparse set
     max_instances: u16,
+}
+
+// Which slot of the packed instance buffer each instance id points to.
+struct InstanceSlots {
+    instance_indices: Box<[u16]>, // Sparse set
+    free_list: Vec<u16>,          // Sparse set
     num_instances: u16,
 }
 
+impl InstanceSlots {
+    fn new(max_instances: u16) -> Self {
+        Self {
+            instance_indices: vec![u16::MAX; max_instances as usize].into_boxed_slice(),
+            free_list: vec![],
+            num_instances: 0,
+        }
+    }
+
+    fn index(&self, instance_id: u16) -> Option<u16> {
+        self.instance_indices
+            .get(instance_id as usize)
+            .copied()
+            .filter(|index| *index != u16::MAX)
+    }
+
+    // Ids 0..num_instances in order, the previous ids are gone.
+    fn reset(&mut self, num_instances: u16) {
+        self.free_list.clear();
+        self.num_instances = num_instances;
+        for (i, index) in self.instance_indices.iter_mut().enumerate() {
+            *index = if i < num_instances as usize { i as u16 } else { u16::MAX };
+        }
+    }
+
+    // Returns the new id and its slot, the one after the last instance.
+    fn insert(&mut self) -> (u16, u16) {
+        let instance_index = self.num_instances;
+        let instance_id = self.free_list.pop().unwrap_or(self.num_instances);
+        self.num_instances += 1;
+        self.instance_indices[instance_id as usize] = instance_index;
+        (instance_id, instance_index)
+    }
+
+    // The last instance moves into the freed slot, so the buffer stays
+    // packed. Returns the freed slot and the one the last instance came
+    // from, None for ids that aren't in use.
+    fn remove(&mut self, instance_id: u16) -> Option<(u16, u16)> {
+        let instance_index = self.index(instance_id)?;
+        let last_index = self.num_instances - 1;
+        if last_index != instance_index {
+            let last_id = self
+                .instance_indices
+                .iter()
+                .position(|index| *index == last_index)
+                .expect("Every instance has an id.");
+            self.instance_indices[last_id] = instance_index;
+        }
+        self.num_instances -= 1;
+        self.instance_indices[instance_id as usize] = u16::MAX;
+        self.free_list.push(instance_id);
+        Some((instance_index, last_index))
+    }
+}
+
 impl InstanceGroup {
+    pub fn max_instances(&self) -> u16 {
+        self.max_instances
+    }
+
     // TODO: Remove
     pub fn len(&self) -> u64 {
-        self.num_instances as u64
+        self.slots.num_instances as u64
     }
     // TODO: Remove
     pub fn buffer(&self) -> &wgpu::Buffer {
         &self.buffer
     }
 
+    // Position of the instance in the instance buffer, if it exists.
+    pub fn instance_index(&self, instance_id: u16) -> Option<u16> {
+        self.slots.index(instance_id)
+    }
+
     // model is a parameter for debug purposes
-    pub fn new_empty(model: u16, device: &mut wgpu::Device, max_instances: u16) -> InstanceGroup {
+    pub fn new_empty(model: u16, device: &wgpu::Device, max_instances: u16) -> InstanceGroup {
         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("instance buffer"), // TODO: Add model name to label
             size: (size_of::<InstanceRaw>() * max_instances as usize) as u64, // TODO: Add model name to label
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false, // TODO: Consider this
         });
-        let instance_indices = vec![u16::MAX; max_instances as usize];
-        let mut instance_data: Vec<InstanceRaw> = Vec::with_capacity(max_instances as usize);
-        unsafe {
-            instance_data.set_len(max_instances as usize);
-        }
-        let instance_indices = instance_indices.into_boxed_slice();
-        debug_assert_eq!(instance_indices.len(), max_instances as usize);
+        let instance_data: Vec<InstanceRaw> = vec![bytemuck::Zeroable::zeroed(); max_instances as usize];
         let instance_data = instance_data.into_boxed_slice();
         debug_assert_eq!(instance_data.len(), max_instances as usize);
 
         Self {
             model,
             buffer,
-            instance_indices,
+            slots: InstanceSlots::new(max_instances),
             instance_data,
             max_instances,
-            num_instances: 0,
-            free_list: vec![],
         }
     }
 }
@@ -140,7 +201,7 @@ impl InstanceManager {
         }
     }
 
-    pub fn add_instance_group(&mut self, device: &mut wgpu::Device, model: u16, max_instances: u16) {
+    pub fn add_instance_group(&mut self, device: &wgpu::Device, model: u16, max_instances: u16) {
         self.instance_groups.push(InstanceGroup::new_empty(model, device, max_instances));
     }
 
@@ -153,17 +214,13 @@ impl InstanceManager {
         let instance_group = &mut self.instance_groups[model.0 as usize];
         debug_assert_eq!(model.0, instance_group.model);
         assert!(instances.len() <= instance_group.max_instances as usize);
-        instance_group.free_list.clear();
-        instance_group.num_instances = instances.len() as u16;
 
         for (i, item) in instances.iter().map(Instance::to_raw).enumerate() {
             instance_group.instance_data[i] = item;
         }
         
         assert!(instances.len() < u16::MAX.into());
-        for i in 0..instances.len() {
-            instance_group.instance_indices[i] = i as u16;
-        }
+        instance_group.slots.reset(instances.len() as u16);
 
         let mut buffer_view = queue
             .write_buffer_with(
@@ -176,71 +233,21 @@ impl InstanceManager {
         buffer_view.copy_from_slice(bytemuck::cast_slice(&instance_group.instance_data[0..instances.len()]));
     }
 
-    pub fn clear_instances(&mut self, model: ModelHandle) {
-        let instance_group = &mut self.instance_groups[model.0 as usize];
-        debug_assert_eq!(model.0, instance_group.model);
-        let n_instances = instance_group.num_instances as usize;
-        instance_group.free_list.extend(&instance_group.instance_indices[0..n_instances]);
-        // TODO: if compiling on debug mode
-        for i in instance_group.instance_indices.iter_mut() {
-            *i = u16::MAX;
-        }
-        instance_group.num_instances = 0;
-    }
-
-    pub fn add_from_slice(
-        &mut self,
-        model: ModelHandle,
-        instances: &[Instance],
-        queue: &mut wgpu::Queue,
-    ) {
-        let instance_group = &mut self.instance_groups[model.0 as usize];
-        debug_assert_eq!(model.0, instance_group.model);
-        let mut i = 0;
-        while let Some(instance_id) = instance_group.free_list.pop() {
-            instance_group.instance_indices[instance_id as usize] = instance_group.num_instances;
-            {
-                let instance_data = instances[i].to_raw();
-                let mut buffer_view = queue
-                    .write_buffer_with(
-                        &instance_group.buffer,
-                        instance_group.num_instances as u64 * Self::INSTANCE_SIZE,
-                        Self::INSTANCE_SIZE_NZ,
-                    )
-                    .expect("Could not access instance buffer.");
-                buffer_view.copy_from_slice(bytemuck::bytes_of(&instance_data));
-                instance_group.instance_data[instance_group.num_instances as usize] = instance_data;
-            }
-            
-            instance_group.num_instances += 1;
-            i += 1;
-        }
+    const INSTANCE_SIZE: u64 = size_of::<InstanceRaw>() as u64;
+    const INSTANCE_SIZE_NZ: NonZeroU64 = NonZeroU64::new(Self::INSTANCE_SIZE).unwrap();
 
-        // for instance in &instances[i..] {
-        //     instance_group.instance_indices[instance_group.num_instances] = instance_group.num_instances;
-        //     instance_group
-        //     {
-        //         let instance_data = instances[i].to_raw();
-        //         let mut buffer_view = queue
-        //             .write_buffer_with(
-        //                 &instance_group.buffer,
-        //                 instance_group.num_instances as u64 * Self::INSTANCE_SIZE,
-        //                 Self::INSTANCE_SIZE_NZ,
-        //             )
-        //             .expect("Could not access instance buffer.");
-        //         buffer_view.copy_from_slice(bytemuck::bytes_of(&instance_data));
-        //         instance_group.instance_data[instance_group.num_instances as usize] = instance_data;
-        //     }
-            
-        //     instance_group.num_instances += 1;
-        //     i += 1;
-        // }
-        todo!()
+    fn write_instance(queue: &wgpu::Queue, instance_group: &mut InstanceGroup, instance_index: u16, instance_data: InstanceRaw) {
+        let mut buffer_view = queue
+            .write_buffer_with(
+                &instance_group.buffer,
+                instance_index as u64 * Self::INSTANCE_SIZE,
+                Self::INSTANCE_SIZE_NZ,
+            )
+            .expect("Could not access instance buffer.");
+        buffer_view.copy_from_slice(bytemuck::bytes_of(&instance_data));
+        instance_group.instance_data[instance_index as usize] = instance_data;
     }
 
-    const INSTANCE_SIZE: u64 = size_of::<InstanceRaw>() as u64;
-    const INSTANCE_SIZE_NZ: NonZeroU64 = unsafe { NonZeroU64::new_unchecked(Self::INSTANCE_SIZE) };
-
     pub fn add_instance(
         &mut self,
         queue: &wgpu::Queue,
@@ -252,37 +259,17 @@ impl InstanceManager {
         debug_assert_eq!(model.0, instance_group.model);
 
         // assert_ne!(instance_group.max_instances, instance_group.num_instances);
-        if instance_group.max_instances == instance_group.num_instances {
+        if instance_group.max_instances == instance_group.slots.num_instances {
             panic!("Instance buffer is full.");
         }
 
-        let instance_index = instance_group.num_instances;
-
-        {
-            let instance_data = instance.to_raw();
-            let mut buffer_view = queue
-                .write_buffer_with(
-                    &instance_group.buffer,
-                    instance_index as u64 * Self::INSTANCE_SIZE,
-                    Self::INSTANCE_SIZE_NZ,
-                )
-                .expect("Could not access instance buffer.");
-            buffer_view.copy_from_slice(bytemuck::bytes_of(&instance_data));
-            instance_group.instance_data[instance_index as usize] = instance_data;
-        }
-
-        let instance_id = if instance_group.free_list.is_empty() {
-            instance_group.num_instances
-        } else {
-            instance_group.free_list.pop().unwrap()
-        };
-        instance_group.num_instances += 1;
-        instance_group.instance_indices[instance_id as usize] = instance_index;
+        let (instance_id, instance_index) = instance_group.slots.insert();
+        Self::write_instance(queue, instance_group, instance_index, instance.to_raw());
 
         InstanceHandle(model, instance_id)
     }
 
-    // Returns an instance id.
+    // Handles of removed instances are ignored.
     pub fn update_instance(
         &mut self,
         queue: &wgpu::Queue,
@@ -291,45 +278,65 @@ impl InstanceManager {
     ) {
         let instance_group = &mut self.instance_groups[instance_handle.0 .0 as usize];
         debug_assert_eq!(instance_handle.0.0, instance_group.model);
-        let instance_index = instance_group.instance_indices[instance_handle.1 as usize];
-
-        {
-            let instance_data = new_instance.to_raw();
-            let mut buffer_view = queue
-                .write_buffer_with(
-                    &instance_group.buffer,
-                    instance_index as u64 * Self::INSTANCE_SIZE,
-                    Self::INSTANCE_SIZE_NZ,
-                )
-                .expect("Could not access instance buffer.");
-            buffer_view.copy_from_slice(bytemuck::bytes_of(&instance_data));
-            instance_group.instance_data[instance_index as usize] = instance_data;
-        }
+        let Some(instance_index) = instance_group.slots.index(instance_handle.1) else {
+            return;
+        };
+        Self::write_instance(queue, instance_group, instance_index, new_instance.to_raw());
     }
 
+    // Handles of removed instances are ignored.
     pub fn delete_instance(&mut self, queue: &wgpu::Queue, instance_handle: InstanceHandle) {
         let instance_group = &mut self.instance_groups[instance_handle.0 .0 as usize];
         debug_assert_eq!(instance_handle.0.0, instance_group.model);
-        debug_assert!(instance_group.num_instances != 0); // TODO: Unreachable
-        let instance_index = instance_group.instance_indices[instance_handle.1 as usize];
-
-        if instance_group.num_instances - 1 != instance_index {
-            let mut buffer_view = queue
-                .write_buffer_with(
-                    &instance_group.buffer,
-                    instance_index as u64 * Self::INSTANCE_SIZE,
-                    Self::INSTANCE_SIZE_NZ,
-                )
-                .expect("Could not access instance buffer.");
-
-            let last_instance_data =
-                &instance_group.instance_data[(instance_group.num_instances - 1) as usize];
-            buffer_view.copy_from_slice(bytemuck::bytes_of(last_instance_data));
+        let Some((instance_index, last_index)) = instance_group.slots.remove(instance_handle.1) else {
+            return;
+        };
+        if last_index != instance_index {
+            let last_instance_data = instance_group.instance_data[last_index as usize];
+            Self::write_instance(queue, instance_group, instance_index, last_instance_data);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InstanceSlots;
+
+    #[test]
+    fn removing_an_instance_moves_the_last_one_into_its_slot() {
+        let mut slots = InstanceSlots::new(4);
+        let ids = [slots.insert(), slots.insert(), slots.insert()];
+        assert_eq!(ids, [(0, 0), (1, 1), (2, 2)]);
+
+        assert_eq!(slots.remove(0), Some((0, 2)));
+        assert_eq!(slots.num_instances, 2);
+        assert_eq!(slots.index(0), None);
+        assert_eq!(slots.index(1), Some(1));
+        assert_eq!(slots.index(2), Some(0));
+    }
 
-        instance_group.num_instances -= 1;
-        instance_group.instance_indices[instance_group.num_instances as usize] = instance_index;
-        instance_group.instance_indices[instance_index as usize] = u16::MAX;
-        instance_group.free_list.push(instance_index);
+    #[test]
+    fn removed_ids_are_reused_and_ignored_until_then() {
+        let mut slots = InstanceSlots::new(4);
+        slots.insert();
+        slots.insert();
+        assert_eq!(slots.remove(1), Some((1, 1)));
+        assert_eq!(slots.remove(1), None);
+        assert_eq!(slots.remove(7), None);
+        assert_eq!(slots.insert(), (1, 1));
+        assert_eq!(slots.insert(), (2, 2));
+    }
+
+    #[test]
+    fn reset_forgets_the_previous_ids() {
+        let mut slots = InstanceSlots::new(4);
+        for _ in 0..4 {
+            slots.insert();
+        }
+        slots.remove(0);
+        slots.reset(2);
+        assert_eq!(slots.index(1), Some(1));
+        assert_eq!(slots.index(3), None);
+        assert_eq!(slots.insert(), (2, 2));
     }
 }
@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, sync::OnceLock, time::UNIX_EPOCH};
+
+use anyhow::anyhow;
+
+use super::resources::{load_binary, load_string};
+
+// The asset-bake tool writes preprocessed assets to res/baked, together with
+// a manifest mapping the logical name the renderer asks for to the baked
+// file and the hash of its contents. Every line of the manifest reads
+//
+// key <tab> file <tab> hash [<tab> source <tab> size <tab> modified]...
+//
+// where key is the file name of a model, or the texture_key of a texture,
+// followed by the files it was baked from. modified is in nanoseconds since
+// the epoch, a baked file is stale as soon as any of its sources changes.
+// Builds that ship res/baked without the sources use it as is.
+pub const BAKED_DIR: &str = "baked";
+const MANIFEST_FILE: &str = "baked/manifest.txt";
+
+pub struct ManifestEntry {
+    // Relative to BAKED_DIR.
+    pub file: String,
+    pub hash: u64,
+    pub sources: Vec<SourceFile>,
+}
+
+// A file an entry was baked from, as it was at the time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: String,
+    pub size: u64,
+    pub modified: u128,
+}
+
+impl SourceFile {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        let metadata = crate::assets::metadata(path)?;
+        let modified = metadata.modified.unwrap_or(UNIX_EPOCH).duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            path: path.to_string(),
+            size: metadata.len,
+            modified: modified.as_nanos(),
+        })
+    }
+
+    // Missing sources are current, only edited ones make a file stale.
+    fn is_current(&self) -> bool {
+        !crate::assets::exists(&self.path) || Self::new(&self.path).is_ok_and(|current| current == *self)
+    }
+}
+
+#[derive(Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut entries = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || anyhow!("Invalid manifest line {}", number + 1);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [key, file, hash, sources @ ..] = fields.as_slice() else {
+                return Err(invalid());
+            };
+            if sources.len() % 3 != 0 {
+                return Err(invalid());
+            }
+            let hash = u64::from_str_radix(hash, 16)?;
+            let sources = sources
+                .chunks(3)
+                .map(|source| {
+                    Ok(SourceFile {
+                        path: source[0].to_string(),
+                        size: source[1].parse()?,
+                        modified: source[2].parse()?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            entries.insert(key.to_string(), ManifestEntry { file: file.to_string(), hash, sources });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.entries
+            .iter()
+            .map(|(key, entry)| {
+                let mut line = format!("{}\t{}\t{:016x}", key, entry.file, entry.hash);
+                for source in &entry.sources {
+                    line += &format!("\t{}\t{}\t{}", source.path, source.size, source.modified);
+                }
+                line + "\n"
+            })
+            .collect()
+    }
+}
+
+// The key of a texture, the same image can be baked once as color and once
+// as linear data.
+pub fn texture_key(name: &str, is_linear: bool) -> String {
+    if is_linear {
+        format!("{}#linear", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// 64 bit FNV-1a, only used to detect baked files that don't match the
+// manifest.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// The manifest is read once, without one nothing is loaded from res/baked.
+fn manifest() -> &'static Manifest {
+    static MANIFEST: OnceLock<Manifest> = OnceLock::new();
+    MANIFEST.get_or_init(|| {
+        let Ok(text) = load_string(MANIFEST_FILE) else {
+            return Manifest::default();
+        };
+        Manifest::parse(&text).unwrap_or_else(|e| {
+            log::warn!("Ignoring the baked asset manifest: {}", e);
+            Manifest::default()
+        })
+    })
+}
+
+// Reads the baked file of key, None if nothing was baked for it, if one of
+// its sources changed since, or if the file is missing or doesn't match its
+// hash. Sources that are gone don't count as changed.
+pub fn load_baked(key: &str) -> Option<Vec<u8>> {
+    let entry = manifest().entries.get(key)?;
+    if !entry.sources.iter().all(SourceFile::is_current) {
+        log::info!("Baked {} is older than its sources, loading them instead", key);
+        return None;
+    }
+    let bytes = match load_binary(&format!("{}/{}", BAKED_DIR, entry.file)) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::warn!("Could not read baked {}: {}", key, e);
+            return None;
+        }
+    };
+    if content_hash(&bytes) != entry.hash {
+        log::warn!("Baked {} doesn't match the manifest, loading the source instead", key);
+        return None;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{texture_key, Manifest, ManifestEntry, SourceFile};
+    use crate::assets::{self, MemorySource};
+
+    #[test]
+    fn manifests_survive_a_round_trip() {
+        let mut manifest = Manifest::default();
+        manifest.entries.insert(
+            "cube.obj".to_string(),
+            ManifestEntry {
+                file: "00000000000000ff.mesh".to_string(),
+                hash: 0xff,
+                sources: vec![
+                    SourceFile { path: "cube.obj".to_string(), size: 10, modified: 1_700_000_000_123_456_789 },
+                    SourceFile { path: "cube.mtl".to_string(), size: 2, modified: 0 },
+                ],
+            },
+        );
+        manifest.entries.insert(
+            texture_key("normal.png", true),
+            ManifestEntry { file: "0123456789abcdef.tex".to_string(), hash: 0x0123456789abcdef, sources: vec![] },
+        );
+        let text = manifest.to_text();
+        assert_eq!(
+            text,
+            "cube.obj\t00000000000000ff.mesh\t00000000000000ff\tcube.obj\t10\t1700000000123456789\tcube.mtl\t2\t0\n\
+             normal.png#linear\t0123456789abcdef.tex\t0123456789abcdef\n"
+        );
+
+        let parsed = Manifest::parse(&text).unwrap();
+        assert_eq!(parsed.entries.len(), 2);
+        let cube = &parsed.entries["cube.obj"];
+        assert_eq!(cube.file, "00000000000000ff.mesh");
+        assert_eq!(cube.hash, 0xff);
+        assert_eq!(cube.sources, manifest.entries["cube.obj"].sources);
+        assert!(parsed.entries["normal.png#linear"].sources.is_empty());
+        assert_eq!(parsed.to_text(), text);
+    }
+
+    #[test]
+    fn only_edited_sources_are_stale() {
+        let source = MemorySource::new();
+        source.insert("model.obj", b"v 0 0 0".to_vec());
+        assets::mount("manifest_tests", source);
+        let baked = SourceFile::new("manifest_tests/model.obj").unwrap();
+        assert!(baked.is_current());
+
+        // Shipped without its sources.
+        let gone = SourceFile { path: "manifest_tests/gone.obj".to_string(), size: 5, modified: 0 };
+        assert!(gone.is_current());
+
+        let edited = MemorySource::new();
+        edited.insert("model.obj", b"v 0 0 0\nv 1 0 0".to_vec());
+        assets::mount("manifest_tests", edited);
+        assert!(!baked.is_current());
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        assert!(Manifest::parse("cube.obj\tff.mesh\n").is_err());
+        assert!(Manifest::parse("cube.obj\tff.mesh\tnot hex\n").is_err());
+        assert!(Manifest::parse("cube.obj\tff.mesh\tff\tcube.obj\t10\n").is_err());
+        assert!(Manifest::parse("cube.obj\tff.mesh\tff\tcube.obj\tten\t0\n").is_err());
+        assert!(Manifest::parse("\n\n").unwrap().entries.is_empty());
+    }
+}
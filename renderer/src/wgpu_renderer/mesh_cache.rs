@@ -0,0 +1,467 @@
+use std::{
+    fmt::Write,
+    io::{BufReader, Cursor},
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, bail};
+
+use super::{model, streaming::Uploads};
+
+// Preprocessed meshes are cached in the asset cache directory so models don't have to be
+// parsed and have their normals and tangents generated on every launch. A
+// cache file stores:
+//
+// CacheHeader
+// the dependencies (DependencyHeader + path), a cache is stale as soon as
+//     any of them changes size or modification time
+// the material libraries (length + path) of OBJ models
+// the materials of OBJ models (length + MTL text), so the MTL files aren't
+//     needed to load them
+// per mesh: MeshHeader, name, ModelVertex data, u32 indices, SkinVertex data
+//     and the morph target buffer
+//
+// Mesh data is laid out exactly as in the GPU buffers, so loading it is a
+// copy into the buffers.
+const MAGIC: [u8; 4] = *b"RWMC";
+// Bump whenever the layout of the file or of the vertex types changes.
+const VERSION: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CacheHeader {
+    magic: [u8; 4],
+    version: u32,
+    vertex_size: u32,
+    num_dependencies: u32,
+    num_material_libraries: u32,
+    num_meshes: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+struct DependencyHeader {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    path_len: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MeshHeader {
+    // u32::MAX for meshes without a material, and for meshes that don't
+    // belong to a node.
+    material: u32,
+    node: u32,
+    num_vertices: u32,
+    num_indices: u32,
+    has_skin: u32,
+    morph_targets_len: u32,
+    name_len: u32,
+    bounds: model::Aabb,
+}
+
+// None while the cache is disabled.
+fn cache_path(file_name: &str) -> Option<PathBuf> {
+    Some(crate::assets::cache_dir()?.join(format!("{}.mesh", file_name)))
+}
+
+// Size and modification time of an asset, paths are stored in the cache so
+// the header also carries the path's length. Sources without modification
+// times only have their size checked.
+fn dependency_header(path: &str) -> anyhow::Result<DependencyHeader> {
+    let metadata = crate::assets::metadata(path)?;
+    let modified = metadata.modified.unwrap_or(UNIX_EPOCH).duration_since(UNIX_EPOCH)?;
+    Ok(DependencyHeader {
+        size: metadata.len,
+        modified_secs: modified.as_secs(),
+        modified_nanos: modified.subsec_nanos(),
+        path_len: path.len() as u32,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            bail!("Unexpected end of mesh cache");
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn read<T: bytemuck::Pod>(&mut self) -> anyhow::Result<T> {
+        Ok(bytemuck::pod_read_unaligned(self.take(size_of::<T>())?))
+    }
+
+    fn read_str(&mut self, len: u32) -> anyhow::Result<&'a str> {
+        Ok(std::str::from_utf8(self.take(len as usize)?)?)
+    }
+}
+
+pub struct CachedMesh {
+    header: MeshHeader,
+    name: String,
+    // Byte ranges into CachedModel::bytes.
+    vertices: std::ops::Range<usize>,
+    indices: std::ops::Range<usize>,
+    skin_vertices: Option<std::ops::Range<usize>>,
+    morph_targets: Option<std::ops::Range<usize>>,
+}
+
+impl CachedMesh {
+    pub fn material(&self) -> Option<usize> {
+        (self.header.material != u32::MAX).then_some(self.header.material as usize)
+    }
+
+    pub fn node(&self) -> Option<usize> {
+        (self.header.node != u32::MAX).then_some(self.header.node as usize)
+    }
+}
+
+pub struct CachedModel {
+    bytes: Vec<u8>,
+    pub material_libraries: Vec<String>,
+    pub materials: Vec<tobj::Material>,
+    pub meshes: Vec<CachedMesh>,
+}
+
+impl CachedModel {
+    pub fn upload(
+        &self,
+        mesh: &CachedMesh,
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        file_name: &str,
+        material: usize,
+    ) -> model::WgpuMesh {
+        model::WgpuMesh::new(
+            device,
+            uploads,
+            file_name,
+            &mesh.name,
+            model::MeshBytes {
+                vertices: &self.bytes[mesh.vertices.clone()],
+                indices: &self.bytes[mesh.indices.clone()],
+                skin_vertices: mesh.skin_vertices.clone().map(|range| &self.bytes[range]),
+                morph_targets: mesh.morph_targets.clone().map(|range| &self.bytes[range]),
+            },
+            material,
+            mesh.header.bounds,
+        )
+    }
+}
+
+// Reads the cache of the model, if there is one and it's up to date.
+pub fn load(file_name: &str) -> Option<CachedModel> {
+    let bytes = std::fs::read(cache_path(file_name)?).ok()?;
+    match parse(bytes) {
+        Ok(Some(cached)) => Some(cached),
+        Ok(None) => {
+            println!("Mesh cache of {} is stale", file_name);
+            None
+        }
+        Err(e) => {
+            log::warn!("Could not read mesh cache of {}: {}", file_name, e);
+            None
+        }
+    }
+}
+
+// Reads a cache file, None if it's stale. Baked models are cache files
+// without dependencies, so they never are.
+pub fn parse(bytes: Vec<u8>) -> anyhow::Result<Option<CachedModel>> {
+    let mut reader = Reader { bytes: &bytes };
+    let header = reader.read::<CacheHeader>()?;
+    if header.magic != MAGIC {
+        bail!("Not a mesh cache");
+    }
+    if header.version != VERSION || header.vertex_size != size_of::<model::ModelVertex>() as u32 {
+        return Ok(None);
+    }
+
+    for _ in 0..header.num_dependencies {
+        let dependency = reader.read::<DependencyHeader>()?;
+        let path = reader.read_str(dependency.path_len)?;
+        if dependency_header(path).ok() != Some(dependency) {
+            return Ok(None);
+        }
+    }
+
+    let mut material_libraries = Vec::new();
+    for _ in 0..header.num_material_libraries {
+        let len = reader.read::<u32>()?;
+        material_libraries.push(reader.read_str(len)?.to_string());
+    }
+    let len = reader.read::<u32>()?;
+    let mtl = reader.read_str(len)?;
+    let (materials, _) = tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mtl)))
+        .map_err(|e| anyhow!("Invalid materials in mesh cache: {}", e))?;
+
+    // The reader only keeps what's left of the file.
+    let range = |reader: &mut Reader, len: usize| -> anyhow::Result<std::ops::Range<usize>> {
+        let start = bytes.len() - reader.bytes.len();
+        reader.take(len)?;
+        Ok(start..start + len)
+    };
+    let mut meshes = Vec::new();
+    for _ in 0..header.num_meshes {
+        let mesh = reader.read::<MeshHeader>()?;
+        let name = reader.read_str(mesh.name_len)?.to_string();
+        let num_vertices = mesh.num_vertices as usize;
+        let vertices = range(&mut reader, num_vertices * size_of::<model::ModelVertex>())?;
+        let indices = range(&mut reader, mesh.num_indices as usize * size_of::<u32>())?;
+        let skin_vertices = match mesh.has_skin {
+            0 => None,
+            _ => Some(range(&mut reader, num_vertices * size_of::<model::SkinVertex>())?),
+        };
+        let morph_targets = match mesh.morph_targets_len {
+            0 => None,
+            len => Some(range(&mut reader, len as usize)?),
+        };
+        meshes.push(CachedMesh {
+            header: mesh,
+            name,
+            vertices,
+            indices,
+            skin_vertices,
+            morph_targets,
+        });
+    }
+    if !reader.bytes.is_empty() {
+        bail!("Trailing data in mesh cache");
+    }
+
+    Ok(Some(CachedModel {
+        bytes,
+        material_libraries,
+        materials,
+        meshes,
+    }))
+}
+
+// Writes the cache of the model. dependencies are the files (relative to
+// res) the meshes were made from.
+pub fn store(
+    file_name: &str,
+    dependencies: &[String],
+    material_libraries: &[String],
+    materials: &[tobj::Material],
+    meshes: &[model::MeshData],
+) -> anyhow::Result<()> {
+    let Some(path) = cache_path(file_name) else {
+        return Ok(());
+    };
+    let bytes = serialize(dependencies, material_libraries, materials, meshes)?;
+    let dir = path.parent().ok_or_else(|| anyhow!("Invalid cache path {:?}", path))?;
+    std::fs::create_dir_all(dir)?;
+    // Written to a temporary file first so a crash can't leave a truncated cache.
+    let temporary = path.with_extension("mesh.tmp");
+    std::fs::write(&temporary, &bytes)?;
+    std::fs::rename(&temporary, &path)?;
+    Ok(())
+}
+
+pub fn serialize(
+    dependencies: &[String],
+    material_libraries: &[String],
+    materials: &[tobj::Material],
+    meshes: &[model::MeshData],
+) -> anyhow::Result<Vec<u8>> {
+    let header = CacheHeader {
+        magic: MAGIC,
+        version: VERSION,
+        vertex_size: size_of::<model::ModelVertex>() as u32,
+        num_dependencies: dependencies.len() as u32,
+        num_material_libraries: material_libraries.len() as u32,
+        num_meshes: meshes.len() as u32,
+    };
+    let mut bytes = bytemuck::bytes_of(&header).to_vec();
+
+    for path in dependencies {
+        bytes.extend_from_slice(bytemuck::bytes_of(&dependency_header(path)?));
+        bytes.extend_from_slice(path.as_bytes());
+    }
+    for path in material_libraries {
+        bytes.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(path.as_bytes());
+    }
+    let mtl = to_mtl(materials);
+    bytes.extend_from_slice(&(mtl.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(mtl.as_bytes());
+
+    for mesh in meshes {
+        let morph_targets = mesh.morph_target_bytes().unwrap_or_default();
+        let header = MeshHeader {
+            material: mesh.material.map_or(u32::MAX, |material| material as u32),
+            node: mesh.node.map_or(u32::MAX, |node| node as u32),
+            num_vertices: mesh.vertices.len() as u32,
+            num_indices: mesh.indices.len() as u32,
+            has_skin: mesh.skin_vertices.is_some() as u32,
+            morph_targets_len: morph_targets.len() as u32,
+            name_len: mesh.name.len() as u32,
+            bounds: model::Aabb::from_vertices(&mesh.vertices),
+        };
+        bytes.extend_from_slice(bytemuck::bytes_of(&header));
+        bytes.extend_from_slice(mesh.name.as_bytes());
+        bytes.extend_from_slice(bytemuck::cast_slice(&mesh.vertices));
+        bytes.extend_from_slice(bytemuck::cast_slice(&mesh.indices));
+        if let Some(skin_vertices) = &mesh.skin_vertices {
+            bytes.extend_from_slice(bytemuck::cast_slice(skin_vertices));
+        }
+        bytes.extend_from_slice(&morph_targets);
+    }
+    Ok(bytes)
+}
+
+// Writes every statement tobj reads back, the colors included even where
+// the MTL files left them out, so their defaults are kept.
+fn to_mtl(materials: &[tobj::Material]) -> String {
+    let mut mtl = String::new();
+    for m in materials {
+        let color = |c: [f32; 3]| format!("{} {} {}", c[0], c[1], c[2]);
+        let _ = writeln!(mtl, "newmtl {}", m.name);
+        let _ = writeln!(mtl, "Ka {}\nKd {}\nKs {}", color(m.ambient), color(m.diffuse), color(m.specular));
+        let _ = writeln!(mtl, "Ns {}\nd {}\nNi {}", m.shininess, m.dissolve, m.optical_density);
+        if let Some(illumination_model) = m.illumination_model {
+            let _ = writeln!(mtl, "illum {}", illumination_model);
+        }
+        let textures = [
+            ("map_Ka", &m.ambient_texture),
+            ("map_Kd", &m.diffuse_texture),
+            ("map_Ks", &m.specular_texture),
+            ("map_Bump", &m.normal_texture),
+            ("map_Ns", &m.shininess_texture),
+            ("map_d", &m.dissolve_texture),
+        ];
+        for (key, texture) in textures {
+            if !texture.is_empty() {
+                let _ = writeln!(mtl, "{} {}", key, texture);
+            }
+        }
+        for (key, value) in &m.unknown_param {
+            let _ = writeln!(mtl, "{} {}", key, value);
+        }
+    }
+    mtl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, serialize};
+    use crate::{
+        assets::{self, MemorySource},
+        wgpu_renderer::model::{MeshData, ModelVertex, MorphVertex, SkinVertex},
+    };
+
+    fn vertex(x: f32) -> ModelVertex {
+        ModelVertex {
+            position: [x, 1.0, 2.0],
+            tex_coords: [0.5, 0.25],
+            normal: [0.0, 1.0, 0.0],
+            tangent: [1.0, 0.0, 0.0, -1.0],
+        }
+    }
+
+    fn meshes() -> Vec<MeshData> {
+        vec![
+            MeshData {
+                name: "plain".to_string(),
+                vertices: vec![vertex(0.0), vertex(1.0), vertex(2.0)],
+                indices: vec![0, 1, 2],
+                material: Some(3),
+                node: None,
+                skin_vertices: None,
+                morph_targets: vec![],
+                weight_offset: 0,
+            },
+            MeshData {
+                name: "animated".to_string(),
+                vertices: vec![vertex(-1.0), vertex(-2.0), vertex(-3.0)],
+                indices: vec![2, 1, 0],
+                material: None,
+                node: Some(1),
+                skin_vertices: Some(vec![SkinVertex { joints: [0, 1, 2, 3], weights: [0.25; 4] }; 3]),
+                morph_targets: vec![vec![MorphVertex { position: [1.0; 4], normal: [0.0; 4] }; 3]],
+                weight_offset: 2,
+            },
+        ]
+    }
+
+    fn materials() -> Vec<tobj::Material> {
+        let mut textured = tobj::Material {
+            name: "textured".to_string(),
+            diffuse: [1.0; 3],
+            specular: [0.5; 3],
+            shininess: 0.1,
+            dissolve: 0.75,
+            diffuse_texture: "textures/base color.png".to_string(),
+            normal_texture: "normal.png".to_string(),
+            illumination_model: Some(2),
+            ..Default::default()
+        };
+        textured.unknown_param.insert("map_Ke".to_string(), "glow.png".to_string());
+        let plain = tobj::Material {
+            name: "plain".to_string(),
+            ..Default::default()
+        };
+        vec![textured, plain]
+    }
+
+    #[test]
+    fn meshes_survive_a_round_trip() {
+        let meshes = meshes();
+        let bytes = serialize(&[], &["a.mtl".to_string(), "b.mtl".to_string()], &materials(), &meshes).unwrap();
+        let cached = parse(bytes).unwrap().expect("caches without dependencies are never stale");
+        assert_eq!(cached.material_libraries, ["a.mtl", "b.mtl"]);
+        assert_eq!(cached.materials.len(), 2);
+        for (cached_material, material) in cached.materials.iter().zip(&materials()) {
+            assert_eq!(format!("{:?}", cached_material), format!("{:?}", material));
+        }
+        assert_eq!(cached.meshes.len(), 2);
+        for (cached_mesh, mesh) in cached.meshes.iter().zip(&meshes) {
+            assert_eq!(cached_mesh.name, mesh.name);
+            assert_eq!(cached_mesh.material(), mesh.material);
+            assert_eq!(cached_mesh.node(), mesh.node);
+            assert_eq!(&cached.bytes[cached_mesh.vertices.clone()], bytemuck::cast_slice::<_, u8>(&mesh.vertices));
+            assert_eq!(&cached.bytes[cached_mesh.indices.clone()], bytemuck::cast_slice::<_, u8>(&mesh.indices));
+            let skin_vertices = cached_mesh.skin_vertices.clone().map(|range| cached.bytes[range].to_vec());
+            let expected = mesh.skin_vertices.as_ref().map(|s| bytemuck::cast_slice::<_, u8>(s).to_vec());
+            assert_eq!(skin_vertices, expected);
+            let morph_targets = cached_mesh.morph_targets.clone().map(|range| cached.bytes[range].to_vec());
+            assert_eq!(morph_targets, mesh.morph_target_bytes());
+        }
+        assert_eq!(cached.meshes[0].header.bounds.min, [0.0, 1.0, 2.0]);
+        assert_eq!(cached.meshes[0].header.bounds.max, [2.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn truncated_caches_are_errors() {
+        let bytes = serialize(&[], &[], &[], &meshes()).unwrap();
+        assert!(parse(bytes[..bytes.len() - 1].to_vec()).is_err());
+        let mut trailing = serialize(&[], &[], &[], &meshes()).unwrap();
+        trailing.push(0);
+        assert!(parse(trailing).is_err());
+    }
+
+    #[test]
+    fn caches_go_stale_with_their_dependencies() {
+        let source = MemorySource::new();
+        source.insert("model.obj", b"v 0 0 0".to_vec());
+        assets::mount("mesh_cache_tests", source);
+        let bytes = serialize(&["mesh_cache_tests/model.obj".to_string()], &[], &[], &meshes()).unwrap();
+        assert!(parse(bytes.clone()).unwrap().is_some());
+
+        // A file of another size now shadows the one the cache was made from.
+        let changed = MemorySource::new();
+        changed.insert("model.obj", b"v 0 0 0\nv 1 0 0".to_vec());
+        assets::mount("mesh_cache_tests", changed);
+        assert!(parse(bytes).unwrap().is_none());
+    }
+}
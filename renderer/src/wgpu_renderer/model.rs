@@ -1,67 +1,443 @@
-use super::texture;
+use std::sync::Arc;
+
+use wgpu::util::DeviceExt;
+
+use super::{animation, sampler, streaming::{self, Uploads}, texture};
 
 pub struct WgpuModel {
     // pub name: String,
     pub meshes: Vec<WgpuMesh>,
     pub materials: Vec<WgpuMaterial>,
+    pub nodes: Vec<ModelNode>,
+    pub skins: Vec<animation::Skin>,
+    pub animations: Vec<animation::AnimationClip>,
+    // The files the model was read from, the model file first. Empty for
+    // models made at runtime or read from memory.
+    pub sources: Vec<String>,
+}
+
+// A node of the scene hierarchy the model was loaded from. Vertices of
+// static meshes are already in model space, the transforms are kept for
+// skeletal animation.
+#[allow(dead_code)]
+pub struct ModelNode {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    // Rest pose relative to the parent node, the rotation is (x, y, z, w).
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+    // Rest morph target weights of the node's meshes, and where they start in
+    // the model's morph weights.
+    pub weights: Vec<f32>,
+    pub weight_offset: u32,
+    // Indices into WgpuModel::meshes.
+    pub meshes: Vec<usize>,
+}
+
+// Scalar factors of the metallic-roughness material model. Every factor is
+// multiplied with the corresponding texture sample in the shader.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    pub base_color_factor: [f32; 4],
+    pub emissive_factor: [f32; 3],
+    pub metallic_factor: f32,
+    // Reflectance of dielectrics, 0.5 maps to the usual 4% at normal incidence.
+    pub specular_factor: [f32; 3],
+    pub roughness_factor: f32,
+    pub occlusion_strength: f32,
+    pub normal_scale: f32,
+    // Set for single and two channel maps, whose red channel holds both.
+    pub gray_metallic_roughness: u32,
+    _padding: u32,
+}
+
+impl MaterialUniform {
+    pub fn new() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            emissive_factor: [0.0; 3],
+            metallic_factor: 1.0,
+            specular_factor: [0.5; 3],
+            roughness_factor: 1.0,
+            occlusion_strength: 1.0,
+            normal_scale: 1.0,
+            gray_metallic_roughness: 0,
+            _padding: 0,
+        }
+    }
+}
+
+// Textures are shared between materials, maps a material doesn't provide
+// point at the renderer's default textures.
+pub struct MaterialTextures {
+    pub base_color: Arc<texture::Texture>,
+    pub normal: Arc<texture::Texture>,
+    // Roughness is read from the green channel and metallic from the blue one.
+    pub metallic_roughness: Arc<texture::Texture>,
+    pub occlusion: Arc<texture::Texture>,
+    pub emissive: Arc<texture::Texture>,
+}
+
+// How each of a material's textures is sampled.
+#[derive(Copy, Clone, Default)]
+pub struct MaterialSamplers {
+    pub base_color: sampler::SamplerDesc,
+    pub normal: sampler::SamplerDesc,
+    pub metallic_roughness: sampler::SamplerDesc,
+    pub occlusion: sampler::SamplerDesc,
+    pub emissive: sampler::SamplerDesc,
+}
+
+impl MaterialSamplers {
+    // Samples every texture the same way.
+    pub fn all(desc: sampler::SamplerDesc) -> Self {
+        Self {
+            base_color: desc,
+            normal: desc,
+            metallic_roughness: desc,
+            occlusion: desc,
+            emissive: desc,
+        }
+    }
+}
+
+// What material bind groups are created with besides the material's own
+// resources.
+pub struct MaterialLayout {
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub samplers: sampler::SamplerCache,
 }
 
 #[allow(dead_code)]
 pub struct WgpuMaterial { // TODO: Do we really need to keep al these fields here?
     pub name: String,
-    pub diffuse_texture: texture::Texture,
-    pub normal_texture: texture::Texture,
+    pub textures: MaterialTextures,
+    pub samplers: MaterialSamplers,
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    // Drawn after the opaque meshes, blended by its base color alpha.
+    pub blended: bool,
 }
 
 impl WgpuMaterial {
     pub fn new(
         device: &wgpu::Device,
         name: &str,
-        diffuse_texture: texture::Texture,
-        normal_texture: texture::Texture, // NEW!
-        layout: &wgpu::BindGroupLayout,
+        textures: MaterialTextures,
+        samplers: MaterialSamplers,
+        mut uniform: MaterialUniform,
+        layout: &MaterialLayout,
     ) -> Self { 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        uniform.gray_metallic_roughness = texture::is_gray(textures.metallic_roughness.texture.format()) as u32;
+        let device_guard = streaming::lock_device();
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        drop(device_guard);
+        let bind_group = Self::create_bind_group(device, name, &textures, &samplers, &uniform_buffer, layout);
+
+        Self {
+            name: String::from(name),
+            textures,
+            samplers,
+            uniform,
+            uniform_buffer,
+            bind_group,
+            blended: uniform.base_color_factor[3] < 1.0,
+        }
+    }
+
+    // Recreates the bind group, for when the samplers of the layout changed.
+    pub fn rebind(&mut self, device: &wgpu::Device, layout: &MaterialLayout) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.name,
+            &self.textures,
+            &self.samplers,
+            &self.uniform_buffer,
             layout,
+        );
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        name: &str,
+        textures: &MaterialTextures,
+        samplers: &MaterialSamplers,
+        uniform_buffer: &wgpu::Buffer,
+        layout: &MaterialLayout,
+    ) -> wgpu::BindGroup {
+        let sampler = |desc| layout.samplers.get(device, desc);
+        let base_color_sampler = sampler(&samplers.base_color);
+        let normal_sampler = sampler(&samplers.normal);
+        let metallic_roughness_sampler = sampler(&samplers.metallic_roughness);
+        let occlusion_sampler = sampler(&samplers.occlusion);
+        let emissive_sampler = sampler(&samplers.emissive);
+
+        let _device_guard = streaming::lock_device();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout.bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&textures.base_color.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&base_color_sampler),
                 },
-                // NEW!
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&textures.normal.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&normal_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&textures.metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&metallic_roughness_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&textures.occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&occlusion_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureView(&textures.emissive.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(&emissive_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: uniform_buffer.as_entire_binding(),
                 },
             ],
             label: Some(name),
-        });
+        })
+    }
 
-        Self {
-            name: String::from(name),
-            diffuse_texture,
-            normal_texture, // NEW!
-            bind_group,
-        }
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        // Every map is a filterable 2D texture followed by its sampler, the
+        // scalar factors come last.
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            // This should match the filterable field of the
+            // corresponding Texture entry above.
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0), // base color
+                sampler_entry(1),
+                texture_entry(2), // normal
+                sampler_entry(3),
+                texture_entry(4), // metallic-roughness
+                sampler_entry(5),
+                texture_entry(6), // occlusion
+                sampler_entry(7),
+                texture_entry(8), // emissive
+                sampler_entry(9),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("texture_bind_group_layout"),
+        })
     }
 }
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct WgpuMesh {
     pub name: String,
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    pub vertex_buffer: Arc<wgpu::Buffer>,
+    pub index_buffer: Arc<wgpu::Buffer>,
     pub num_elements: u32,
     pub material: usize,
+    // SkinVertex data of skinned meshes, drawn with the skinning pipeline.
+    pub skin_buffer: Option<Arc<wgpu::Buffer>>,
+    // Storage buffer with a MorphTargetsHeader followed by the MorphVertex
+    // deltas of every target, target after target.
+    pub morph_targets: Option<Arc<wgpu::Buffer>>,
+    pub bounds: Aabb,
+}
+
+// Raw contents of the buffers of a mesh, either serialized from MeshData or
+// read straight out of a mesh cache.
+pub struct MeshBytes<'a> {
+    pub vertices: &'a [u8],
+    pub indices: &'a [u8],
+    pub skin_vertices: Option<&'a [u8]>,
+    pub morph_targets: Option<&'a [u8]>,
+}
+
+// A buffer for contents, which are written once it's the buffer's turn.
+fn create_buffer(
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    label: &str,
+    contents: &[u8],
+    usage: wgpu::BufferUsages,
+) -> Arc<wgpu::Buffer> {
+    let device_guard = streaming::lock_device();
+    let buffer = Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: (contents.len() as u64).next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT),
+        usage: usage | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    }));
+    drop(device_guard);
+    uploads.write_buffer(&buffer, contents.to_vec());
+    buffer
+}
+
+impl WgpuMesh {
+    pub fn new(
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        file_name: &str,
+        name: &str,
+        bytes: MeshBytes,
+        material: usize,
+        bounds: Aabb,
+    ) -> Self {
+        let buffer = |kind: &str, contents: &[u8], usage| {
+            create_buffer(device, uploads, &format!("{:?} {} Buffer", file_name, kind), contents, usage)
+        };
+        let vertex_buffer = buffer("Vertex", bytes.vertices, wgpu::BufferUsages::VERTEX);
+        let index_buffer = buffer("Index", bytes.indices, wgpu::BufferUsages::INDEX);
+        let skin_buffer = bytes
+            .skin_vertices
+            .map(|contents| buffer("Skin", contents, wgpu::BufferUsages::VERTEX));
+        let morph_targets = bytes
+            .morph_targets
+            .map(|contents| buffer("Morph Target", contents, wgpu::BufferUsages::STORAGE));
+
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: (bytes.indices.len() / size_of::<u32>()) as u32,
+            material,
+            skin_buffer,
+            morph_targets,
+            bounds,
+        }
+    }
+}
+
+// Axis aligned bounding box in model space.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Aabb {
+    pub fn from_vertices(vertices: &[ModelVertex]) -> Self {
+        let mut bounds = Self {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        };
+        for v in vertices {
+            for c in 0..3 {
+                bounds.min[c] = bounds.min[c].min(v.position[c]);
+                bounds.max[c] = bounds.max[c].max(v.position[c]);
+            }
+        }
+        bounds
+    }
+}
+
+// A mesh as the loaders produce it, before it's uploaded to the GPU.
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    // Index into WgpuModel::materials, None for the default material.
+    pub material: Option<usize>,
+    // Index into WgpuModel::nodes of the node the mesh belongs to.
+    pub node: Option<usize>,
+    pub skin_vertices: Option<Vec<SkinVertex>>,
+    // The deltas of each morph target, if the mesh has any.
+    pub morph_targets: Vec<Vec<MorphVertex>>,
+    pub weight_offset: u32,
+}
+
+impl MeshData {
+    // The MorphTargetsHeader and deltas as the shader reads them.
+    pub fn morph_target_bytes(&self) -> Option<Vec<u8>> {
+        if self.morph_targets.is_empty() {
+            return None;
+        }
+        let header = MorphTargetsHeader {
+            num_targets: self.morph_targets.len() as u32,
+            num_vertices: self.vertices.len() as u32,
+            weight_offset: self.weight_offset,
+            _padding: 0,
+        };
+        let mut bytes = bytemuck::bytes_of(&header).to_vec();
+        for deltas in &self.morph_targets {
+            bytes.extend_from_slice(bytemuck::cast_slice(deltas));
+        }
+        Some(bytes)
+    }
+
+    pub fn upload(&self, device: &wgpu::Device, uploads: &Uploads, file_name: &str, material: usize) -> WgpuMesh {
+        let morph_targets = self.morph_target_bytes();
+        WgpuMesh::new(
+            device,
+            uploads,
+            file_name,
+            &self.name,
+            MeshBytes {
+                vertices: bytemuck::cast_slice(&self.vertices),
+                indices: bytemuck::cast_slice(&self.indices),
+                skin_vertices: self.skin_vertices.as_deref().map(bytemuck::cast_slice),
+                morph_targets: morph_targets.as_deref(),
+            },
+            material,
+            Aabb::from_vertices(&self.vertices),
+        )
+    }
 }
 
 #[repr(C)]
@@ -71,8 +447,9 @@ pub struct ModelVertex {
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
 
-    pub tangent: [f32; 3],
-    pub bitangent: [f32; 3],
+    // The bitangent sign is stored in w, the shader reconstructs it as
+    // cross(normal, tangent.xyz) * tangent.w.
+    pub tangent: [f32; 4],
 }
 
 impl ModelVertex {
@@ -100,18 +477,64 @@ impl ModelVertex {
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
                     shader_location: 3,
-                    format: wgpu::VertexFormat::Float32x2,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
+            ],
+        }
+    }
+}
+
+// Joint influences of a skinned vertex, stored in a separate vertex buffer so
+// static meshes don't pay for them.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinVertex {
+    // Indices into the model's joint palette.
+    pub joints: [u16; 4],
+    pub weights: [f32; 4],
+}
+
+impl SkinVertex {
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<SkinVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                // Locations 5 to 11 are taken by InstanceRaw.
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                    format: wgpu::VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Uint16x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 4]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
             ],
         }
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MorphTargetsHeader {
+    pub num_targets: u32,
+    pub num_vertices: u32,
+    // Where the mesh's weights start in the model's morph weights.
+    pub weight_offset: u32,
+    pub _padding: u32,
+}
+
+// Displacement of one vertex by one morph target at full weight.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MorphVertex {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+}
+
 // model.rs
 // pub trait DrawModel {
 //     fn draw_model(&mut self, model: &WgpuModel, camera_bind_group: &wgpu::BindGroup, light_bind_group: &wgpu::BindGroup);
@@ -0,0 +1,445 @@
+use std::{collections::HashMap, f32::consts::PI};
+
+use cgmath::{InnerSpace, Vector3};
+
+use super::{geometry, model::ModelVertex};
+
+// Generators for common shapes, to be uploaded with Renderer::create_mesh:
+//
+// let sphere = renderer::primitives::uv_sphere(1.0, 32, 16);
+// renderer.create_mesh(&sphere.vertices, &sphere.indices, &material, false, 16)?;
+//
+// Shapes are centered on the origin with Y up, front faces wind counter
+// clockwise and texture coordinates have v pointing down. Tangents are
+// generated already.
+pub struct PrimitiveMesh {
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Default)]
+struct Builder {
+    vertices: Vec<ModelVertex>,
+    indices: Vec<u32>,
+}
+
+impl Builder {
+    fn vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, tex_coords: [f32; 2]) -> u32 {
+        self.vertices.push(ModelVertex {
+            position: position.into(),
+            tex_coords,
+            normal: normal.into(),
+            tangent: [0.0; 4],
+        });
+        self.vertices.len() as u32 - 1
+    }
+
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        self.indices.extend_from_slice(&[a, b, c]);
+    }
+
+    // A grid of columns x rows quads with a flat normal, centered on center
+    // and spanning u_axis horizontally and v_axis downwards in texture
+    // space. u_axis x v_axis has to point away from the front.
+    fn face(
+        &mut self,
+        center: Vector3<f32>,
+        u_axis: Vector3<f32>,
+        v_axis: Vector3<f32>,
+        columns: u32,
+        rows: u32,
+    ) {
+        let normal = v_axis.cross(u_axis).normalize();
+        let start = self.vertices.len() as u32;
+        for row in 0..=rows {
+            for column in 0..=columns {
+                let u = column as f32 / columns as f32;
+                let v = row as f32 / rows as f32;
+                let position = center + u_axis * (u - 0.5) + v_axis * (v - 0.5);
+                self.vertex(position, normal, [u, v]);
+            }
+        }
+        let index = |column: u32, row: u32| start + row * (columns + 1) + column;
+        for row in 0..rows {
+            for column in 0..columns {
+                let (a, b) = (index(column, row), index(column, row + 1));
+                let (c, d) = (index(column + 1, row + 1), index(column + 1, row));
+                self.triangle(a, b, c);
+                self.triangle(a, c, d);
+            }
+        }
+    }
+
+    // Revolves a profile around the Y axis. The profile runs from top to
+    // bottom, rows with a radius of zero are poles.
+    fn revolve(&mut self, profile: &[ProfileRow], segments: u32) {
+        let start = self.vertices.len() as u32;
+        for row in profile {
+            for segment in 0..=segments {
+                let u = segment as f32 / segments as f32;
+                let (sin, cos) = (u * 2.0 * PI).sin_cos();
+                let position = Vector3::new(row.radius * sin, row.y, row.radius * cos);
+                let normal = Vector3::new(row.normal[0] * sin, row.normal[1], row.normal[0] * cos);
+                self.vertex(position, normal, [u, row.v]);
+            }
+        }
+        let index = |segment: u32, row: usize| start + row as u32 * (segments + 1) + segment;
+        for (row, rows) in profile.windows(2).enumerate() {
+            for segment in 0..segments {
+                let (a, b) = (index(segment, row), index(segment, row + 1));
+                let (c, d) = (index(segment + 1, row + 1), index(segment + 1, row));
+                // Triangles with two corners on a pole are degenerate.
+                if rows[1].radius != 0.0 {
+                    self.triangle(a, b, c);
+                }
+                if rows[0].radius != 0.0 {
+                    self.triangle(a, c, d);
+                }
+            }
+        }
+    }
+
+    // A flat disc facing up or down.
+    fn disc(&mut self, y: f32, radius: f32, segments: u32, up: bool) {
+        let normal = Vector3::new(0.0, if up { 1.0 } else { -1.0 }, 0.0);
+        // Seen from the front, u follows X and v follows Z on the top and -Z
+        // on the bottom.
+        let v_sign = if up { 1.0 } else { -1.0 };
+        let center = self.vertex(Vector3::new(0.0, y, 0.0), normal, [0.5, 0.5]);
+        let start = self.vertices.len() as u32;
+        for segment in 0..=segments {
+            let (sin, cos) = (segment as f32 / segments as f32 * 2.0 * PI).sin_cos();
+            self.vertex(
+                Vector3::new(radius * sin, y, radius * cos),
+                normal,
+                [0.5 + 0.5 * sin, 0.5 + 0.5 * cos * v_sign],
+            );
+        }
+        for segment in start..start + segments {
+            if up {
+                self.triangle(center, segment, segment + 1);
+            } else {
+                self.triangle(center, segment + 1, segment);
+            }
+        }
+    }
+
+    fn finish(mut self) -> PrimitiveMesh {
+        geometry::compute_tangents(&mut self.vertices, &mut self.indices, false);
+        PrimitiveMesh {
+            vertices: self.vertices,
+            indices: self.indices,
+        }
+    }
+}
+
+struct ProfileRow {
+    radius: f32,
+    y: f32,
+    // Radial and vertical component of the normal.
+    normal: [f32; 2],
+    v: f32,
+}
+
+// A point of a circle in the profile plane, angle 0 being the top.
+fn arc_row(radius: f32, center_y: f32, angle: f32, v: f32) -> ProfileRow {
+    let (sin, cos) = angle.sin_cos();
+    ProfileRow {
+        // Poles are exactly on the axis.
+        radius: if sin.abs() < 1e-6 { 0.0 } else { radius * sin },
+        y: center_y + radius * cos,
+        normal: [sin, cos],
+        v,
+    }
+}
+
+// An axis aligned cube with a size x size x size extent, every face showing
+// the whole texture.
+pub fn cube(size: f32) -> PrimitiveMesh {
+    let mut builder = Builder::default();
+    let h = size / 2.0;
+    let faces = [
+        (Vector3::unit_x(), -Vector3::unit_z(), -Vector3::unit_y()),
+        (-Vector3::unit_x(), Vector3::unit_z(), -Vector3::unit_y()),
+        (Vector3::unit_y(), Vector3::unit_x(), Vector3::unit_z()),
+        (-Vector3::unit_y(), Vector3::unit_x(), -Vector3::unit_z()),
+        (Vector3::unit_z(), Vector3::unit_x(), -Vector3::unit_y()),
+        (-Vector3::unit_z(), -Vector3::unit_x(), -Vector3::unit_y()),
+    ];
+    for (normal, u_axis, v_axis) in faces {
+        builder.face(normal * h, u_axis * size, v_axis * size, 1, 1);
+    }
+    builder.finish()
+}
+
+// A plane in XZ facing up.
+pub fn plane(width: f32, depth: f32) -> PrimitiveMesh {
+    grid(width, depth, 1, 1)
+}
+
+// A plane in XZ facing up, made of columns x rows quads. The texture covers
+// the whole grid once.
+pub fn grid(width: f32, depth: f32, columns: u32, rows: u32) -> PrimitiveMesh {
+    let mut builder = Builder::default();
+    builder.face(
+        Vector3::new(0.0, 0.0, 0.0),
+        Vector3::unit_x() * width,
+        Vector3::unit_z() * depth,
+        columns.max(1),
+        rows.max(1),
+    );
+    builder.finish()
+}
+
+// A sphere of segments slices around Y and rings stacks from pole to pole,
+// textured with an equirectangular map.
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> PrimitiveMesh {
+    let rings = rings.max(2);
+    let profile = (0..=rings)
+        .map(|ring| {
+            let t = ring as f32 / rings as f32;
+            arc_row(radius, 0.0, t * PI, t)
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::default();
+    builder.revolve(&profile, segments.max(3));
+    builder.finish()
+}
+
+// A sphere from a subdivided icosahedron, with evenly sized triangles. Each
+// subdivision splits every triangle into four.
+pub fn icosphere(radius: f32, subdivisions: u32) -> PrimitiveMesh {
+    // The icosahedron stands on a pole, with a ring of five vertices above
+    // and below the equator.
+    let (ring_y, ring_radius) = (1.0 / 5.0f32.sqrt(), 2.0 / 5.0f32.sqrt());
+    let ring = |k: u32, y: f32, offset: f32| {
+        let (sin, cos) = ((k as f32 + offset) * 2.0 * PI / 5.0).sin_cos();
+        Vector3::new(ring_radius * sin, y, ring_radius * cos)
+    };
+    let mut positions = vec![Vector3::unit_y(), -Vector3::unit_y()];
+    positions.extend((0..5).map(|k| ring(k, ring_y, 0.0)));
+    positions.extend((0..5).map(|k| ring(k, -ring_y, 0.5)));
+    let (upper, lower) = (|k: usize| 2 + k % 5, |k: usize| 7 + k % 5);
+    let mut triangles = (0..5)
+        .flat_map(|k| {
+            [
+                [0, upper(k), upper(k + 1)],
+                [upper(k), lower(k), upper(k + 1)],
+                [upper(k + 1), lower(k), lower(k + 1)],
+                [1, lower(k + 1), lower(k)],
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    for _ in 0..subdivisions {
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: usize, b: usize| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                positions.push(((positions[a] + positions[b]) / 2.0).normalize());
+                positions.len() - 1
+            })
+        };
+        triangles = triangles
+            .into_iter()
+            .flat_map(|[a, b, c]| {
+                let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    // Texture coordinates are spherical, triangles crossing the seam at
+    // u = 0 get copies of their vertices on the other side, and the poles a
+    // copy per triangle.
+    let mut builder = Builder::default();
+    let mut vertices = HashMap::new();
+    for triangle in triangles {
+        let mut uvs = triangle.map(|i| {
+            let p = positions[i];
+            [0.5 + p.x.atan2(p.z) / (2.0 * PI), p.y.clamp(-1.0, 1.0).acos() / PI]
+        });
+        let max_u = uvs.iter().map(|uv| uv[0]).fold(0.0, f32::max);
+        for uv in &mut uvs {
+            if max_u - uv[0] > 0.5 {
+                uv[0] += 1.0;
+            }
+        }
+        for corner in 0..3 {
+            if positions[triangle[corner]].y.abs() > 1.0 - 1e-6 {
+                uvs[corner][0] = (uvs[(corner + 1) % 3][0] + uvs[(corner + 2) % 3][0]) / 2.0;
+            }
+        }
+        let corners = [0, 1, 2].map(|corner| {
+            let p = positions[triangle[corner]];
+            let uv = uvs[corner];
+            *vertices
+                .entry((triangle[corner], uv[0].to_bits()))
+                .or_insert_with(|| builder.vertex(p * radius, p, uv))
+        });
+        builder.triangle(corners[0], corners[1], corners[2]);
+    }
+    builder.finish()
+}
+
+// A capped cylinder along Y.
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> PrimitiveMesh {
+    let segments = segments.max(3);
+    let h = height / 2.0;
+    let side = [
+        ProfileRow { radius, y: h, normal: [1.0, 0.0], v: 0.0 },
+        ProfileRow { radius, y: -h, normal: [1.0, 0.0], v: 1.0 },
+    ];
+    let mut builder = Builder::default();
+    builder.revolve(&side, segments);
+    builder.disc(h, radius, segments, true);
+    builder.disc(-h, radius, segments, false);
+    builder.finish()
+}
+
+// A cone along Y with its tip at the top and a capped base.
+pub fn cone(radius: f32, height: f32, segments: u32) -> PrimitiveMesh {
+    let segments = segments.max(3);
+    let h = height / 2.0;
+    let slant = (radius * radius + height * height).sqrt();
+    let normal = [height / slant, radius / slant];
+    let side = [
+        ProfileRow { radius: 0.0, y: h, normal, v: 0.0 },
+        ProfileRow { radius, y: -h, normal, v: 1.0 },
+    ];
+    let mut builder = Builder::default();
+    builder.revolve(&side, segments);
+    builder.disc(-h, radius, segments, false);
+    builder.finish()
+}
+
+// A cylinder along Y with hemispheres on both ends, height being the total
+// height including them. rings is the number of stacks per hemisphere.
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> PrimitiveMesh {
+    let rings = rings.max(1);
+    let h = (height / 2.0 - radius).max(0.0);
+    // v follows the length of the profile, so the texture isn't stretched.
+    let length = PI * radius + 2.0 * h;
+    let arc = PI / 2.0 * radius;
+    let mut profile = Vec::new();
+    for ring in 0..=rings {
+        let t = ring as f32 / rings as f32;
+        profile.push(arc_row(radius, h, t * PI / 2.0, t * arc / length));
+    }
+    for ring in 0..=rings {
+        let t = ring as f32 / rings as f32;
+        profile.push(arc_row(radius, -h, (1.0 + t) * PI / 2.0, (arc + 2.0 * h + t * arc) / length));
+    }
+    let mut builder = Builder::default();
+    builder.revolve(&profile, segments.max(3));
+    builder.finish()
+}
+
+// A ring around Y, major_radius being the distance from the center to the
+// middle of the tube.
+pub fn torus(major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32) -> PrimitiveMesh {
+    let minor_segments = minor_segments.max(3);
+    // The tube's cross-section runs from its top over the outside and the
+    // bottom to the inside.
+    let profile = (0..=minor_segments)
+        .map(|segment| {
+            let t = segment as f32 / minor_segments as f32;
+            let (sin, cos) = (t * 2.0 * PI).sin_cos();
+            ProfileRow {
+                radius: major_radius + minor_radius * sin,
+                y: minor_radius * cos,
+                normal: [sin, cos],
+                v: t,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::default();
+    builder.revolve(&profile, major_segments.max(3));
+    builder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Vector3};
+
+    use super::*;
+
+    fn shapes() -> Vec<(&'static str, PrimitiveMesh)> {
+        vec![
+            ("cube", cube(2.0)),
+            ("plane", plane(2.0, 1.0)),
+            ("grid", grid(2.0, 1.0, 3, 2)),
+            ("uv_sphere", uv_sphere(1.0, 12, 6)),
+            ("icosphere", icosphere(1.0, 0)),
+            ("icosphere subdivided", icosphere(1.0, 2)),
+            ("cylinder", cylinder(0.5, 2.0, 8)),
+            ("cone", cone(0.5, 2.0, 8)),
+            ("capsule", capsule(0.5, 2.0, 8, 3)),
+            ("torus", torus(1.0, 0.25, 12, 8)),
+        ]
+    }
+
+    #[test]
+    fn triangles_face_the_way_their_normals_point() {
+        for (name, mesh) in shapes() {
+            assert_eq!(mesh.indices.len() % 3, 0, "{}", name);
+            for triangle in mesh.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| &mesh.vertices[triangle[i] as usize]);
+                let [pa, pb, pc] = [a, b, c].map(|v| Vector3::from(v.position));
+                let face_normal = (pb - pa).cross(pc - pa);
+                assert!(face_normal.magnitude() > 1e-6, "{} has a degenerate triangle", name);
+                let vertex_normal = [a, b, c].iter().map(|v| Vector3::from(v.normal)).sum::<Vector3<f32>>();
+                assert!(
+                    face_normal.dot(vertex_normal) > 0.0,
+                    "{} has a triangle {:?} facing against its normals",
+                    name,
+                    triangle
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn normals_and_tangents_are_unit_length() {
+        for (name, mesh) in shapes() {
+            for vertex in &mesh.vertices {
+                let normal = Vector3::from(vertex.normal);
+                let tangent = Vector3::new(vertex.tangent[0], vertex.tangent[1], vertex.tangent[2]);
+                assert!((normal.magnitude() - 1.0).abs() < 1e-4, "{} normal {:?}", name, normal);
+                assert!((tangent.magnitude() - 1.0).abs() < 1e-4, "{} tangent {:?}", name, tangent);
+                assert!(tangent.dot(normal).abs() < 1e-3, "{} tangent {:?} is not perpendicular", name, tangent);
+                assert_eq!(vertex.tangent[3].abs(), 1.0, "{} bitangent sign", name);
+            }
+        }
+    }
+
+    #[test]
+    fn texture_coordinates_stay_in_range() {
+        for (name, mesh) in shapes() {
+            // Icosphere triangles crossing the seam continue past u = 1.
+            let max_u = if name.starts_with("icosphere") { 1.5 } else { 1.0 };
+            for vertex in &mesh.vertices {
+                let [u, v] = vertex.tex_coords;
+                assert!((0.0..=max_u).contains(&u) && (0.0..=1.0).contains(&v), "{} uv {:?}", name, vertex.tex_coords);
+            }
+        }
+    }
+
+    #[test]
+    fn icosphere_triangles_dont_stretch_across_the_seam() {
+        let mesh = icosphere(1.0, 2);
+        for triangle in mesh.indices.chunks_exact(3) {
+            let u = triangle.iter().map(|i| mesh.vertices[*i as usize].tex_coords[0]);
+            let (min, max) = u.fold((f32::MAX, f32::MIN), |(min, max), u| (min.min(u), max.max(u)));
+            assert!(max - min < 0.5, "triangle {:?} spans u {}..{}", triangle, min, max);
+        }
+    }
+
+    #[test]
+    fn icosphere_poles_get_a_vertex_per_triangle() {
+        let mesh = icosphere(1.0, 0);
+        assert_eq!(mesh.indices.len(), 20 * 3);
+        let top = mesh.vertices.iter().filter(|v| v.position[1] == 1.0).count();
+        let bottom = mesh.vertices.iter().filter(|v| v.position[1] == -1.0).count();
+        assert_eq!((top, bottom), (5, 5));
+    }
+}
@@ -1,64 +1,319 @@
 use std::{
     io::{BufReader, Cursor},
+    sync::{Arc, Mutex},
     time::Instant,
 };
-use wgpu::util::DeviceExt;
 
-use super::{model, texture};
+use crate::FileResolver;
 
+use super::{baked_texture, bake::Baker, geometry, gltf_loader, manifest, mesh_cache, model, streaming::Uploads, texture, texture_cache::TextureCache};
+
+// Files are read from the mounted asset sources, see crate::assets.
 pub fn load_string(file_name: &str) -> anyhow::Result<String> {
-    let path = std::path::Path::new("res")
-        .join(file_name);
-    Ok(std::fs::read_to_string(path)?)
+    crate::assets::read_string(file_name)
 }
 
 pub fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    let path = std::path::Path::new("res")
-        .join(file_name);
-    let data = std::fs::read(path)?;
+    crate::assets::read(file_name)
+}
 
-    Ok(data)
+// Creates a texture from an image file or a baked texture.
+pub fn create_texture(
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    bytes: &[u8],
+    label: &str,
+    kind: texture::TextureKind
+) -> anyhow::Result<texture::Texture> {
+    if baked_texture::is_baked(bytes) {
+        baked_texture::load(device, uploads, bytes, label)
+    } else {
+        texture::Texture::from_bytes(device, uploads, bytes, label, kind)
+    }
 }
 
+// Textures already loaded by another material are shared.
 pub fn load_texture(
     file_name: &str,
     device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    is_normal_map: bool
-) -> anyhow::Result<texture::Texture> {
+    uploads: &Uploads,
+    kind: texture::TextureKind,
+    textures: &TextureCache,
+) -> anyhow::Result<Arc<texture::Texture>> {
     let begin = Instant::now();
-    let data = load_binary(file_name)?;
-    println!(
-        "Loading binary data for texture {} took {}ms",
-        file_name,
-        begin.elapsed().as_millis()
-    );
-    let res = texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map);
-    println!(
-        "Loading texture {} took {}ms",
-        file_name,
-        begin.elapsed().as_millis()
-    );
-    res
+    let read = || match manifest::load_baked(&manifest::texture_key(file_name, kind.is_linear())) {
+        Some(baked) => Ok(baked),
+        None => {
+            let data = load_binary(file_name)?;
+            println!(
+                "Loading binary data for texture {} took {}ms",
+                file_name,
+                begin.elapsed().as_millis()
+            );
+            Ok(data)
+        }
+    };
+    textures.load(Some(file_name), kind, read, |bytes| {
+        let res = create_texture(device, uploads, bytes, file_name, kind);
+        println!(
+            "Loading texture {} took {}ms",
+            file_name,
+            begin.elapsed().as_millis()
+        );
+        res
+    })
+}
+
+// Where the MTL files and textures an OBJ references are read from, by the
+// names the OBJ and MTL files use.
+pub enum ObjFiles<'a> {
+    // The mounted assets, baked textures are used where there are any.
+    Assets,
+    // Caller provided resolvers, for OBJs loaded from memory.
+    Resolvers {
+        mtl: &'a FileResolver<'a>,
+        texture: &'a FileResolver<'a>,
+    },
+}
+
+impl ObjFiles<'_> {
+    fn load_mtl(&self, file_name: &str) -> tobj::MTLLoadResult {
+        let bytes = match self {
+            ObjFiles::Assets => load_binary(file_name),
+            ObjFiles::Resolvers { mtl, .. } => mtl(file_name),
+        };
+        let Ok(bytes) = bytes else {
+            return Err(tobj::LoadError::OpenFileFailed);
+        };
+        let (mut materials, names) = tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(&bytes)))?;
+        default_missing_colors(&bytes, &mut materials);
+        Ok((materials, names))
+    }
+
+    // Resolved names are only meaningful to their resolver, the textures
+    // they return are shared by content.
+    fn load_texture(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        kind: texture::TextureKind,
+        textures: &TextureCache,
+    ) -> anyhow::Result<Arc<texture::Texture>> {
+        match self {
+            ObjFiles::Assets => load_texture(file_name, device, uploads, kind, textures),
+            ObjFiles::Resolvers { texture, .. } => textures.load(
+                None,
+                kind,
+                || texture(file_name),
+                |bytes| texture::Texture::from_bytes(device, uploads, bytes, file_name, kind),
+            ),
+        }
+    }
+
+    fn load_roughness_from_specular(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        textures: &TextureCache,
+    ) -> anyhow::Result<Arc<texture::Texture>> {
+        match self {
+            ObjFiles::Assets => load_roughness_from_specular(file_name, device, uploads, textures),
+            ObjFiles::Resolvers { texture, .. } => {
+                let (pixels, width, height) = roughness_from_specular(&texture(file_name)?, file_name)?;
+                let texture = texture::Texture::from_image(device, uploads, &pixels, width, height, Some(file_name), texture::TextureKind::Data)?;
+                Ok(Arc::new(texture))
+            }
+        }
+    }
+
+    // Loads the texture if the material references one, otherwise shares
+    // the given default texture.
+    fn load_optional_texture(
+        &self,
+        file_name: &str,
+        fallback: &Arc<texture::Texture>,
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        kind: texture::TextureKind,
+        textures: &TextureCache,
+    ) -> anyhow::Result<Arc<texture::Texture>> {
+        if file_name.is_empty() {
+            Ok(fallback.clone())
+        } else {
+            self.load_texture(file_name, device, uploads, kind, textures)
+        }
+    }
+}
+
+// Used for meshes without a material, either because the OBJ has no MTL
+// or because it references a material the MTL doesn't define.
+pub fn default_material(
+    textures: &TextureCache,
+    device: &wgpu::Device,
+    layout: &model::MaterialLayout,
+) -> model::WgpuMaterial {
+    let mut uniform = model::MaterialUniform::new();
+    uniform.base_color_factor = [0.8, 0.8, 0.8, 1.0];
+    uniform.metallic_factor = 0.0;
+    uniform.roughness_factor = 0.5;
+
+    let defaults = &textures.defaults;
+    model::WgpuMaterial::new(
+        device,
+        "default material",
+        model::MaterialTextures {
+            base_color: defaults.white.clone(),
+            normal: defaults.normal.clone(),
+            metallic_roughness: defaults.white.clone(),
+            occlusion: defaults.white.clone(),
+            emissive: defaults.white.clone(),
+        },
+        model::MaterialSamplers::default(),
+        uniform,
+        layout,
+    )
+}
+
+// MTL files have no roughness maps, so a specular (gloss) map is converted
+// into a metallic-roughness texture with roughness = 1 - specular and no metal.
+fn roughness_from_specular(data: &[u8], label: &str) -> anyhow::Result<(Vec<u8>, u32, u32)> {
+    let (mut pixels, width, height) = texture::Texture::decode_rgba8(data, label)?;
+    for pixel in pixels.chunks_exact_mut(4) {
+        let gloss = pixel[0].max(pixel[1]).max(pixel[2]);
+        pixel[0] = 0;
+        pixel[1] = 255 - gloss;
+        pixel[2] = 0;
+        pixel[3] = 255;
+    }
+    Ok((pixels, width, height))
+}
+
+// The name the converted specular map is baked under.
+fn roughness_texture_name(file_name: &str) -> String {
+    format!("{}#roughness", file_name)
 }
 
+// The converted map isn't the content of any file, it's shared by the name
+// it's baked under.
+fn load_roughness_from_specular(
+    file_name: &str,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    textures: &TextureCache,
+) -> anyhow::Result<Arc<texture::Texture>> {
+    let name = roughness_texture_name(file_name);
+    textures.load_path(&name, texture::TextureKind::Data, || {
+        if let Some(baked) = manifest::load_baked(&manifest::texture_key(&name, true)) {
+            return baked_texture::load(device, uploads, &baked, &name);
+        }
+        let (pixels, width, height) = roughness_from_specular(&load_binary(file_name)?, file_name)?;
+        texture::Texture::from_image(device, uploads, &pixels, width, height, Some(file_name), texture::TextureKind::Data)
+    })
+}
+
+// tobj reads a missing Kd or Ks as black. Diffuse colors default to white
+// instead, so map_Kd isn't darkened, and specular colors to the common
+// dielectric 0.5.
+fn default_missing_colors(mtl: &[u8], materials: &mut [tobj::Material]) {
+    // tobj adds a material for every newmtl, in order.
+    let mut material = None;
+    let mut defined = Vec::new();
+    for line in String::from_utf8_lossy(mtl).lines() {
+        match line.split_whitespace().next() {
+            Some("newmtl") => {
+                material = Some(defined.len());
+                defined.push((false, false));
+            }
+            Some("Kd") => {
+                if let Some(i) = material {
+                    defined[i].0 = true;
+                }
+            }
+            Some("Ks") => {
+                if let Some(i) = material {
+                    defined[i].1 = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    for (m, (has_diffuse, has_specular)) in materials.iter_mut().zip(defined) {
+        if !has_diffuse {
+            m.diffuse = [1.0; 3];
+        }
+        if !has_specular {
+            m.specular = [0.5; 3];
+        }
+    }
+}
+
+// Converts a Blinn-Phong specular exponent (Ns) into a GGX roughness using
+// alpha^2 = 2 / (Ns + 2), with alpha = roughness^2.
+fn shininess_to_roughness(shininess: f32) -> f32 {
+    (2.0 / (shininess.max(0.0) + 2.0)).powf(0.25)
+}
+
+// Parses an "r g b" triple as found in MTL statements tobj doesn't know about.
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+    let mut components = value.split_whitespace().map(|c| c.parse::<f32>());
+    let color = [
+        components.next()?.ok()?,
+        components.next()?.ok()?,
+        components.next()?.ok()?,
+    ];
+    Some(color)
+}
+
+pub fn is_gltf(file_name: &str) -> bool {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    matches!(extension.as_deref(), Some("gltf" | "glb"))
+}
+
+// Baked meshes are used until their sources change, the manifest keeps
+// track of them.
+fn load_baked_meshes(file_name: &str) -> Option<mesh_cache::CachedModel> {
+    let bytes = manifest::load_baked(file_name)?;
+    match mesh_cache::parse(bytes) {
+        Ok(cached) => cached,
+        Err(e) => {
+            log::warn!("Could not read the baked meshes of {}: {}", file_name, e);
+            None
+        }
+    }
+}
+
+// Picks the loader from the file extension.
 pub async fn load_wgpu_model(
     file_name: &str,
     device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    layout: &wgpu::BindGroupLayout,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    textures: &TextureCache,
 ) -> anyhow::Result<model::WgpuModel> {
-    let begin = Instant::now();
+    let cached = load_baked_meshes(file_name).or_else(|| mesh_cache::load(file_name));
+    if is_gltf(file_name) {
+        gltf_loader::load_gltf_model(file_name, device, uploads, layout, textures, cached.as_ref()).await
+    } else {
+        load_obj_model(file_name, device, uploads, layout, textures, cached.as_ref()).await
+    }
+}
 
-    let obj_text = load_string(file_name)?;
-    let obj_cursor = Cursor::new(obj_text);
-    let mut obj_reader = BufReader::new(obj_cursor); // TODO: Unnecessary
-    println!(
-        "File {} took {}ms to load.",
-        file_name,
-        begin.elapsed().as_millis()
-    );
+// Parses the OBJ and builds its meshes, together with the materials and the
+// names of the MTL files they came from. file_name only labels the meshes.
+async fn parse_obj_meshes(
+    file_name: &str,
+    obj: &[u8],
+    files: &ObjFiles<'_>,
+    begin: Instant,
+) -> anyhow::Result<(Vec<model::MeshData>, Result<Vec<tobj::Material>, tobj::LoadError>, Vec<String>)> {
+    let mut obj_reader = BufReader::new(Cursor::new(obj)); // TODO: Unnecessary
 
+    let material_libraries = Mutex::new(Vec::new());
     let (models, obj_materials) = tobj::load_obj_buf_async(
         &mut obj_reader,
         &tobj::LoadOptions {
@@ -66,9 +321,9 @@ pub async fn load_wgpu_model(
             single_index: true,
             ..Default::default()
         },
-        |p| async move {
-            let mat_text = load_string(&p).unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+        |p| {
+            material_libraries.lock().unwrap().push(p.clone());
+            async move { files.load_mtl(&p) }
         },
     ).await?;
     println!(
@@ -77,58 +332,44 @@ pub async fn load_wgpu_model(
         begin.elapsed().as_millis()
     );
 
-    let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, device, queue, false)?;
-        let normal_texture = load_texture(&m.normal_texture, device, queue, true)?;
-        materials.push(model::WgpuMaterial::new(
-            device,
-            &m.name,
-            diffuse_texture,
-            normal_texture,
-            layout,
-        ));
-    }
-
     let meshes = models
         .into_iter()
         .map(|m| {
+            let has_normals = !m.mesh.normals.is_empty();
+            let has_tex_coords = !m.mesh.texcoords.is_empty();
             let mut vertices = (0..m.mesh.positions.len() / 3)
                 .map(|i| {
-                    if m.mesh.normals.is_empty() {
-                        model::ModelVertex {
-                            position: [
-                                m.mesh.positions[i * 3],
-                                m.mesh.positions[i * 3 + 1],
-                                m.mesh.positions[i * 3 + 2],
-                            ],
-                            tex_coords: [
-                                m.mesh.texcoords[i * 2],
-                                1.0 - m.mesh.texcoords[i * 2 + 1],
-                            ],
-                            normal: [0.0, 0.0, 0.0],
-                            tangent: [0.0; 3],
-                            bitangent: [0.0; 3],
-                        }
+                    // Meshes without UVs get zero texture coordinates, their
+                    // materials can still use flat colors.
+                    let tex_coords = if !has_tex_coords {
+                        [0.0, 0.0]
+                    } else if has_normals {
+                        [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]]
+                    } else {
+                        [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]]
+                    };
+                    let normal = if has_normals {
+                        [
+                            m.mesh.normals[i * 3],
+                            m.mesh.normals[i * 3 + 1],
+                            m.mesh.normals[i * 3 + 2],
+                        ]
                     } else {
-                        model::ModelVertex {
-                            position: [
-                                m.mesh.positions[i * 3],
-                                m.mesh.positions[i * 3 + 1],
-                                m.mesh.positions[i * 3 + 2],
-                            ],
-                            tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                            normal: [
-                                m.mesh.normals[i * 3],
-                                m.mesh.normals[i * 3 + 1],
-                                m.mesh.normals[i * 3 + 2],
-                            ],
-                            tangent: [0.0; 3],
-                            bitangent: [0.0; 3],
-                        }
+                        [0.0; 3]
+                    };
+                    model::ModelVertex {
+                        position: [
+                            m.mesh.positions[i * 3],
+                            m.mesh.positions[i * 3 + 1],
+                            m.mesh.positions[i * 3 + 2],
+                        ],
+                        tex_coords,
+                        normal,
+                        tangent: [0.0; 4],
                     }
                 })
                 .collect::<Vec<_>>();
+            let mut indices = m.mesh.indices;
 
             println!(
                 "Collecting vertex data of {} took {}ms",
@@ -136,108 +377,431 @@ pub async fn load_wgpu_model(
                 begin.elapsed().as_millis()
             );
 
-            let indices = &m.mesh.indices;
-            let mut triangles_included = vec![0; vertices.len()];
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                // We flip the bitangent to enable right-handed normal
-                // maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
-            }
-
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
+            if !has_normals {
+                geometry::generate_normals(&mut vertices, &mut indices, geometry::SMOOTHING_CREASE_ANGLE);
+                println!(
+                    "Generating normals of {} took {}ms",
+                    file_name,
+                    begin.elapsed().as_millis()
+                );
             }
 
+            // We flip the bitangent to enable right-handed normal
+            // maps with wgpu texture coordinate system
+            geometry::compute_tangents(&mut vertices, &mut indices, true);
 
             println!(
-                "Calculating tangents/bitangents of {} took {}ms",
+                "Calculating tangents of {} took {}ms",
                 file_name,
                 begin.elapsed().as_millis()
             );
 
-            
-
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
-
-            model::WgpuMesh {
+            model::MeshData {
                 name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+                vertices,
+                indices,
+                material: m.mesh.material_id,
+                node: None,
+                skin_vertices: None,
+                morph_targets: vec![],
+                weight_offset: 0,
             }
         })
         .collect::<Vec<_>>();
 
+    Ok((meshes, obj_materials, material_libraries.into_inner().unwrap()))
+}
+
+// Bakes the meshes of the OBJ and the textures of its materials, with the
+// same color spaces load_obj_model loads them in.
+pub async fn bake_obj_model(baker: &mut Baker, file_name: &str) -> anyhow::Result<()> {
+    let obj = load_binary(file_name)?;
+    let (mesh_data, obj_materials, material_libraries) =
+        parse_obj_meshes(file_name, &obj, &ObjFiles::Assets, Instant::now()).await?;
+    let sources: Vec<String> = std::iter::once(file_name.to_string())
+        .chain(material_libraries.iter().cloned())
+        .collect();
+    let obj_materials = obj_materials.unwrap_or_else(|e| {
+        log::warn!("Could not load materials of {}: {}", file_name, e);
+        Vec::new()
+    });
+    baker.add_model(file_name, &sources, &material_libraries, &obj_materials, &mesh_data)?;
+    for m in obj_materials {
+        baker.add_texture_file(&m.diffuse_texture, texture::TextureKind::Color)?;
+        baker.add_texture_file(&m.normal_texture, texture::TextureKind::Normal)?;
+        if !m.specular_texture.is_empty() {
+            let sources = [m.specular_texture.clone()];
+            baker.add_texture(&roughness_texture_name(&m.specular_texture), texture::TextureKind::Data, &sources, || {
+                roughness_from_specular(&load_binary(&m.specular_texture)?, &m.specular_texture)
+            })?;
+        }
+        baker.add_texture_file(&m.ambient_texture, texture::TextureKind::Data)?;
+        if let Some(emissive_texture) = m.unknown_param.get("map_Ke") {
+            baker.add_texture_file(emissive_texture, texture::TextureKind::Color)?;
+        }
+    }
+    Ok(())
+}
+
+// Checks that indices make up a triangle list over num_vertices vertices.
+pub fn check_triangles(num_vertices: usize, indices: &[u32]) -> anyhow::Result<()> {
+    if !indices.len().is_multiple_of(3) {
+        anyhow::bail!("Meshes need a list of triangles, got {} indices", indices.len());
+    }
+    if let Some(index) = indices.iter().find(|index| **index as usize >= num_vertices) {
+        anyhow::bail!("Index {} is out of range for {} vertices", index, num_vertices);
+    }
+    Ok(())
+}
+
+// Builds a model of a single mesh from vertices and indices made at runtime.
+pub fn create_mesh_model(
+    vertices: &[model::ModelVertex],
+    indices: &[u32],
+    material: model::WgpuMaterial,
+    generate_tangents: bool,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+) -> anyhow::Result<model::WgpuModel> {
+    if indices.is_empty() {
+        anyhow::bail!("Meshes need at least one triangle");
+    }
+    check_triangles(vertices.len(), indices)?;
+
+    let mut vertices = vertices.to_vec();
+    let mut indices = indices.to_vec();
+    if generate_tangents {
+        geometry::compute_tangents(&mut vertices, &mut indices, false);
+    }
+    let mesh = model::MeshData {
+        name: "runtime mesh".to_string(),
+        vertices,
+        indices,
+        material: Some(0),
+        node: None,
+        skin_vertices: None,
+        morph_targets: vec![],
+        weight_offset: 0,
+    };
+    Ok(model::WgpuModel {
+        meshes: vec![mesh.upload(device, uploads, "runtime mesh", 0)],
+        materials: vec![material],
+        nodes: vec![],
+        skins: vec![],
+        animations: vec![],
+        sources: vec![],
+    })
+}
+
+// The meshes of an OBJ, either freshly parsed or from its mesh cache.
+enum ObjMeshes<'a> {
+    Parsed(Vec<model::MeshData>),
+    Cached(&'a mesh_cache::CachedModel),
+}
+
+pub async fn load_obj_model(
+    file_name: &str,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    textures: &TextureCache,
+    cached: Option<&mesh_cache::CachedModel>,
+) -> anyhow::Result<model::WgpuModel> {
+    let begin = Instant::now();
+    let files = ObjFiles::Assets;
+
+    // Up to date caches spare parsing the OBJ and its MTL files.
+    let (meshes, obj_materials, material_libraries) = match cached {
+        Some(cached) => (ObjMeshes::Cached(cached), Ok(cached.materials.clone()), cached.material_libraries.clone()),
+        None => {
+            let obj = load_binary(file_name)?;
+            println!(
+                "File {} took {}ms to load.",
+                file_name,
+                begin.elapsed().as_millis()
+            );
+            let (mesh_data, obj_materials, material_libraries) = parse_obj_meshes(file_name, &obj, &files, begin).await?;
+            // The material indices of the meshes come from the MTL files.
+            let dependencies: Vec<String> = std::iter::once(file_name.to_string())
+                .chain(material_libraries.iter().cloned())
+                .collect();
+            let materials = obj_materials.as_deref().unwrap_or_default();
+            if let Err(e) = mesh_cache::store(file_name, &dependencies, &material_libraries, materials, &mesh_data) {
+                log::warn!("Could not write mesh cache of {}: {}", file_name, e);
+            }
+            (ObjMeshes::Parsed(mesh_data), obj_materials, material_libraries)
+        }
+    };
+
+    let sources = obj_sources(file_name, &material_libraries, obj_materials.as_deref().unwrap_or_default());
+    let materials = load_obj_materials(file_name, obj_materials, &files, device, uploads, layout, textures)?;
+    let mut model = create_obj_model(file_name, meshes, materials, device, uploads, layout, textures);
+    model.sources = sources;
     println!(
         "Model {} took {}ms to load.",
         file_name,
         begin.elapsed().as_millis()
     );
-    Ok(model::WgpuModel { meshes, materials })
+    Ok(model)
+}
+
+// The files an OBJ was read from: the OBJ, its MTL files and the textures
+// they reference.
+fn obj_sources(file_name: &str, material_libraries: &[String], materials: &[tobj::Material]) -> Vec<String> {
+    let mut sources = vec![file_name.to_string()];
+    sources.extend(material_libraries.iter().cloned());
+    for m in materials {
+        let emissive_texture = m.unknown_param.get("map_Ke").map(String::as_str).unwrap_or("");
+        let textures = [
+            m.diffuse_texture.as_str(),
+            &m.normal_texture,
+            &m.specular_texture,
+            &m.ambient_texture,
+            emissive_texture,
+        ];
+        for texture in textures {
+            if !texture.is_empty() && !sources.iter().any(|source| source == texture) {
+                sources.push(texture.to_string());
+            }
+        }
+    }
+    sources
+}
+
+// Loads an OBJ held in memory, the MTL files and textures it references are
+// read through the resolvers. Nothing is cached or baked for these.
+pub async fn load_obj_model_from_bytes(
+    obj: &[u8],
+    mtl_resolver: &FileResolver<'_>,
+    texture_resolver: &FileResolver<'_>,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    textures: &TextureCache,
+) -> anyhow::Result<model::WgpuModel> {
+    let name = "in-memory OBJ";
+    let files = ObjFiles::Resolvers {
+        mtl: mtl_resolver,
+        texture: texture_resolver,
+    };
+    let (mesh_data, obj_materials, _) = parse_obj_meshes(name, obj, &files, Instant::now()).await?;
+    let materials = load_obj_materials(name, obj_materials, &files, device, uploads, layout, textures)?;
+    Ok(create_obj_model(name, ObjMeshes::Parsed(mesh_data), materials, device, uploads, layout, textures))
+}
+
+fn load_obj_materials(
+    file_name: &str,
+    obj_materials: Result<Vec<tobj::Material>, tobj::LoadError>,
+    files: &ObjFiles,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    textures: &TextureCache,
+) -> anyhow::Result<Vec<model::WgpuMaterial>> {
+    // A missing MTL isn't fatal, the meshes are drawn with the default material.
+    let obj_materials = obj_materials.unwrap_or_else(|e| {
+        log::warn!("Could not load materials of {}: {}", file_name, e);
+        Vec::new()
+    });
+
+    let defaults = &textures.defaults;
+    let mut materials = Vec::new();
+    for m in obj_materials {
+        // Materials without maps fall back to flat colors through the factors.
+        let base_color = files.load_optional_texture(&m.diffuse_texture, &defaults.white, device, uploads, texture::TextureKind::Color, textures)?;
+        let normal = files.load_optional_texture(&m.normal_texture, &defaults.normal, device, uploads, texture::TextureKind::Normal, textures)?;
+        let metallic_roughness = if m.specular_texture.is_empty() {
+            defaults.white.clone()
+        } else {
+            files.load_roughness_from_specular(&m.specular_texture, device, uploads, textures)?
+        };
+        // Exporters commonly write baked ambient occlusion into map_Ka.
+        let occlusion = files.load_optional_texture(&m.ambient_texture, &defaults.white, device, uploads, texture::TextureKind::Data, textures)?;
+        let emissive_texture = m.unknown_param.get("map_Ke").map(String::as_str).unwrap_or("");
+        let emissive = files.load_optional_texture(emissive_texture, &defaults.white, device, uploads, texture::TextureKind::Color, textures)?;
+
+        let mut uniform = model::MaterialUniform::new();
+        uniform.base_color_factor = [m.diffuse[0], m.diffuse[1], m.diffuse[2], m.dissolve];
+        uniform.specular_factor = m.specular;
+        uniform.metallic_factor = 0.0;
+        // A missing Ns reads as 0, which keeps the default roughness like an
+        // explicit Ns 0 would.
+        if m.specular_texture.is_empty() && m.shininess > 0.0 {
+            uniform.roughness_factor = shininess_to_roughness(m.shininess);
+        }
+        uniform.emissive_factor = match m.unknown_param.get("Ke").and_then(|ke| parse_color(ke)) {
+            Some(ke) => ke,
+            // A map without a factor is emitted as is.
+            None if !emissive_texture.is_empty() => [1.0; 3],
+            None => [0.0; 3],
+        };
+
+        materials.push(model::WgpuMaterial::new(
+            device,
+            &m.name,
+            model::MaterialTextures {
+                base_color,
+                normal,
+                metallic_roughness,
+                occlusion,
+                emissive,
+            },
+            // MTL files don't describe sampling.
+            model::MaterialSamplers::default(),
+            uniform,
+            layout,
+        ));
+    }
+    Ok(materials)
+}
+
+fn create_obj_model(
+    file_name: &str,
+    meshes: ObjMeshes,
+    mut materials: Vec<model::WgpuMaterial>,
+    device: &wgpu::Device,
+    uploads: &Uploads,
+    layout: &model::MaterialLayout,
+    textures: &TextureCache,
+) -> model::WgpuModel {
+    // Meshes referencing a material the MTL doesn't define get the default one.
+    let num_materials = materials.len();
+    let material = |id: Option<usize>| id.filter(|id| *id < num_materials).unwrap_or(num_materials);
+    let meshes = match meshes {
+        ObjMeshes::Cached(cached) => cached
+            .meshes
+            .iter()
+            .map(|m| cached.upload(m, device, uploads, file_name, material(m.material())))
+            .collect::<Vec<_>>(),
+        ObjMeshes::Parsed(mesh_data) => mesh_data
+            .iter()
+            .map(|m| m.upload(device, uploads, file_name, material(m.material)))
+            .collect(),
+    };
+    if meshes.iter().any(|m| m.material == num_materials) {
+        materials.push(default_material(textures, device, layout));
+    }
+
+    // OBJ has no node hierarchy or animation.
+    model::WgpuModel {
+        meshes,
+        materials,
+        nodes: vec![],
+        skins: vec![],
+        animations: vec![],
+        sources: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::{check_triangles, parse_obj_meshes, ObjFiles};
+    use crate::wgpu_renderer::{model, streaming::block_on};
+
+    const MTL: &str = "\
+newmtl red
+Kd 1 0 0
+newmtl blue
+Kd 0 0 1
+Ks 0 0 0
+Ns 10
+newmtl mapped
+map_Kd mapped.png
+";
+
+    // Parses obj with test.mtl resolving to MTL, and no textures.
+    fn parse(obj: &str) -> (Vec<model::MeshData>, Result<Vec<tobj::Material>, tobj::LoadError>, Vec<String>) {
+        let mtl = |name: &str| match name {
+            "test.mtl" => Ok(MTL.as_bytes().to_vec()),
+            _ => anyhow::bail!("No file {}", name),
+        };
+        let texture = |name: &str| -> anyhow::Result<Vec<u8>> { anyhow::bail!("No file {}", name) };
+        let files = ObjFiles::Resolvers { mtl: &mtl, texture: &texture };
+        block_on(parse_obj_meshes("test.obj", obj.as_bytes(), &files, Instant::now())).unwrap()
+    }
+
+    #[test]
+    fn meshes_use_the_materials_of_their_library() {
+        let (meshes, materials, libraries) = parse(
+            "\
+mtllib test.mtl
+v 0 0 0
+v 1 0 0
+v 0 1 0
+v 1 1 0
+o first
+usemtl blue
+f 1 2 3
+o second
+usemtl red
+f 2 4 3
+",
+        );
+        assert_eq!(libraries, ["test.mtl"]);
+        let materials = materials.unwrap();
+        let names: Vec<&str> = materials.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["red", "blue", "mapped"]);
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].material, Some(1));
+        assert_eq!(meshes[1].material, Some(0));
+    }
+
+    #[test]
+    fn missing_colors_get_defaults() {
+        let (_, materials, _) = parse("mtllib test.mtl\n");
+        let materials = materials.unwrap();
+        // Only a map, which isn't darkened.
+        assert_eq!(materials[2].diffuse, [1.0; 3]);
+        assert_eq!(materials[2].specular, [0.5; 3]);
+        assert_eq!(materials[2].shininess, 0.0);
+        // Given colors are kept, black included.
+        assert_eq!(materials[1].diffuse, [0.0, 0.0, 1.0]);
+        assert_eq!(materials[1].specular, [0.0; 3]);
+        assert_eq!(materials[0].specular, [0.5; 3]);
+    }
+
+    #[test]
+    fn missing_libraries_only_fail_the_materials() {
+        let (meshes, materials, libraries) = parse("mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n");
+        assert_eq!(libraries, ["missing.mtl"]);
+        assert!(materials.is_err());
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].material, None);
+    }
+
+    #[test]
+    fn normals_are_generated_and_missing_uvs_are_zero() {
+        // A quad facing +z, counter-clockwise seen from the front.
+        let (meshes, _, _) = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n");
+        assert_eq!(meshes.len(), 1);
+        let mesh = &meshes[0];
+        assert_eq!(mesh.indices.len(), 6);
+        for vertex in &mesh.vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+            assert_eq!(vertex.tex_coords, [0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn uvs_are_flipped_for_meshes_without_normals() {
+        let (meshes, _, _) = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0.25\nvt 1 0.25\nvt 0 1\nf 1/1 2/2 3/3\n");
+        let tex_coords: Vec<[f32; 2]> = meshes[0].vertices.iter().map(|v| v.tex_coords).collect();
+        assert!(tex_coords.contains(&[0.0, 0.75]));
+        assert!(tex_coords.contains(&[1.0, 0.75]));
+        assert!(tex_coords.contains(&[0.0, 0.0]));
+        for vertex in &meshes[0].vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn triangle_lists_are_checked() {
+        assert!(check_triangles(3, &[0, 1, 2]).is_ok());
+        assert!(check_triangles(0, &[]).is_ok());
+        assert!(check_triangles(3, &[0, 1]).is_err());
+        assert!(check_triangles(3, &[0, 1, 3]).is_err());
+        assert!(check_triangles(4, &[0, 1, 2, 2, 3, 0]).is_ok());
+    }
 }
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use super::streaming;
+
+// How texture coordinates outside of [0, 1] are handled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WrapMode {
+    Repeat,
+    MirrorRepeat,
+    ClampToEdge,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FilterMode {
+    Nearest,
+    Linear,
+}
+
+// How a material samples one of its textures. The default repeats and
+// filters trilinearly, which is what tiling textures need.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub mag_filter: FilterMode,
+    pub min_filter: FilterMode,
+    // Filtering between mip levels.
+    pub mipmap_filter: FilterMode,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            wrap_u: WrapMode::Repeat,
+            wrap_v: WrapMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+        }
+    }
+}
+
+impl SamplerDesc {
+    // Anisotropic filtering only applies to samplers filtering linearly in
+    // every direction, wgpu rejects it for the others.
+    fn is_trilinear(&self) -> bool {
+        self.mag_filter == FilterMode::Linear
+            && self.min_filter == FilterMode::Linear
+            && self.mipmap_filter == FilterMode::Linear
+    }
+}
+
+// Sets the anisotropic filtering of every trilinear sampler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureQuality {
+    // Trilinear filtering only.
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+impl TextureQuality {
+    fn max_anisotropy(self) -> u16 {
+        match self {
+            TextureQuality::Low => 1,
+            TextureQuality::Medium => 4,
+            TextureQuality::High => 8,
+            TextureQuality::Ultra => 16,
+        }
+    }
+}
+
+fn address_mode(wrap: WrapMode) -> wgpu::AddressMode {
+    match wrap {
+        WrapMode::Repeat => wgpu::AddressMode::Repeat,
+        WrapMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrapMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+    }
+}
+
+fn filter_mode(filter: FilterMode) -> wgpu::FilterMode {
+    match filter {
+        FilterMode::Nearest => wgpu::FilterMode::Nearest,
+        FilterMode::Linear => wgpu::FilterMode::Linear,
+    }
+}
+
+// Samplers shared between every material that samples the same way.
+pub struct SamplerCache {
+    quality: Mutex<TextureQuality>,
+    samplers: Mutex<HashMap<SamplerDesc, Arc<wgpu::Sampler>>>,
+}
+
+impl SamplerCache {
+    pub fn new(quality: TextureQuality) -> Self {
+        Self {
+            quality: Mutex::new(quality),
+            samplers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, device: &wgpu::Device, desc: &SamplerDesc) -> Arc<wgpu::Sampler> {
+        let mut samplers = self.samplers.lock().unwrap();
+        let quality = *self.quality.lock().unwrap();
+        samplers
+            .entry(*desc)
+            .or_insert_with(|| {
+                // Devices without anisotropic filtering clamp this to 1.
+                let anisotropy_clamp = if desc.is_trilinear() { quality.max_anisotropy() } else { 1 };
+                let _device_guard = streaming::lock_device();
+                Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
+                    label: Some("material sampler"),
+                    address_mode_u: address_mode(desc.wrap_u),
+                    address_mode_v: address_mode(desc.wrap_v),
+                    address_mode_w: wgpu::AddressMode::ClampToEdge,
+                    mag_filter: filter_mode(desc.mag_filter),
+                    min_filter: filter_mode(desc.min_filter),
+                    mipmap_filter: filter_mode(desc.mipmap_filter),
+                    anisotropy_clamp,
+                    ..Default::default()
+                }))
+            })
+            .clone()
+    }
+
+    // Samplers handed out before keep the old quality, the bind groups
+    // using them have to be recreated. Returns whether the quality changed.
+    pub fn set_quality(&self, quality: TextureQuality) -> bool {
+        let mut samplers = self.samplers.lock().unwrap();
+        let mut current = self.quality.lock().unwrap();
+        if quality == *current {
+            return false;
+        }
+        *current = quality;
+        samplers.clear();
+        true
+    }
+}
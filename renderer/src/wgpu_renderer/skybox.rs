@@ -0,0 +1,235 @@
+use anyhow::*;
+use cgmath::{Matrix, Matrix3, Matrix4, SquareMatrix, Vector3};
+use wgpu::util::DeviceExt;
+
+use super::{environment::Environment, resources, texture::Texture};
+use crate::camera::{projection::Projection, Camera};
+
+const SHADER_FILE: &str = "skybox.wgsl";
+
+const MODE_GRADIENT: u32 = 0;
+const MODE_ENVIRONMENT: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyboxUniform {
+    clip_to_direction: [[f32; 4]; 4],
+    zenith: [f32; 3],
+    mode: u32,
+    horizon: [f32; 3],
+    _padding: u32,
+    ground: [f32; 3],
+    _padding2: u32,
+}
+
+// Draws a gradient or an environment map behind the scene, after the opaque
+// meshes so only the uncovered pixels are shaded. Plain colors are left to
+// the clear color.
+pub struct SkyboxPass {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    uniform: SkyboxUniform,
+    buffer: wgpu::Buffer,
+    clear_color: wgpu::Color,
+    enabled: bool,
+}
+
+impl SkyboxPass {
+    // placeholder is bound while the skybox doesn't show an environment.
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        placeholder: &Environment,
+    ) -> Result<Self> {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("skybox_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(SHADER_FILE),
+            source: wgpu::ShaderSource::Wgsl(resources::load_string(SHADER_FILE)?.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+                compilation_options: Default::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: color_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: Default::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            // The triangle lies on the far plane, which only the cleared
+            // depth passes.
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: depth_format,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("skybox sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform = SkyboxUniform {
+            clip_to_direction: Matrix4::identity().into(),
+            zenith: [0.0; 3],
+            mode: MODE_GRADIENT,
+            horizon: [0.0; 3],
+            _padding: 0,
+            ground: [0.0; 3],
+            _padding2: 0,
+        };
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Skybox Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = create_bind_group(device, &bind_group_layout, &buffer, &placeholder.cube, &sampler);
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            sampler,
+            uniform,
+            buffer,
+            clear_color: wgpu::Color {
+                r: 0.1,
+                g: 0.2,
+                b: 0.3,
+                a: 1.0,
+            },
+            enabled: false,
+        })
+    }
+
+    pub fn set_color(&mut self, color: [f32; 3]) {
+        let [r, g, b] = color.map(f64::from);
+        self.clear_color = wgpu::Color { r, g, b, a: 1.0 };
+        self.enabled = false;
+    }
+
+    pub fn set_gradient(&mut self, zenith: [f32; 3], horizon: [f32; 3], ground: [f32; 3]) {
+        self.uniform.mode = MODE_GRADIENT;
+        self.uniform.zenith = zenith;
+        self.uniform.horizon = horizon;
+        self.uniform.ground = ground;
+        self.enabled = true;
+    }
+
+    pub fn set_environment(&mut self, device: &wgpu::Device, environment: &Environment) {
+        self.uniform.mode = MODE_ENVIRONMENT;
+        self.bind_group = create_bind_group(device, &self.bind_group_layout, &self.buffer, &environment.cube, &self.sampler);
+        self.enabled = true;
+    }
+
+    pub fn clear_color(&self) -> wgpu::Color {
+        self.clear_color
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &Camera, projection: &Projection) {
+        if !self.enabled {
+            return;
+        }
+        // Rays are built from the field of view rather than by inverting the
+        // projection, only their direction matters. The camera rotation is
+        // orthonormal, so its transpose turns view into world directions.
+        let view = camera.calc_matrix();
+        let rotation = Matrix3::from_cols(view.x.truncate(), view.y.truncate(), view.z.truncate());
+        let (scale_x, scale_y) = projection.view_ray_scale();
+        let view_ray = Matrix3::from_diagonal(Vector3::new(scale_x, scale_y, -1.0));
+        self.uniform.clip_to_direction = Matrix4::from(rotation.transpose() * view_ray).into();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if !self.enabled {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffer: &wgpu::Buffer,
+    cube: &Texture,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("skybox_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&cube.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    })
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::pin,
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use wgpu::util::StagingBelt;
+
+use super::{model, resources, texture_cache::TextureCache};
+
+// How many bytes of loaded data are uploaded per frame, enough for a few
+// 2K textures with their mips without dropping frames.
+const FRAME_BUDGET: u64 = 16 << 20;
+
+// Writes larger than this get a staging chunk of their own.
+const STAGING_CHUNK_SIZE: u64 = 4 << 20;
+
+// A write of loaded data to the GPU, waiting for its turn.
+enum Write {
+    Buffer {
+        buffer: Arc<wgpu::Buffer>,
+        data: Vec<u8>,
+    },
+    Texture {
+        texture: Arc<wgpu::Texture>,
+        mip_level: u32,
+        layout: wgpu::ImageDataLayout,
+        size: wgpu::Extent3d,
+        data: Vec<u8>,
+    },
+}
+
+impl Write {
+    fn len(&self) -> u64 {
+        match self {
+            Write::Buffer { data, .. } | Write::Texture { data, .. } => data.len() as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Writes {
+    pending: VecDeque<Write>,
+    pending_bytes: u64,
+    // Writes queued and executed since the renderer was created.
+    queued: u64,
+    executed: u64,
+}
+
+// The data of new buffers and textures. Loaders queue it here instead of
+// writing it themselves, so loading threads never touch the queue and a
+// large model is uploaded over several frames. Writes are executed in the
+// order they were queued.
+#[derive(Default)]
+pub struct Uploads {
+    writes: Mutex<Writes>,
+}
+
+impl Uploads {
+    fn push(&self, write: Write) {
+        let mut writes = self.writes.lock().unwrap();
+        writes.pending_bytes += write.len();
+        writes.queued += 1;
+        writes.pending.push_back(write);
+    }
+
+    // The buffer needs COPY_DST and a size of data rounded up to a multiple
+    // of four.
+    pub fn write_buffer(&self, buffer: &Arc<wgpu::Buffer>, mut data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        data.resize(data.len().next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize), 0);
+        self.push(Write::Buffer {
+            buffer: buffer.clone(),
+            data,
+        });
+    }
+
+    pub fn write_texture(
+        &self,
+        texture: &Arc<wgpu::Texture>,
+        mip_level: u32,
+        layout: wgpu::ImageDataLayout,
+        size: wgpu::Extent3d,
+        data: Vec<u8>,
+    ) {
+        self.push(Write::Texture {
+            texture: texture.clone(),
+            mip_level,
+            layout,
+            size,
+            data,
+        });
+    }
+
+    // Everything queued before queued() returned n is on the GPU once
+    // executed() reaches n.
+    pub fn queued(&self) -> u64 {
+        self.writes.lock().unwrap().queued
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.writes.lock().unwrap().executed
+    }
+
+    pub fn pending_bytes(&self) -> u64 {
+        self.writes.lock().unwrap().pending_bytes
+    }
+
+    // Executes queued writes until budget bytes were written, at least one.
+    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue, belt: &mut StagingBelt, budget: u64) {
+        let mut batch = Vec::new();
+        {
+            let mut writes = self.writes.lock().unwrap();
+            let mut size = 0;
+            while size < budget {
+                let Some(write) = writes.pending.pop_front() else {
+                    break;
+                };
+                size += write.len();
+                batch.push(write);
+            }
+            writes.pending_bytes -= size;
+        }
+        if batch.is_empty() {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Upload Encoder"),
+        });
+        for write in &batch {
+            match write {
+                Write::Buffer { buffer, data } => {
+                    // Both are non-zero multiples of four, see write_buffer.
+                    let size = wgpu::BufferSize::new(data.len() as u64).unwrap();
+                    belt.write_buffer(&mut encoder, buffer, 0, size, device).copy_from_slice(data);
+                }
+                // wgpu stages texture writes itself, they are submitted
+                // together with the encoder.
+                Write::Texture {
+                    texture,
+                    mip_level,
+                    layout,
+                    size,
+                    data,
+                } => queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        aspect: wgpu::TextureAspect::All,
+                        texture,
+                        mip_level: *mip_level,
+                        origin: wgpu::Origin3d::ZERO,
+                    },
+                    data,
+                    *layout,
+                    *size,
+                ),
+            }
+        }
+        belt.finish();
+        queue.submit(std::iter::once(encoder.finish()));
+        belt.recall();
+        self.writes.lock().unwrap().executed += batch.len() as u64;
+    }
+}
+
+// How far the models requested with load_model are.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub requested: usize,
+    // Loaded and uploaded, drawn instead of their placeholders.
+    pub loaded: usize,
+    // Drawn as placeholders for good, see the log for why.
+    pub failed: usize,
+    // Loaded data still waiting to be uploaded.
+    pub pending_bytes: u64,
+}
+
+impl LoadProgress {
+    // Between 0 and 1, for progress bars.
+    pub fn fraction(&self) -> f32 {
+        if self.requested == 0 {
+            return 1.0;
+        }
+        (self.loaded + self.failed) as f32 / self.requested as f32
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.loaded + self.failed == self.requested
+    }
+}
+
+// Loaders hold this around the resources they create, which may happen on
+// any thread. Error scopes are shared by every thread, so whoever opens one
+// holds it too, to keep the errors of other threads out.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn lock_device() -> MutexGuard<'static, ()> {
+    DEVICE_LOCK.lock().unwrap()
+}
+
+struct Job {
+    model: u16,
+    file_name: String,
+}
+
+// The loaders are async in name only, they never wait for anything, and
+// neither do error scopes on native backends.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::yield_now();
+    }
+}
+
+// Reads, parses and decodes models on a thread of its own. Loaded models
+// wait here until their data was uploaded, the renderer draws placeholders
+// for them until then.
+pub struct ModelStreamer {
+    jobs: mpsc::Sender<Job>,
+    loaded: mpsc::Receiver<(u16, anyhow::Result<model::WgpuModel>)>,
+    uploads: Arc<Uploads>,
+    belt: StagingBelt,
+    // Models loaded already, with the number of writes to wait for.
+    uploading: Vec<(u16, model::WgpuModel, u64)>,
+    progress: LoadProgress,
+}
+
+impl ModelStreamer {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        uploads: Arc<Uploads>,
+        layout: Arc<model::MaterialLayout>,
+        textures: Arc<TextureCache>,
+    ) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (loaded_sender, loaded) = mpsc::channel();
+        let thread_uploads = uploads.clone();
+        thread::Builder::new()
+            .name("model streaming".to_string())
+            .spawn(move || {
+                // Ends once the renderer is dropped.
+                for job in job_receiver {
+                    let model = block_on(resources::load_wgpu_model(
+                        &job.file_name,
+                        &device,
+                        &thread_uploads,
+                        &layout,
+                        &textures,
+                    ))
+                    .map_err(|e| e.context(format!("Could not load {}", job.file_name)));
+                    if loaded_sender.send((job.model, model)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Could not start the model streaming thread");
+
+        Self {
+            jobs,
+            loaded,
+            uploads,
+            belt: StagingBelt::new(STAGING_CHUNK_SIZE),
+            uploading: Vec::new(),
+            progress: LoadProgress::default(),
+        }
+    }
+
+    // Keeps the streaming thread from creating resources until the guard
+    // is dropped, for error scopes, see lock_device. Reading and decoding
+    // go on in the meantime.
+    pub fn pause(&self) -> MutexGuard<'static, ()> {
+        lock_device()
+    }
+
+    pub fn load(&mut self, model: u16, file_name: &str) {
+        self.progress.requested += 1;
+        let job = Job {
+            model,
+            file_name: file_name.to_string(),
+        };
+        if self.jobs.send(job).is_err() {
+            log::error!("The model streaming thread is gone, {} is not loaded", file_name);
+            self.progress.failed += 1;
+        }
+    }
+
+    // Uploads this frame's share of the loaded data and returns the models
+    // that are ready to be drawn.
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<(u16, model::WgpuModel)> {
+        for (handle, model) in self.loaded.try_iter() {
+            match model {
+                // Writes queued for the next model may be counted too, which
+                // only delays this one a little.
+                Ok(model) => self.uploading.push((handle, model, self.uploads.queued())),
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    self.progress.failed += 1;
+                }
+            }
+        }
+
+        self.uploads.upload(device, queue, &mut self.belt, FRAME_BUDGET);
+
+        let executed = self.uploads.executed();
+        let (ready, uploading) = std::mem::take(&mut self.uploading)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, _, writes)| *writes <= executed);
+        self.uploading = uploading;
+        self.progress.loaded += ready.len();
+        ready.into_iter().map(|(handle, model, _)| (handle, model)).collect()
+    }
+
+    // Uploads everything queued, for models and textures created in place
+    // which are drawn right away.
+    pub fn flush(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.uploads.upload(device, queue, &mut self.belt, u64::MAX);
+    }
+
+    pub fn uploads(&self) -> &Uploads {
+        &self.uploads
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        LoadProgress {
+            pending_bytes: self.uploads.pending_bytes(),
+            ..self.progress
+        }
+    }
+}
@@ -1,112 +1,175 @@
-use std::time::Instant;
+use std::sync::Arc;
 
-// use image::GenericImageView;
 use anyhow::*;
-use stb_image::stb_image::{stbi_image_free, stbi_load_from_memory};
+
+use super::{compressed_texture, image_decoding::{self, ImageError}, streaming::{self, Uploads}};
 
 pub struct Texture {
+    // Shared with the uploads writing its data.
     #[allow(unused)]
-    pub texture: wgpu::Texture,
+    pub texture: Arc<wgpu::Texture>,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
 }
 
 impl Texture {
     pub fn from_bytes(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
+        uploads: &Uploads,
         bytes: &[u8], 
         label: &str,
-        is_normal_map: bool
+        kind: TextureKind
     ) -> Result<Self> {
-        // let img = image::load_from_memory(bytes)?;
-        // Self::from_image(device, queue, img.to_rgba8().as_bytes(), img.width(), img.height(), Some(label))
-        unsafe {
-            let begin = Instant::now();
-
-            let mut width: i32 = 0;
-            let mut height: i32 = 0;
-            let mut real_channels: i32 = 0;
-            let actual_channels = 4;
-            let buffer = stbi_load_from_memory(bytes.as_ptr() as _ , bytes.len() as i32, &mut width, &mut height, &mut real_channels, actual_channels);
-
-            if buffer.is_null() {
-                log::warn!("Invalid texture with label {}", label);
-                bail!("Image not found"); // TODO: Implement proper error handling.
+        if compressed_texture::is_container(bytes) {
+            return compressed_texture::load(device, uploads, bytes, label, kind);
+        }
+        let image = image_decoding::decode(bytes, device.limits().max_texture_dimension_2d)
+            .with_context(|| format!("Could not decode {}", label))?;
+        let (width, height) = (image.width(), image.height());
+        if kind == TextureKind::Data && image.color().channel_count() <= 2 {
+            return Self::from_gray(device, uploads, image, label);
+        }
+        if image_decoding::is_high_precision(&image) {
+            // Float images are linear already, 16 bit colors are sRGB encoded.
+            let is_float = matches!(image.color(), image::ColorType::Rgb32F | image::ColorType::Rgba32F);
+            let mut pixels = image.into_rgba32f().into_raw();
+            if kind == TextureKind::Color && !is_float {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    for c in &mut pixel[..3] {
+                        *c = srgb_to_linear(*c);
+                    }
+                }
             }
-
-            let data = std::slice::from_raw_parts(buffer, (width*height*actual_channels) as usize);
-            println!("stb_image::image::load_from_memory for label: {} took {}ms", label, begin.elapsed().as_millis());
-            let res = Self::from_image(device, queue, data, width as u32, height as u32, Some(label), is_normal_map);
-            stbi_image_free(buffer as _);
-            res
+            let levels = generate_mips_f16(pixels, width, height, kind);
+            let levels = levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+            return Self::from_mips(device, uploads, wgpu::TextureFormat::Rgba16Float, width, height, &levels, label);
         }
+        Self::from_image(device, uploads, &image.into_rgba8(), width, height, Some(label), kind)
+    }
+
+    // Uploads grayscale data with one channel, or two with alpha, instead of
+    // expanding it to four. Gray ends up in red and alpha in green.
+    fn from_gray(device: &wgpu::Device, uploads: &Uploads, image: image::DynamicImage, label: &str) -> Result<Self> {
+        use wgpu::TextureFormat;
+
+        let (width, height) = (image.width(), image.height());
+        let has_alpha = image.color().has_alpha();
+        let channels: &[usize] = if has_alpha { &[0, 3] } else { &[0] };
+        let (format, levels) = if image_decoding::is_high_precision(&image) {
+            // 16 bit unorm formats need a device feature, half floats keep
+            // more than 8 bits as well.
+            let format = if has_alpha { TextureFormat::Rg16Float } else { TextureFormat::R16Float };
+            let levels = generate_mips_f16(image.into_rgba32f().into_raw(), width, height, TextureKind::Data);
+            (format, select_channels(levels, 2, channels))
+        } else {
+            let format = if has_alpha { TextureFormat::Rg8Unorm } else { TextureFormat::R8Unorm };
+            let levels = generate_mips(image.into_rgba8().into_raw(), width, height, TextureKind::Data);
+            (format, select_channels(levels, 1, channels))
+        };
+        let levels = levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        Self::from_mips(device, uploads, format, width, height, &levels, label)
+    }
+
+    // Decodes an encoded image into tightly packed RGBA8 pixels, for
+    // processing on the CPU.
+    pub fn decode_rgba8(bytes: &[u8], label: &str) -> Result<(Vec<u8>, u32, u32)> {
+        let image = image_decoding::decode(bytes, image_decoding::DEFAULT_MAX_DIMENSION)
+            .with_context(|| format!("Could not decode {}", label))?;
+        let (width, height) = (image.width(), image.height());
+        Ok((image.into_rgba8().into_raw(), width, height))
+    }
+
+    // Creates a 1x1 texture filled with a single color.
+    pub fn from_color(
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        color: [u8; 4],
+        label: &str,
+        is_linear: bool
+    ) -> Result<Self> {
+        let kind = if is_linear { TextureKind::Data } else { TextureKind::Color };
+        Self::from_image(device, uploads, &color, 1, 1, Some(label), kind)
     }
 
+    // Uploads tightly packed RGBA8 pixels together with the mip chain
+    // generated from them.
     pub fn from_image(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
+        uploads: &Uploads,
         data: &[u8],
         width: u32,
         height: u32,
         label: Option<&str>,
-        is_normal_map: bool
+        kind: TextureKind
     ) -> Result<Self> {
-        let size = wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        };
-
-        let format = if is_normal_map {
+        let format = if kind.is_linear() {
             wgpu::TextureFormat::Rgba8Unorm
         } else {
             wgpu::TextureFormat::Rgba8UnormSrgb
         };
+        let levels = generate_mips(data.to_vec(), width, height, kind);
+        let levels = levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        Self::from_mips(device, uploads, format, width, height, &levels, label.unwrap_or("texture"))
+    }
+
+    // Creates a texture from a full or partial mip chain, level i being
+    // max(1, width >> i) by max(1, height >> i). Block compressed levels hold
+    // whole blocks, even when the level is smaller than a block.
+    pub fn from_mips(
+        device: &wgpu::Device,
+        uploads: &Uploads,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        levels: &[&[u8]],
+        label: &str,
+    ) -> Result<Self> {
+        let (block_width, block_height) = format.block_dimensions();
+        let block_size = format
+            .block_copy_size(None)
+            .ok_or_else(|| anyhow!("Unsupported texture format {:?}", format))?;
+        check_mips(device, format, width, height, levels, label)?;
 
-        let texture = device.create_texture(
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let device_guard = streaming::lock_device();
+        let texture = Arc::new(device.create_texture(
             &wgpu::TextureDescriptor {
-                label,
+                label: Some(label),
                 size,
-                mip_level_count: 1,
+                mip_level_count: levels.len() as u32,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
                 format,
                 usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
                 view_formats: &[],
             }
-        );
-
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-           data,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * width),
-                rows_per_image: Some(height),
-            },
-            size,
-        );
-
+        ));
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
-        
-        Ok(Self { texture, view, sampler })
+        drop(device_guard);
+
+        for (level, data) in levels.iter().enumerate() {
+            let level_size = size
+                .mip_level_size(level as u32, wgpu::TextureDimension::D2)
+                .physical_size(format);
+            let blocks_per_row = level_size.width / block_width;
+            let rows = level_size.height / block_height;
+            uploads.write_texture(
+                &texture,
+                level as u32,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(blocks_per_row * block_size),
+                    rows_per_image: Some(rows),
+                },
+                level_size,
+                data.to_vec(),
+            );
+        }
+
+        Ok(Self { texture, view })
     }
 
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float; // 1.
@@ -128,24 +191,288 @@ impl Texture {
                 | wgpu::TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
         };
-        let texture = device.create_texture(&desc);
+        let texture = Arc::new(device.create_texture(&desc));
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor { // 4.
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                compare: Some(wgpu::CompareFunction::LessEqual), // 5.
-                lod_min_clamp: 0.0,
-                lod_max_clamp: 100.0,
-                ..Default::default()
+        Self { texture, view }
+    }
+}
+
+// Keeps the given channels of RGBA pixels, channel_size bytes each.
+fn select_channels(levels: Vec<Vec<u8>>, channel_size: usize, channels: &[usize]) -> Vec<Vec<u8>> {
+    levels
+        .into_iter()
+        .map(|level| {
+            level
+                .chunks_exact(4 * channel_size)
+                .flat_map(|pixel| channels.iter().flat_map(move |c| &pixel[c * channel_size..(c + 1) * channel_size]))
+                .copied()
+                .collect()
+        })
+        .collect()
+}
+
+// Whether from_bytes uploaded the texture as grayscale, see from_gray.
+pub fn is_gray(format: wgpu::TextureFormat) -> bool {
+    use wgpu::TextureFormat;
+
+    matches!(
+        format,
+        TextureFormat::R8Unorm | TextureFormat::Rg8Unorm | TextureFormat::R16Float | TextureFormat::Rg16Float
+    )
+}
+
+// The size of a mip level in bytes, in whole blocks.
+pub fn level_size(format: wgpu::TextureFormat, width: u32, height: u32, level: u32) -> usize {
+    let (block_width, block_height) = format.block_dimensions();
+    let block_size = format.block_copy_size(None).unwrap_or(4);
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    }
+    .mip_level_size(level, wgpu::TextureDimension::D2)
+    .physical_size(format);
+    (size.width / block_width) as usize * (size.height / block_height) as usize * block_size as usize
+}
+
+// Checks the size, the number of levels and the size of every level of a
+// mip chain against each other and the device, before anything is created
+// or decoded from it.
+pub fn check_mips(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    levels: &[&[u8]],
+    label: &str,
+) -> Result<()> {
+    let max_dimension = device.limits().max_texture_dimension_2d;
+    if width == 0 || height == 0 {
+        return Err(ImageError::Corrupt(format!("{} is {}x{} pixels", label, width, height)).into());
+    }
+    if width > max_dimension || height > max_dimension {
+        return Err(ImageError::TooLarge { width, height, max_dimension }.into());
+    }
+    let max_levels = 32 - width.max(height).leading_zeros();
+    if levels.is_empty() || levels.len() > max_levels as usize {
+        return Err(ImageError::Corrupt(format!(
+            "{} has {} mip levels, {}x{} textures have 1 to {}",
+            label,
+            levels.len(),
+            width,
+            height,
+            max_levels
+        ))
+        .into());
+    }
+    for (level, data) in levels.iter().enumerate() {
+        let expected = level_size(format, width, height, level as u32);
+        if data.len() != expected {
+            return Err(ImageError::Corrupt(format!(
+                "mip level {} of {} has {} bytes instead of {}",
+                level,
+                label,
+                data.len(),
+                expected
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// What a texture's pixels hold, which decides how they are filtered into
+// smaller mip levels and how they may be compressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TextureKind {
+    // sRGB encoded colors.
+    Color,
+    // Linear values such as metallic-roughness or occlusion.
+    Data,
+    // Tangent space normals encoded as n * 0.5 + 0.5.
+    Normal,
+}
+
+impl TextureKind {
+    pub fn is_linear(self) -> bool {
+        self != TextureKind::Color
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let c = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+// Halves linear RGBA pixels by averaging 2x2 of them, keeping normals unit
+// length. Odd sizes clamp to the last row or column.
+fn downsample(source: &[f32], width: usize, height: usize, kind: TextureKind) -> Vec<f32> {
+    let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut level = Vec::with_capacity(next_width * next_height * 4);
+    for y in 0..next_height {
+        for x in 0..next_width {
+            let mut sum = [0.0f32; 4];
+            for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (x * 2 + sx).min(width - 1);
+                let sy = (y * 2 + sy).min(height - 1);
+                let pixel = &source[(sy * width + sx) * 4..][..4];
+                for c in 0..4 {
+                    sum[c] += match kind {
+                        TextureKind::Normal if c < 3 => pixel[c] * 2.0 - 1.0,
+                        _ => pixel[c],
+                    };
+                }
             }
-        );
+            let mut average = sum.map(|c| c / 4.0);
+            if kind == TextureKind::Normal {
+                let length = (average[0] * average[0] + average[1] * average[1] + average[2] * average[2]).sqrt();
+                let normal = if length > 0.0 {
+                    [average[0] / length, average[1] / length, average[2] / length]
+                } else {
+                    [0.0, 0.0, 1.0]
+                };
+                for c in 0..3 {
+                    average[c] = normal[c] * 0.5 + 0.5;
+                }
+            }
+            level.extend_from_slice(&average);
+        }
+    }
+    level
+}
+
+// The mip chain of linear RGBA pixels down to 1x1, level 0 being the
+// pixels themselves.
+fn mip_chain(pixels: Vec<f32>, width: u32, height: u32, kind: TextureKind) -> Vec<Vec<f32>> {
+    let mut levels = vec![pixels];
+    let (mut width, mut height) = (width as usize, height as usize);
+    while width > 1 || height > 1 {
+        let level = downsample(levels.last().unwrap(), width, height, kind);
+        levels.push(level);
+        (width, height) = ((width / 2).max(1), (height / 2).max(1));
+    }
+    levels
+}
+
+// Builds the mip chain of tightly packed RGBA8 pixels down to 1x1, level 0
+// being the pixels themselves. Each level averages 2x2 pixels of the one
+// above, in linear space for colors, and keeps normals unit length.
+pub fn generate_mips(pixels: Vec<u8>, width: u32, height: u32, kind: TextureKind) -> Vec<Vec<u8>> {
+    let srgb_table = (0..=255).map(|value| srgb_to_linear(value as f32 / 255.0)).collect::<Vec<_>>();
+    let srgb_table = &srgb_table;
+    let linear = pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            (0..4).map(move |c| match kind {
+                TextureKind::Color if c < 3 => srgb_table[pixel[c] as usize],
+                _ => pixel[c] as f32 / 255.0,
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut levels = mip_chain(linear, width, height, kind)
+        .into_iter()
+        .skip(1)
+        .map(|level| {
+            level
+                .chunks_exact(4)
+                .flat_map(|pixel| {
+                    (0..4).map(move |c| match kind {
+                        TextureKind::Color if c < 3 => linear_to_srgb(pixel[c]),
+                        _ => (pixel[c] * 255.0).round().clamp(0.0, 255.0) as u8,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    levels.insert(0, pixels);
+    levels
+}
+
+// Builds the mip chain of linear RGBA float pixels as Rgba16Float data.
+pub fn generate_mips_f16(pixels: Vec<f32>, width: u32, height: u32, kind: TextureKind) -> Vec<Vec<u8>> {
+    mip_chain(pixels, width, height, kind)
+        .into_iter()
+        .map(|level| {
+            let level = level.into_iter().map(half::f16::from_f32).collect::<Vec<_>>();
+            bytemuck::cast_slice(&level).to_vec()
+        })
+        .collect()
+}
+
+// 1x1 textures bound in place of the maps a material doesn't provide. They
+// are created once by the renderer and shared by every material.
+pub struct DefaultTextures {
+    // Neutral for base color, metallic-roughness, occlusion and emissive,
+    // since all of them are multiplied with their factors.
+    pub white: Arc<Texture>,
+    // A flat tangent space normal, (0.5, 0.5, 1.0) once encoded.
+    pub normal: Arc<Texture>,
+}
+
+impl DefaultTextures {
+    pub fn new(device: &wgpu::Device, uploads: &Uploads) -> Result<Self> {
+        Ok(Self {
+            white: Arc::new(Texture::from_color(device, uploads, [255; 4], "default white texture", true)?),
+            normal: Arc::new(Texture::from_color(device, uploads, [128, 128, 255, 255], "default normal texture", true)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_mips, level_size, TextureKind};
+
+    #[test]
+    fn levels_hold_whole_blocks() {
+        use wgpu::TextureFormat;
+        assert_eq!(level_size(TextureFormat::Rgba8Unorm, 5, 3, 0), 5 * 3 * 4);
+        assert_eq!(level_size(TextureFormat::Rgba8Unorm, 5, 3, 2), 4);
+        // 8 bytes per 4x4 block, levels smaller than a block still take one.
+        assert_eq!(level_size(TextureFormat::Bc1RgbaUnorm, 8, 6, 0), 2 * 2 * 8);
+        assert_eq!(level_size(TextureFormat::Bc1RgbaUnorm, 8, 6, 2), 8);
+        assert_eq!(level_size(TextureFormat::Rgba32Float, 16384, 16384, 0), 16384 * 16384 * 16);
+    }
+
+    #[test]
+    fn chains_go_down_to_one_pixel() {
+        let levels = generate_mips(vec![0; 5 * 3 * 4], 5, 3, TextureKind::Data);
+        let sizes: Vec<usize> = levels.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [5 * 3 * 4, 2 * 4, 4]);
+    }
+
+    #[test]
+    fn colors_are_averaged_in_linear_space() {
+        let pixels = vec![0, 0, 0, 0, 255, 255, 255, 255];
+        let color = generate_mips(pixels.clone(), 2, 1, TextureKind::Color);
+        assert_eq!(color[0], pixels);
+        // Half of linear white is 188 in sRGB, alpha is linear.
+        assert_eq!(color[1], [188, 188, 188, 128]);
+        let data = generate_mips(pixels, 2, 1, TextureKind::Data);
+        assert_eq!(data[1], [128, 128, 128, 128]);
+    }
 
-        Self { texture, view, sampler }
+    #[test]
+    fn normals_stay_unit_length() {
+        // +X and +Z average to a normal halfway between them.
+        let pixels = vec![255, 128, 128, 255, 128, 128, 255, 255];
+        let levels = generate_mips(pixels, 2, 1, TextureKind::Normal);
+        let normal = levels[1][..3].iter().map(|c| *c as f32 / 255.0 * 2.0 - 1.0).collect::<Vec<_>>();
+        let length = normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+        assert!((length - 1.0).abs() < 0.02, "{:?}", normal);
+        assert!((normal[0] - normal[2]).abs() < 0.02, "{:?}", normal);
+        assert_eq!(levels[1][3], 255);
     }
 }
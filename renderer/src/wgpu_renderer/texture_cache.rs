@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+};
+
+use super::{
+    manifest,
+    texture::{DefaultTextures, Texture, TextureKind},
+};
+
+// Counters of the texture cache since the renderer was created.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextureCacheStats {
+    // Loads served by a texture that was loaded already.
+    pub hits: u64,
+    // Loads that decoded and uploaded a texture.
+    pub misses: u64,
+    // Textures currently alive, held by at least one material.
+    pub live: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Path(String, TextureKind),
+    // The content hash of the file the texture was created from.
+    Content(u64, TextureKind),
+}
+
+impl Key {
+    fn path(path: &str, kind: TextureKind) -> Self {
+        let path = crate::assets::normalize(path).unwrap_or_else(|| path.to_string());
+        Key::Path(path, kind)
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    textures: HashMap<Key, Weak<Texture>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Entries {
+    fn get(&mut self, key: &Key) -> Option<Arc<Texture>> {
+        let texture = self.textures.get(key)?.upgrade()?;
+        self.hits += 1;
+        Some(texture)
+    }
+
+    fn insert(&mut self, keys: &[Key], texture: &Arc<Texture>) {
+        // Textures no material holds anymore were freed, forget them.
+        self.textures.retain(|_, texture| texture.strong_count() > 0);
+        for key in keys {
+            self.textures.insert(key.clone(), Arc::downgrade(texture));
+        }
+    }
+}
+
+// Textures shared between materials: the defaults, and every loaded texture
+// that is still in use. Loaded textures are found by the path they were
+// loaded from and by their content, so the same file under another path is
+// uploaded once as well. The cache doesn't keep textures alive, they are
+// freed with the last material using them.
+pub struct TextureCache {
+    pub defaults: DefaultTextures,
+    entries: Mutex<Entries>,
+}
+
+impl TextureCache {
+    pub fn new(defaults: DefaultTextures) -> Self {
+        Self {
+            defaults,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // The texture loaded from path, or the one create makes of the bytes
+    // read returns. Textures without a path, like the ones of in-memory
+    // files, are only found by their content.
+    pub fn load(
+        &self,
+        path: Option<&str>,
+        kind: TextureKind,
+        read: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+        create: impl FnOnce(&[u8]) -> anyhow::Result<Texture>,
+    ) -> anyhow::Result<Arc<Texture>> {
+        let path = path.map(|path| Key::path(path, kind));
+        if let Some(texture) = path.as_ref().and_then(|path| self.entries.lock().unwrap().get(path)) {
+            return Ok(texture);
+        }
+
+        let bytes = read()?;
+        let content = Key::Content(manifest::content_hash(&bytes), kind);
+        let mut keys = vec![content.clone()];
+        keys.extend(path);
+        let shared = self.entries.lock().unwrap().get(&content);
+        let texture = match shared {
+            Some(texture) => texture,
+            None => {
+                let texture = Arc::new(create(&bytes)?);
+                self.entries.lock().unwrap().misses += 1;
+                texture
+            }
+        };
+        self.entries.lock().unwrap().insert(&keys, &texture);
+        Ok(texture)
+    }
+
+    // Like load, for textures derived from files which are only found by
+    // their path.
+    pub fn load_path(
+        &self,
+        path: &str,
+        kind: TextureKind,
+        create: impl FnOnce() -> anyhow::Result<Texture>,
+    ) -> anyhow::Result<Arc<Texture>> {
+        let path = Key::path(path, kind);
+        if let Some(texture) = self.entries.lock().unwrap().get(&path) {
+            return Ok(texture);
+        }
+        let texture = Arc::new(create()?);
+        let mut entries = self.entries.lock().unwrap();
+        entries.misses += 1;
+        entries.insert(&[path], &texture);
+        Ok(texture)
+    }
+
+    // Forgets which textures were loaded from path or derived from it, for
+    // files that changed. Textures with the same content are still shared.
+    pub fn forget(&self, path: &str) {
+        let Key::Path(path, _) = Key::path(path, TextureKind::Color) else {
+            unreachable!()
+        };
+        let derived = format!("{}#", path);
+        self.entries.lock().unwrap().textures.retain(|key, _| match key {
+            Key::Path(key, _) => *key != path && !key.starts_with(&derived),
+            Key::Content(..) => true,
+        });
+    }
+
+    pub fn stats(&self) -> TextureCacheStats {
+        let entries = self.entries.lock().unwrap();
+        let live = entries
+            .textures
+            .values()
+            .filter(|texture| texture.strong_count() > 0)
+            .map(Weak::as_ptr)
+            .collect::<HashSet<_>>()
+            .len();
+        TextureCacheStats {
+            hits: entries.hits,
+            misses: entries.misses,
+            live,
+        }
+    }
+}
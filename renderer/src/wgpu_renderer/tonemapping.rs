@@ -0,0 +1,333 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use super::resources;
+use crate::{Exposure, Tonemapper};
+
+const SHADER_FILE: &str = "tonemapping.wgsl";
+
+// The scene is rendered in this format, so lighting isn't clamped at 1.0
+// before exposure and tonemapping.
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+const HISTOGRAM_BINS: u64 = 256;
+// The log2 luminance range auto exposure adapts to.
+const MIN_LOG_LUMINANCE: f32 = -10.0;
+const LOG_LUMINANCE_RANGE: f32 = 14.0;
+const ADAPTATION_RATE: f32 = 1.5;
+// Of the histogram shader.
+const TILE_SIZE: u32 = 16;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemappingUniform {
+    exposure: f32,
+    tonemapper: u32,
+    auto_exposure: u32,
+    encode_srgb: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LuminanceUniform {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    dt: f32,
+    adaptation_rate: f32,
+}
+
+// Owns the HDR target the scene is drawn into and resolves it to the
+// surface. With auto exposure a histogram of the frame's luminance is built
+// first and the exposure follows its average over time, on the GPU so no
+// readback stalls the frame.
+pub struct TonemapPass {
+    target: wgpu::Texture,
+    target_view: wgpu::TextureView,
+    tonemap_pipeline: wgpu::RenderPipeline,
+    histogram_pipeline: wgpu::ComputePipeline,
+    average_pipeline: wgpu::ComputePipeline,
+    tonemap_bind_group: wgpu::BindGroup,
+    histogram_bind_group: wgpu::BindGroup,
+    average_bind_group: wgpu::BindGroup,
+    uniform: TonemappingUniform,
+    uniform_buffer: wgpu::Buffer,
+    luminance_buffer: wgpu::Buffer,
+    histogram_buffer: wgpu::Buffer,
+    adapted_buffer: wgpu::Buffer,
+}
+
+impl TonemapPass {
+    // surface_format is whatever the surface reports, sRGB encoding is done
+    // in the shader if the surface doesn't do it.
+    pub fn new(device: &wgpu::Device, surface_format: wgpu::TextureFormat, width: u32, height: u32) -> Result<Self> {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(SHADER_FILE),
+            source: wgpu::ShaderSource::Wgsl(resources::load_string(SHADER_FILE)?.into()),
+        });
+        let tonemap_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Tonemapping Pipeline"),
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+                compilation_options: Default::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: surface_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: Default::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        let compute_pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let histogram_pipeline = compute_pipeline("build_histogram");
+        let average_pipeline = compute_pipeline("average_luminance");
+
+        let uniform = TonemappingUniform {
+            exposure: 0.0,
+            tonemapper: tonemapper_index(Tonemapper::Aces),
+            auto_exposure: 0,
+            // Float surfaces take linear colors.
+            encode_srgb: (!surface_format.is_srgb()
+                && !matches!(surface_format, wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rgba32Float))
+                as u32,
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tonemapping Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let luminance = LuminanceUniform {
+            min_log_luminance: MIN_LOG_LUMINANCE,
+            log_luminance_range: LOG_LUMINANCE_RANGE,
+            dt: 0.0,
+            adaptation_rate: ADAPTATION_RATE,
+        };
+        let luminance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Luminance Buffer"),
+            contents: bytemuck::cast_slice(&[luminance]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        // The average pass clears the histogram after reading it.
+        let histogram_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Luminance Histogram"),
+            size: HISTOGRAM_BINS * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        // Zero until the first frame was measured.
+        let adapted_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Adapted Luminance"),
+            size: 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let (target, target_view) = create_target(device, width, height);
+        let (tonemap_bind_group, histogram_bind_group, average_bind_group) = create_bind_groups(
+            device,
+            [&tonemap_pipeline.get_bind_group_layout(0), &histogram_pipeline.get_bind_group_layout(0), &average_pipeline.get_bind_group_layout(0)],
+            &target_view,
+            [&uniform_buffer, &luminance_buffer, &histogram_buffer, &adapted_buffer],
+        );
+
+        Ok(Self {
+            target,
+            target_view,
+            tonemap_pipeline,
+            histogram_pipeline,
+            average_pipeline,
+            tonemap_bind_group,
+            histogram_bind_group,
+            average_bind_group,
+            uniform,
+            uniform_buffer,
+            luminance_buffer,
+            histogram_buffer,
+            adapted_buffer,
+        })
+    }
+
+    // The view the scene is rendered to.
+    pub fn target_view(&self) -> &wgpu::TextureView {
+        &self.target_view
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.target, self.target_view) = create_target(device, width, height);
+        (self.tonemap_bind_group, self.histogram_bind_group, self.average_bind_group) = create_bind_groups(
+            device,
+            [
+                &self.tonemap_pipeline.get_bind_group_layout(0),
+                &self.histogram_pipeline.get_bind_group_layout(0),
+                &self.average_pipeline.get_bind_group_layout(0),
+            ],
+            &self.target_view,
+            [&self.uniform_buffer, &self.luminance_buffer, &self.histogram_buffer, &self.adapted_buffer],
+        );
+    }
+
+    pub fn set_tonemapper(&mut self, tonemapper: Tonemapper) {
+        self.uniform.tonemapper = tonemapper_index(tonemapper);
+    }
+
+    pub fn set_exposure(&mut self, exposure: Exposure) {
+        (self.uniform.exposure, self.uniform.auto_exposure) = match exposure {
+            Exposure::Manual(stops) => (stops, 0),
+            Exposure::Auto { compensation } => (compensation, 1),
+        };
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, dt: &std::time::Duration) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+        if self.uniform.auto_exposure == 1 {
+            let luminance = LuminanceUniform {
+                min_log_luminance: MIN_LOG_LUMINANCE,
+                log_luminance_range: LOG_LUMINANCE_RANGE,
+                dt: dt.as_secs_f32(),
+                adaptation_rate: ADAPTATION_RATE,
+            };
+            queue.write_buffer(&self.luminance_buffer, 0, bytemuck::cast_slice(&[luminance]));
+        }
+    }
+
+    // Measures the target if auto exposure is on, then draws it to view.
+    pub fn draw(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        if self.uniform.auto_exposure == 1 {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Luminance Histogram Pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_pipeline(&self.histogram_pipeline);
+            compute_pass.set_bind_group(0, &self.histogram_bind_group, &[]);
+            let size = self.target.size();
+            compute_pass.dispatch_workgroups(size.width.div_ceil(TILE_SIZE), size.height.div_ceil(TILE_SIZE), 1);
+            compute_pass.set_pipeline(&self.average_pipeline);
+            compute_pass.set_bind_group(0, &self.average_bind_group, &[]);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Tonemapping Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.tonemap_pipeline);
+        render_pass.set_bind_group(0, &self.tonemap_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn tonemapper_index(tonemapper: Tonemapper) -> u32 {
+    match tonemapper {
+        Tonemapper::Aces => 0,
+        Tonemapper::Reinhard => 1,
+        Tonemapper::AgX => 2,
+    }
+}
+
+fn create_target(device: &wgpu::Device, width: u32, height: u32) -> (wgpu::Texture, wgpu::TextureView) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("hdr_target"),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
+
+// The pipelines use derived layouts, which only hold the bindings their
+// entry points use.
+fn create_bind_groups(
+    device: &wgpu::Device,
+    [tonemap_layout, histogram_layout, average_layout]: [&wgpu::BindGroupLayout; 3],
+    target_view: &wgpu::TextureView,
+    [uniform, luminance, histogram, adapted]: [&wgpu::Buffer; 4],
+) -> (wgpu::BindGroup, wgpu::BindGroup, wgpu::BindGroup) {
+    let target = wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(target_view),
+    };
+    let histogram_entries = [
+        target.clone(),
+        wgpu::BindGroupEntry {
+            binding: 3,
+            resource: histogram.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+            binding: 4,
+            resource: luminance.as_entire_binding(),
+        },
+    ];
+    let tonemap = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("tonemapping_bind_group"),
+        layout: tonemap_layout,
+        entries: &[
+            target.clone(),
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: uniform.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: adapted.as_entire_binding(),
+            },
+        ],
+    });
+    let histogram_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("luminance_histogram_bind_group"),
+        layout: histogram_layout,
+        entries: &histogram_entries,
+    });
+    let average = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("luminance_average_bind_group"),
+        layout: average_layout,
+        entries: &[
+            histogram_entries[0].clone(),
+            histogram_entries[1].clone(),
+            histogram_entries[2].clone(),
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: adapted.as_entire_binding(),
+            },
+        ],
+    });
+    (tonemap, histogram_group, average)
+}
@@ -0,0 +1,187 @@
+// Compute passes precomputing the image based lighting of an environment.
+// Storage textures can't be cube maps everywhere, so the six faces of the
+// cube map level being written are stacked vertically in the output and
+// copied into place afterwards.
+
+const PI: f32 = 3.14159265359;
+
+struct Params {
+    // Of the prefiltered mip level being written.
+    roughness: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var source_sampler: sampler;
+@group(0) @binding(2)
+var equirectangular: texture_2d<f32>;
+@group(0) @binding(3)
+var environment: texture_cube<f32>;
+@group(0) @binding(4)
+var output: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(5)
+var brdf_lut_output: texture_storage_2d<rgba16float, write>;
+
+// The direction through the center of a texel of a cube map face, faces
+// ordered +X, -X, +Y, -Y, +Z, -Z like wgpu expects them.
+fn texel_direction(id: vec3<u32>, size: u32) -> vec3<f32> {
+    let uv = (vec2<f32>(id.xy) + 0.5) / f32(size) * 2.0 - 1.0;
+    switch id.z {
+        case 0u: { return normalize(vec3<f32>(1.0, -uv.y, -uv.x)); }
+        case 1u: { return normalize(vec3<f32>(-1.0, -uv.y, uv.x)); }
+        case 2u: { return normalize(vec3<f32>(uv.x, 1.0, uv.y)); }
+        case 3u: { return normalize(vec3<f32>(uv.x, -1.0, -uv.y)); }
+        case 4u: { return normalize(vec3<f32>(uv.x, -uv.y, 1.0)); }
+        default: { return normalize(vec3<f32>(-uv.x, -uv.y, -1.0)); }
+    }
+}
+
+fn store_texel(id: vec3<u32>, size: u32, color: vec3<f32>) {
+    textureStore(output, vec2<u32>(id.x, id.z * size + id.y), vec4<f32>(color, 1.0));
+}
+
+// Samples each texel of the cube map level being written from the
+// equirectangular image, at the mip level with about the same texel size.
+@compute @workgroup_size(8, 8, 1)
+fn equirectangular_to_cube(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(output).x;
+    if id.x >= size || id.y >= size {
+        return;
+    }
+    let direction = texel_direction(id, size);
+    let uv = vec2<f32>(
+        atan2(direction.z, direction.x) / (2.0 * PI) + 0.5,
+        acos(clamp(direction.y, -1.0, 1.0)) / PI,
+    );
+    // Four faces span the width of the image.
+    let level = max(log2(f32(textureDimensions(equirectangular).x) / (4.0 * f32(size))), 0.0);
+    let color = textureSampleLevel(equirectangular, source_sampler, uv, level);
+    store_texel(id, size, color.rgb);
+}
+
+// Builds an orthonormal basis around n.
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(n.y) > 0.999);
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+// The cosine weighted average of the light arriving from the hemisphere
+// around each texel's direction, so diffuse lighting is irradiance * albedo.
+@compute @workgroup_size(8, 8, 1)
+fn irradiance(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(output).x;
+    if id.x >= size || id.y >= size {
+        return;
+    }
+    let frame = tangent_frame(texel_direction(id, size));
+    let delta = 0.05;
+    // Samples are about delta apart, a face spans PI / 2.
+    let level = max(log2(f32(textureDimensions(environment).x) * 2.0 * delta / PI), 0.0);
+    var sum = vec3<f32>(0.0);
+    var count = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi += delta) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta += delta) {
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(environment, source_sampler, frame * local, level).rgb;
+            sum += color * cos(theta) * sin(theta);
+            count += 1.0;
+        }
+    }
+    store_texel(id, size, PI * sum / count);
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// A half vector distributed like the GGX lobe around n.
+fn importance_sample_ggx(xi: vec2<f32>, n: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return normalize(tangent_frame(n) * h);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+const PREFILTER_SAMPLES: u32 = 256u;
+
+// Convolves the environment with the GGX lobe of params.roughness, assuming
+// the view direction is the reflection direction. Samples are read from
+// blurrier mips where they are sparse.
+@compute @workgroup_size(8, 8, 1)
+fn prefilter(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(output).x;
+    if id.x >= size || id.y >= size {
+        return;
+    }
+    let n = texel_direction(id, size);
+    if params.roughness == 0.0 {
+        store_texel(id, size, textureSampleLevel(environment, source_sampler, n, 0.0).rgb);
+        return;
+    }
+    let source_size = f32(textureDimensions(environment).x);
+    let texel_solid_angle = 4.0 * PI / (6.0 * source_size * source_size);
+    var sum = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < PREFILTER_SAMPLES; i += 1u) {
+        let h = importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), n, params.roughness);
+        let l = normalize(2.0 * dot(n, h) * h - n);
+        let n_dot_l = dot(n, l);
+        if n_dot_l > 0.0 {
+            let n_dot_h = max(dot(n, h), 0.0);
+            let pdf = distribution_ggx(n_dot_h, params.roughness) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(PREFILTER_SAMPLES) * pdf + 0.0001);
+            let level = max(0.5 * log2(sample_solid_angle / texel_solid_angle), 0.0);
+            sum += textureSampleLevel(environment, source_sampler, l, level).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    store_texel(id, size, sum / weight);
+}
+
+const BRDF_SAMPLES: u32 = 512u;
+
+// The scale and bias applied to F0 by the specular BRDF integrated over the
+// hemisphere, by n_dot_v along x and roughness along y.
+@compute @workgroup_size(8, 8, 1)
+fn brdf_lut(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(brdf_lut_output);
+    if id.x >= size.x || id.y >= size.y {
+        return;
+    }
+    let n_dot_v = (f32(id.x) + 0.5) / f32(size.x);
+    let roughness = (f32(id.y) + 0.5) / f32(size.y);
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let n = vec3<f32>(0.0, 0.0, 1.0);
+    // Schlick-GGX with the k used for image based lighting.
+    let k = roughness * roughness / 2.0;
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < BRDF_SAMPLES; i += 1u) {
+        let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), n, roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        if n_dot_l > 0.0 {
+            let n_dot_h = max(h.z, 0.0);
+            let v_dot_h = max(dot(v, h), 0.0);
+            let g = (n_dot_v / (n_dot_v * (1.0 - k) + k)) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+            let visibility = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fresnel) * visibility;
+            bias += fresnel * visibility;
+        }
+    }
+    let result = vec2<f32>(scale, bias) / f32(BRDF_SAMPLES);
+    textureStore(brdf_lut_output, id.xy, vec4<f32>(result, 0.0, 1.0));
+}
@@ -13,10 +13,21 @@ struct InstanceInput {
 struct Light {
     direction: vec3<f32>,
     color: vec3<f32>,
+    // The mip level of t_prefiltered for a roughness of 1.
+    environment_max_lod: f32,
 }
 
 @group(2) @binding(0)
 var<uniform> light: Light;
+// Image based lighting, see environment.wgsl.
+@group(2) @binding(1)
+var t_irradiance: texture_cube<f32>;
+@group(2) @binding(2)
+var t_prefiltered: texture_cube<f32>;
+@group(2) @binding(3)
+var t_brdf_lut: texture_2d<f32>;
+@group(2) @binding(4)
+var s_environment: sampler;
 
 // Vertex shader
 struct CameraUniform {
@@ -30,8 +41,7 @@ struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) normal: vec3<f32>, 
-    @location(3) tangent: vec3<f32>, 
-    @location(4) bitangent: vec3<f32>, 
+    @location(3) tangent: vec4<f32>, 
 };
 
 struct VertexOutput {
@@ -41,31 +51,67 @@ struct VertexOutput {
     @location(2) world_position: vec3<f32>,
     @location(3) tangent_light_direction: vec3<f32>, 
     @location(4) tangent_view_position: vec3<f32>, 
+    // The environment is looked up in world space.
+    @location(5) world_tangent: vec3<f32>,
+    @location(6) world_bitangent: vec3<f32>,
+    @location(7) world_normal: vec3<f32>,
 };
 
 
-@vertex
-fn vs_main(
-    model: VertexInput,
-    instance: InstanceInput,
-) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
+struct SkinInput {
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+};
 
-    let normal_matrix = mat3x3<f32>(
-        instance.normal_matrix_0,
-        instance.normal_matrix_1,
-        instance.normal_matrix_2,
-    );
+// Joint matrices of every instance, instance i starts at
+// i * joints_per_instance.
+struct JointPalette {
+    joints_per_instance: u32,
+    matrices: array<mat4x4<f32>>,
+};
+@group(3) @binding(0)
+var<storage, read> palette: JointPalette;
+
+// Morph target weights of every instance, laid out like the joint palette.
+struct MorphWeights {
+    weights_per_instance: u32,
+    weights: array<f32>,
+};
+@group(3) @binding(1)
+var<storage, read> morph_weights: MorphWeights;
+
+// Position and normal deltas of every vertex, target after target.
+struct MorphTargets {
+    num_targets: u32,
+    num_vertices: u32,
+    weight_offset: u32,
+    deltas: array<vec4<f32>>,
+};
+@group(3) @binding(2)
+var<storage, read> morph_targets: MorphTargets;
 
+fn apply_morph_targets(model: VertexInput, vertex_index: u32, instance_index: u32) -> VertexInput {
+    var out = model;
+    let first_weight = instance_index * morph_weights.weights_per_instance + morph_targets.weight_offset;
+    for (var t = 0u; t < morph_targets.num_targets; t += 1u) {
+        let weight = morph_weights.weights[first_weight + t];
+        let delta = (t * morph_targets.num_vertices + vertex_index) * 2u;
+        out.position += morph_targets.deltas[delta].xyz * weight;
+        out.normal += morph_targets.deltas[delta + 1u].xyz * weight;
+    }
+    return out;
+}
+
+fn transform_vertex(
+    model: VertexInput,
+    model_matrix: mat4x4<f32>,
+    normal_matrix: mat3x3<f32>,
+) -> VertexOutput {
     // Construct the tangent matrix
     let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
+    let world_tangent = normalize(normal_matrix * model.tangent.xyz);
+    // The bitangent isn't stored, w holds its sign.
+    let world_bitangent = cross(world_normal, world_tangent) * model.tangent.w;
     let tangent_matrix = transpose(mat3x3<f32>(
         world_tangent,
         world_bitangent,
@@ -77,6 +123,10 @@ fn vs_main(
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
     out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    out.world_tangent = world_tangent;
+    out.world_bitangent = world_bitangent;
+    out.world_normal = world_normal;
     out.tangent_position = tangent_matrix * world_position.xyz;
     out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
 
@@ -85,38 +135,202 @@ fn vs_main(
     return out;
 }
 
+fn instance_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+fn instance_normal_matrix(instance: InstanceInput) -> mat3x3<f32> {
+    return mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(model, instance_model_matrix(instance), instance_normal_matrix(instance));
+}
+
+@vertex
+fn vs_morphed(
+    model: VertexInput,
+    instance: InstanceInput,
+    @builtin(vertex_index) vertex_index: u32,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    let morphed = apply_morph_targets(model, vertex_index, instance_index);
+    return transform_vertex(morphed, instance_model_matrix(instance), instance_normal_matrix(instance));
+}
+
+// Morph targets are applied in bind space, before skinning.
+@vertex
+fn vs_skinned(
+    model: VertexInput,
+    instance: InstanceInput,
+    skin: SkinInput,
+    @builtin(vertex_index) vertex_index: u32,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    let morphed = apply_morph_targets(model, vertex_index, instance_index);
+    let first_joint = instance_index * palette.joints_per_instance;
+    let skin_matrix =
+        palette.matrices[first_joint + skin.joints.x] * skin.weights.x +
+        palette.matrices[first_joint + skin.joints.y] * skin.weights.y +
+        palette.matrices[first_joint + skin.joints.z] * skin.weights.z +
+        palette.matrices[first_joint + skin.joints.w] * skin.weights.w;
+    let model_matrix = instance_model_matrix(instance) * skin_matrix;
+    // Joints may scale non uniformly, so the normals use the inverse transpose.
+    let skin_3x3 = mat3x3<f32>(skin_matrix[0].xyz, skin_matrix[1].xyz, skin_matrix[2].xyz);
+    let skin_normal_matrix = cofactor_3x3(skin_3x3) * sign(determinant(skin_3x3));
+    let normal_matrix = instance_normal_matrix(instance) * skin_normal_matrix;
+    return transform_vertex(morphed, model_matrix, normal_matrix);
+}
+
+// The inverse transpose scaled by the determinant, good enough for directions
+// that get normalized.
+fn cofactor_3x3(m: mat3x3<f32>) -> mat3x3<f32> {
+    return mat3x3<f32>(
+        cross(m[1], m[2]),
+        cross(m[2], m[0]),
+        cross(m[0], m[1]),
+    );
+}
+
 @group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(0)@binding(1)
-var s_diffuse: sampler;
-@group(0)@binding(2)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_base_color: sampler;
+@group(0) @binding(2)
 var t_normal: texture_2d<f32>;
 @group(0) @binding(3)
 var s_normal: sampler;
+@group(0) @binding(4)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(5)
+var s_metallic_roughness: sampler;
+@group(0) @binding(6)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(7)
+var s_occlusion: sampler;
+@group(0) @binding(8)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(9)
+var s_emissive: sampler;
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    specular_factor: vec3<f32>,
+    roughness_factor: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+    gray_metallic_roughness: u32,
+}
+
+@group(0) @binding(10)
+var<uniform> material: Material;
+
+const PI: f32 = 3.14159265359;
+
+// Trowbridge-Reitz GGX normal distribution.
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith's method with the Schlick-GGX approximation for direct lighting.
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = (r * r) / 8.0;
+    let ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Light arriving from every direction has no single half vector, rough
+// surfaces get less of the grazing angle boost.
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color_factor;
     let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-    
-    // We don't need (or want) much ambient light, so 0.1 is fine
-    let ambient_strength = 0.01;
-    let ambient_color = light.color * ambient_strength;
+    var metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, in.tex_coords);
+    // Grayscale maps are uploaded with a single channel, or two with alpha.
+    if material.gray_metallic_roughness != 0u {
+        metallic_roughness = metallic_roughness.rrra;
+    }
+    let occlusion = textureSample(t_occlusion, s_occlusion, in.tex_coords).r;
+    let emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive_factor;
+
+    let metallic = clamp(metallic_roughness.b * material.metallic_factor, 0.0, 1.0);
+    // Very low roughness makes the highlight collapse to a single pixel.
+    let roughness = clamp(metallic_roughness.g * material.roughness_factor, 0.045, 1.0);
+    let ao = mix(1.0, occlusion, material.occlusion_strength);
 
     // Create the lighting vectors
-    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    var tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    tangent_normal = normalize(vec3<f32>(tangent_normal.xy * material.normal_scale, tangent_normal.z));
     let light_dir = normalize(in.tangent_light_direction);
     let view_dir = normalize(in.tangent_view_position - in.tangent_position);
     let half_dir = normalize(view_dir + light_dir);
 
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+    let n_dot_l = max(dot(tangent_normal, light_dir), 0.0);
+    let n_dot_v = max(dot(tangent_normal, view_dir), 1e-4);
+    let n_dot_h = max(dot(tangent_normal, half_dir), 0.0);
+    let v_dot_h = max(dot(view_dir, half_dir), 0.0);
 
-    // let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    // let specular_color = specular_strength * light.color;
+    // Dielectrics reflect about 4% at normal incidence, metals tint the
+    // reflection with their base color.
+    let dielectric_f0 = 0.08 * material.specular_factor;
+    let f0 = mix(dielectric_f0, base_color.rgb, metallic);
+    let fresnel = fresnel_schlick(v_dot_h, f0);
+    let d = distribution_ggx(n_dot_h, roughness);
+    let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+    let specular = d * g * fresnel / (4.0 * n_dot_v * max(n_dot_l, 1e-4));
+
+    // Metals have no diffuse reflection.
+    let k_diffuse = (vec3<f32>(1.0) - fresnel) * (1.0 - metallic);
+    let diffuse = k_diffuse * base_color.rgb / PI;
+
+    let direct = (diffuse + specular) * light.color * n_dot_l;
+
+    // Ambient light from the environment, split like the direct light.
+    let tangent_to_world = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let world_normal = normalize(tangent_to_world * tangent_normal);
+    let world_view_dir = normalize(camera.view_pos.xyz - in.world_position);
+    let reflection = reflect(-world_view_dir, world_normal);
+    let ambient_fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let ambient_k_diffuse = (vec3<f32>(1.0) - ambient_fresnel) * (1.0 - metallic);
+    let irradiance = textureSample(t_irradiance, s_environment, world_normal).rgb;
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflection, roughness * light.environment_max_lod).rgb;
+    let brdf = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    let ambient_specular = prefiltered * (ambient_fresnel * brdf.x + brdf.y);
+    let ambient = (ambient_k_diffuse * irradiance * base_color.rgb + ambient_specular) * ao;
 
-    let result = (ambient_color + diffuse_color // + specular_color
-                 ) * object_color.xyz;
+    let result = ambient + direct + emissive;
 
-    return vec4<f32>(result, object_color.a);
+    return vec4<f32>(result, base_color.a);
 }
@@ -0,0 +1,53 @@
+// Draws the background behind everything else, on the far plane.
+
+struct Skybox {
+    // Turns clip space positions into world space view directions.
+    clip_to_direction: mat4x4<f32>,
+    zenith: vec3<f32>,
+    // 0 for the gradient, 1 for the environment.
+    mode: u32,
+    horizon: vec3<f32>,
+    ground: vec3<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> skybox: Skybox;
+@group(0) @binding(1)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(2)
+var s_environment: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) clip: vec2<f32>,
+};
+
+// A single triangle covering the screen.
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    let clip = uv * 2.0 - 1.0;
+    var out: VertexOutput;
+    // z = w puts it on the far plane, behind everything drawn before.
+    out.clip_position = vec4<f32>(clip, 1.0, 1.0);
+    out.clip = clip;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = normalize((skybox.clip_to_direction * vec4<f32>(in.clip, 1.0, 0.0)).xyz);
+    var color: vec3<f32>;
+    if skybox.mode == 1u {
+        color = textureSampleLevel(t_environment, s_environment, direction, 0.0).rgb;
+    } else {
+        // The square root keeps most of the change close to the horizon.
+        let height = direction.y;
+        if height >= 0.0 {
+            color = mix(skybox.horizon, skybox.zenith, sqrt(height));
+        } else {
+            color = mix(skybox.horizon, skybox.ground, sqrt(-height));
+        }
+    }
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,203 @@
+// Turns the HDR scene into colors the display can show: the exposure scales
+// it, a tonemapper compresses it into [0, 1].
+
+struct Tonemapping {
+    // In stops, added to the automatic exposure if there is one.
+    exposure: f32,
+    // 0 for ACES, 1 for Reinhard, 2 for AgX.
+    tonemapper: u32,
+    auto_exposure: u32,
+    // For surfaces that don't encode sRGB themselves.
+    encode_srgb: u32,
+}
+
+struct LuminanceParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    // Seconds since the last frame.
+    dt: f32,
+    // How quickly the exposure follows the scene, per second.
+    adaptation_rate: f32,
+}
+
+struct AdaptedLuminance {
+    luminance: f32,
+}
+
+@group(0) @binding(0)
+var hdr: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> tonemapping: Tonemapping;
+@group(0) @binding(2)
+var<storage, read> adapted: AdaptedLuminance;
+@group(0) @binding(3)
+var<storage, read_write> histogram: array<atomic<u32>, 256>;
+@group(0) @binding(4)
+var<uniform> luminance_params: LuminanceParams;
+@group(0) @binding(5)
+var<storage, read_write> adapted_output: AdaptedLuminance;
+
+const HISTOGRAM_BINS: u32 = 256u;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Bin 0 holds the pixels too dark to count, the others split the log
+// luminance range evenly.
+fn luminance_bin(color: vec3<f32>) -> u32 {
+    let l = luminance(color);
+    if l < exp2(luminance_params.min_log_luminance) {
+        return 0u;
+    }
+    let t = clamp((log2(l) - luminance_params.min_log_luminance) / luminance_params.log_luminance_range, 0.0, 1.0);
+    return u32(t * f32(HISTOGRAM_BINS - 2u)) + 1u;
+}
+
+var<workgroup> local_bins: array<atomic<u32>, 256>;
+
+// Counts the pixels of each tile in workgroup memory first, so the global
+// histogram only sees one add per bin and tile.
+@compute @workgroup_size(16, 16, 1)
+fn build_histogram(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+) {
+    atomicStore(&local_bins[index], 0u);
+    workgroupBarrier();
+    let size = textureDimensions(hdr);
+    if id.x < size.x && id.y < size.y {
+        let color = textureLoad(hdr, id.xy, 0).rgb;
+        atomicAdd(&local_bins[luminance_bin(color)], 1u);
+    }
+    workgroupBarrier();
+    atomicAdd(&histogram[index], atomicLoad(&local_bins[index]));
+}
+
+var<workgroup> weighted_counts: array<u32, 256>;
+
+// Averages the log luminance of the histogram, moves the adapted luminance
+// towards it and clears the histogram for the next frame.
+@compute @workgroup_size(256, 1, 1)
+fn average_luminance(@builtin(local_invocation_index) index: u32) {
+    let count = atomicExchange(&histogram[index], 0u);
+    weighted_counts[index] = count * index;
+    workgroupBarrier();
+    for (var stride = HISTOGRAM_BINS / 2u; stride > 0u; stride >>= 1u) {
+        if index < stride {
+            weighted_counts[index] += weighted_counts[index + stride];
+        }
+        workgroupBarrier();
+    }
+    if index == 0u {
+        // count is the number of pixels in bin 0 here.
+        let size = textureDimensions(hdr);
+        let counted = max(f32(size.x * size.y) - f32(count), 1.0);
+        let average_bin = f32(weighted_counts[0]) / counted - 1.0;
+        let average_log = average_bin / f32(HISTOGRAM_BINS - 2u) * luminance_params.log_luminance_range
+            + luminance_params.min_log_luminance;
+        let average = exp2(average_log);
+        let previous = adapted_output.luminance;
+        // Starts out adapted.
+        if previous <= 0.0 {
+            adapted_output.luminance = average;
+        } else {
+            let t = 1.0 - exp(-luminance_params.dt * luminance_params.adaptation_rate);
+            adapted_output.luminance = previous + (average - previous) * t;
+        }
+    }
+}
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms,
+// matrices given by column.
+const ACES_INPUT = mat3x3<f32>(
+    vec3<f32>(0.59719, 0.07600, 0.02840),
+    vec3<f32>(0.35458, 0.90834, 0.13383),
+    vec3<f32>(0.04823, 0.01566, 0.83777),
+);
+const ACES_OUTPUT = mat3x3<f32>(
+    vec3<f32>(1.60475, -0.10208, -0.00327),
+    vec3<f32>(-0.53108, 1.10813, -0.07276),
+    vec3<f32>(-0.07367, -0.00605, 1.07602),
+);
+
+fn tonemap_aces(color: vec3<f32>) -> vec3<f32> {
+    let v = ACES_INPUT * color;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return clamp(ACES_OUTPUT * (a / b), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn tonemap_reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + color);
+}
+
+// AgX with the polynomial fit of its default contrast curve, as published
+// by Benjamin Wrensch.
+const AGX_INSET = mat3x3<f32>(
+    vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+    vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+    vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+);
+const AGX_OUTSET = mat3x3<f32>(
+    vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+    vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+    vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+);
+const AGX_MIN_EV: f32 = -12.47393;
+const AGX_MAX_EV: f32 = 4.026069;
+
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+fn tonemap_agx(color: vec3<f32>) -> vec3<f32> {
+    var v = AGX_INSET * max(color, vec3<f32>(1e-10));
+    v = clamp(log2(v), vec3<f32>(AGX_MIN_EV), vec3<f32>(AGX_MAX_EV));
+    v = (v - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+    v = AGX_OUTSET * agx_contrast(v);
+    // The curve produces display encoded values.
+    return pow(clamp(v, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(2.2));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+// A single triangle covering the screen.
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(hdr, vec2<u32>(in.clip_position.xy), 0).rgb;
+    var exposure = exp2(tonemapping.exposure);
+    if tonemapping.auto_exposure == 1u {
+        // Maps the average luminance to middle gray.
+        exposure *= 0.18 / max(adapted.luminance, 1e-4);
+    }
+    let exposed = color * exposure;
+    var mapped: vec3<f32>;
+    switch tonemapping.tonemapper {
+        case 1u: { mapped = tonemap_reinhard(exposed); }
+        case 2u: { mapped = tonemap_agx(exposed); }
+        default: { mapped = tonemap_aces(exposed); }
+    }
+    if tonemapping.encode_srgb == 1u {
+        mapped = linear_to_srgb(mapped);
+    }
+    return vec4<f32>(mapped, 1.0);
+}